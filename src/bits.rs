@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use crate::sandbox::{self, Cancel};
+
+pub type BitsResult<T> = Result<T, &'static str>;
+
+/// Bases shown by `base` when none are given
+pub const DEFAULT_BASES: &[u32] = &[10, 16, 8, 2];
+
+/// The size of an integer, and whether it is signed
+#[derive(Clone, Copy, PartialEq)]
+pub struct Width {
+    bits: u32,
+    signed: bool,
+}
+
+impl Width {
+    pub const DEFAULT: Width = Width { bits: 64, signed: true };
+
+    /// Parse a width such as `u8` or `i32`
+    pub fn parse(s: &str) -> Option<Width> {
+        let signed = match s.chars().next()? {
+            'i' | 's' => true,
+            'u' => false,
+            _ => return None
+        };
+        match s[1..].parse::<u32>().ok()? {
+            bits @ (8 | 16 | 32 | 64) => Some(Width { bits, signed }),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}{}", if self.signed { 'i' } else { 'u' }, self.bits)
+    }
+
+    fn mask(&self) -> u64 {
+        match self.bits {
+            64 => u64::MAX,
+            n => (1 << n) - 1
+        }
+    }
+
+    /// Truncate a value to this width, returning its bits and whether it fitted
+    fn wrap(&self, x: i64) -> (u64, bool) {
+        let x128 = x as i128;
+        let fits = match (self.bits, self.signed) {
+            // Values above `i64::MAX` are written as negative numbers, so any
+            // 64 bit value fits
+            (64, _) => true,
+            (n, true) => x128 >= -(1 << (n - 1)) && x128 < 1 << (n - 1),
+            (_, false) => x >= 0 && x as u64 <= self.mask()
+        };
+        (x as u64 & self.mask(), fits)
+    }
+
+    /// The value of some bits as a number of this width
+    fn value(&self, bits: u64) -> i128 {
+        match self.signed && bits >> (self.bits - 1) & 1 == 1 {
+            true => bits as i128 - (1i128 << self.bits),
+            false => bits as i128
+        }
+    }
+}
+
+/// Write the bits of a number in a base from 2 to 36
+fn to_radix(mut x: u64, base: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit((x % base as u64) as u32, base).unwrap());
+        x /= base as u64;
+        if x == 0 {
+            break
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// Show a value in several bases, truncated to `width`. Bases other than
+/// 10 show the bits of the value, so negative numbers are in two's complement.
+/// Also returns whether the value fitted in the width.
+pub fn table(x: i64, width: Width, bases: &[u32]) -> (String, bool) {
+    let (bits, fits) = width.wrap(x);
+    let label = |base: u32| match base {
+        2 => String::from("bin"),
+        8 => String::from("oct"),
+        10 => String::from("dec"),
+        16 => String::from("hex"),
+        n => format!("base {}", n)
+    };
+    let label_width = bases.iter().map(|b| label(*b).len()).max().unwrap_or(0);
+    let lines = bases.iter()
+        .map(|base| {
+            let value = match base {
+                2 => {
+                    // Group binary digits in fours so that they can be counted
+                    let digits = to_radix(bits, 2);
+                    let padded = format!("{:0>width$}", digits, width = digits.len().div_ceil(4) * 4);
+                    let groups = padded.as_bytes()
+                        .chunks(4)
+                        .map(|c| std::str::from_utf8(c).unwrap())
+                        .collect::<Vec<&str>>();
+                    format!("0b{}", groups.join("_"))
+                },
+                8 => format!("0o{}", to_radix(bits, 8)),
+                10 => width.value(bits).to_string(),
+                16 => format!("0x{}", to_radix(bits, 16)),
+                n => to_radix(bits, *n)
+            };
+            format!("{:<width$}  {}", label(*base), value, width = label_width)
+        })
+        .collect::<Vec<String>>();
+    (lines.join("\n"), fits)
+}
+
+/// Parse a number written in a base from 2 to 36, such as `ff` in base 16
+pub fn parse_radix(s: &str, base: u32) -> BitsResult<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s)
+    };
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(base)) {
+        return Err("invalid digit for that base")
+    }
+    let x = u64::from_str_radix(&digits, base).map_err(|_| "number doesn't fit in 64 bits")? as i64;
+    Ok(if negative { x.wrapping_neg() } else { x })
+}
+
+/// Whether an expression uses base prefixes or bitwise operators, and so
+/// should be evaluated as 64 bit integers
+pub fn is_programmer(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    let prefixed = bytes.windows(2).enumerate().any(|(i, w)| {
+        w[0] == b'0' && matches!(w[1], b'x' | b'b' | b'o')
+            && !(i > 0 && (bytes[i-1].is_ascii_alphanumeric() || bytes[i-1] == b'.'))
+    });
+    prefixed || s.contains(['&', '|', '~']) || s.contains("<<") || s.contains(">>")
+}
+
+//
+// Parsing and evaluation
+//
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    vars: &'a BTreeMap<String, f64>,
+    cancel: &'a Cancel,
+}
+
+/// Evaluate an expression with 64 bit integers. Arithmetic wraps around, and
+/// `^` is exclusive or, with `**` for powers. Variables in `vars` can be used
+/// if they are whole numbers.
+pub fn eval(s: &str, vars: &BTreeMap<String, f64>, cancel: &Cancel) -> BitsResult<i64> {
+    let src = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    if src.is_empty() {
+        return Err("no expression given")
+    }
+    let mut parser = Parser { src: src.as_bytes(), pos: 0, vars, cancel };
+    let value = parser.or()?;
+    if parser.pos < parser.src.len() {
+        return Err("unexpected character")
+    }
+    Ok(value)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.src[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_cancel(&self) -> BitsResult<()> {
+        match self.cancel.is_cancelled() {
+            true => Err(sandbox::CANCELLED),
+            false => Ok(())
+        }
+    }
+
+    fn or(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.xor()?;
+        while self.eat("|") {
+            lhs |= self.xor()?;
+        }
+        Ok(lhs)
+    }
+
+    fn xor(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.and()?;
+        while self.eat("^") {
+            lhs ^= self.and()?;
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.shift()?;
+        while self.eat("&") {
+            lhs &= self.shift()?;
+        }
+        Ok(lhs)
+    }
+
+    fn shift(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.sum()?;
+        loop {
+            let left = if self.eat("<<") {
+                true
+            } else if self.eat(">>") {
+                false
+            } else {
+                return Ok(lhs)
+            };
+            let amount = self.sum()?;
+            if !(0..64).contains(&amount) {
+                return Err("shift amounts must be between 0 and 63")
+            }
+            // Right shifts are arithmetic, keeping the sign
+            lhs = match left {
+                true => lhs << amount,
+                false => lhs >> amount
+            };
+        }
+    }
+
+    fn sum(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.product()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat("+") {
+                lhs = lhs.wrapping_add(self.product()?);
+            } else if self.eat("-") {
+                lhs = lhs.wrapping_sub(self.product()?);
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn product(&mut self) -> BitsResult<i64> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat("*") {
+                lhs = lhs.wrapping_mul(self.unary()?);
+            } else if self.eat("/") {
+                match self.unary()? {
+                    0 => return Err("division by zero"),
+                    rhs => lhs = lhs.wrapping_div(rhs)
+                }
+            } else if self.eat("%") {
+                match self.unary()? {
+                    0 => return Err("division by zero"),
+                    rhs => lhs = lhs.wrapping_rem(rhs)
+                }
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn unary(&mut self) -> BitsResult<i64> {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> BitsResult<i64> {
+        let base = self.atom()?;
+        if self.eat("**") {
+            // Right associative, and binds tighter than a unary minus on the left
+            let exp = self.unary()?;
+            if exp < 0 {
+                return Err("integer powers can't be negative")
+            }
+            let exp = u32::try_from(exp).map_err(|_| "power is too large")?;
+            return Ok(base.wrapping_pow(exp))
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> BitsResult<i64> {
+        if self.eat("(") {
+            let inner = self.or()?;
+            if !self.eat(")") {
+                return Err("unmatched parenthesis")
+            }
+            return Ok(inner)
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == b'_' => self.name(),
+            None => Err("unexpected end of expression"),
+            _ => Err("unexpected character")
+        }
+    }
+
+    /// Parse a number such as `42`, `0xff`, `0b1010` or `0o17`, with optional `_` separators
+    fn number(&mut self) -> BitsResult<i64> {
+        let base = match self.src.get(self.pos..self.pos+2) {
+            Some(b"0x") => 16,
+            Some(b"0b") => 2,
+            Some(b"0o") => 8,
+            _ => 10
+        };
+        if base != 10 {
+            self.pos += 2;
+        }
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'.') {
+            return Err("only whole numbers can be used with bitwise operators")
+        }
+        parse_radix(std::str::from_utf8(&self.src[start..self.pos]).unwrap(), base)
+    }
+
+    fn name(&mut self) -> BitsResult<i64> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        match self.vars.get(name) {
+            Some(x) if x.fract() == 0.0 && x.abs() < 2f64.powi(63) => Ok(*x as i64),
+            Some(_) => Err("only variables with whole number values can be used with bitwise operators"),
+            None => Err("unknown variable")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(s: &str) -> BitsResult<i64> {
+        eval(s, &BTreeMap::new(), &Cancel::default())
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(eval_str("0xff & ~0b1010").unwrap(), 0xf5);
+        assert_eq!(eval_str("1 << 40").unwrap(), 1 << 40);
+        assert_eq!(eval_str("-16 >> 2").unwrap(), -4);
+        assert_eq!(eval_str("7 ^ 2").unwrap(), 5);
+        assert_eq!(eval_str("2 ** 10").unwrap(), 1024);
+        assert_eq!(eval_str("0o17 | 0b10000").unwrap(), 31);
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(eval_str("0x7fffffffffffffff + 1").unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn variables() {
+        let vars = vec![(String::from("x"), 12.0)].into_iter().collect();
+        assert_eq!(eval("x & 4", &vars, &Cancel::default()).unwrap(), 4);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_str("5 / 0").unwrap_err(), "division by zero");
+        assert!(eval_str("1 << 64").is_err());
+        assert!(eval_str("").is_err());
+    }
+}
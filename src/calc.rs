@@ -0,0 +1,199 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use meval::{Context, ContextProvider, Expr, FuncEvalError};
+use serde::{Serialize, Deserialize};
+use crate::exact;
+use crate::sandbox::{self, Cancel};
+
+const MAX_VARS: usize = 50;
+const MAX_FUNCS: usize = 20;
+/// Most calls to user functions in one evaluation, shared between all of
+/// them, since functions calling each other can take exponential time
+const MAX_CALLS: usize = 100_000;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: String,
+}
+
+/// The first error from inside a user function. meval functions can only
+/// return numbers, so errors are passed back through this instead.
+type FuncError = Rc<RefCell<Option<String>>>;
+
+pub enum Outcome {
+    Value(f64),
+    Assigned(String, f64),
+    Defined(String),
+}
+
+/// A user's variables and functions for `eval`. Functions can use any
+/// variable, and any function defined before them.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Workspace {
+    pub ans: Option<f64>,
+    pub vars: BTreeMap<String, f64>,
+    pub funcs: Vec<Function>,
+    /// Whether to evaluate with exact rational arithmetic by default
+    #[serde(default)]
+    pub exact: bool,
+    /// Number of decimal places shown in exact mode
+    #[serde(default)]
+    pub precision: Option<usize>,
+    /// Changed whenever the variables and functions are stored or cleared,
+    /// so that evaluations started before then can't overwrite them
+    #[serde(skip)]
+    pub version: u64,
+}
+
+impl Workspace {
+    pub fn is_empty(&self) -> bool {
+        self.ans.is_none() && self.vars.is_empty() && self.funcs.is_empty()
+    }
+
+    pub fn precision(&self) -> usize {
+        self.precision.unwrap_or(exact::DEFAULT_PRECISION)
+    }
+
+    /// Build a context with the variables and the first `funcs` functions
+    fn context(&self, funcs: usize, cancel: &Cancel) -> Result<(Context<'static>, FuncError), String> {
+        let error = FuncError::default();
+        let calls = Rc::new(Cell::new(0));
+        let mut ctx = Context::new();
+        if let Some(ans) = self.ans {
+            ctx.var("ans", ans);
+        }
+        for (name, value) in &self.vars {
+            ctx.var(name.clone(), *value);
+        }
+        for func in self.funcs.iter().take(funcs) {
+            let body = func.body.parse::<Expr>().map_err(|e| e.to_string())?;
+            let params = func.params.clone();
+            let outer = ctx.clone();
+            let (name, error, calls, cancel) = (func.name.clone(), error.clone(), calls.clone(), cancel.clone());
+            ctx.funcn(func.name.clone(), move |args: &[f64]| {
+                // Once anything has failed, return straight away so that
+                // the rest of the expression finishes quickly
+                if error.borrow().is_some() {
+                    return f64::NAN
+                }
+                calls.set(calls.get() + 1);
+                if cancel.is_cancelled() {
+                    *error.borrow_mut() = Some(String::from(sandbox::CANCELLED));
+                    return f64::NAN
+                }
+                if calls.get() > MAX_CALLS {
+                    *error.borrow_mut() = Some(String::from("Too many function calls"));
+                    return f64::NAN
+                }
+                let bound = params.iter()
+                    .map(String::as_str)
+                    .zip(args.iter().copied())
+                    .collect::<Vec<_>>();
+                body.eval_with_context((bound, &outer)).unwrap_or_else(|e| {
+                    error.borrow_mut().get_or_insert_with(|| format!("Error in `{}`: {}", name, e));
+                    f64::NAN
+                })
+            }, func.params.len());
+        }
+        Ok((ctx, error))
+    }
+
+    /// Evaluate an expression with the variables and the first `funcs` functions
+    fn eval_expr(&self, input: &str, funcs: usize, cancel: &Cancel) -> Result<f64, String> {
+        let (ctx, error) = self.context(funcs, cancel)?;
+        let value = meval::eval_str_with_context(input, ctx);
+        let error = error.borrow_mut().take();
+        match error {
+            Some(e) => Err(e),
+            None => value.map_err(|e| e.to_string())
+        }
+    }
+
+    /// Evaluate an expression, assign a variable (`x = 3`) or define a function (`f(x) = x^2`)
+    pub fn eval(&mut self, input: &str, cancel: &Cancel) -> Result<Outcome, String> {
+        let input = input.trim();
+        let (lhs, rhs) = match input.find('=') {
+            Some(i) => (input[..i].trim(), input[i+1..].trim()),
+            None => {
+                let value = self.eval_expr(input, self.funcs.len(), cancel)?;
+                self.ans = Some(value);
+                return Ok(Outcome::Value(value))
+            }
+        };
+        match lhs.find('(') {
+            Some(i) if lhs.ends_with(')') => {
+                let name = lhs[..i].trim();
+                let params = lhs[i+1..lhs.len()-1].split(',')
+                    .map(|p| p.trim().to_owned())
+                    .filter(|p| !p.is_empty())
+                    .collect::<Vec<String>>();
+                self.define(name, params, rhs, cancel)?;
+                Ok(Outcome::Defined(name.to_owned()))
+            },
+            Some(_) => Err(String::from("Invalid function definition")),
+            None => {
+                check_name(lhs)?;
+                if self.funcs.iter().any(|f| f.name == lhs) {
+                    return Err(format!("`{}` is already a function", lhs))
+                }
+                if !self.vars.contains_key(lhs) && self.vars.len() >= MAX_VARS {
+                    return Err(String::from("Too many variables"))
+                }
+                let value = self.eval_expr(rhs, self.funcs.len(), cancel)?;
+                self.vars.insert(lhs.to_owned(), value);
+                self.ans = Some(value);
+                Ok(Outcome::Assigned(lhs.to_owned(), value))
+            }
+        }
+    }
+
+    fn define(&mut self, name: &str, params: Vec<String>, body: &str, cancel: &Cancel) -> Result<(), String> {
+        check_name(name)?;
+        for (i, param) in params.iter().enumerate() {
+            check_name(param)?;
+            if params[..i].contains(param) {
+                return Err(format!("Parameter `{}` is repeated", param))
+            }
+        }
+        if self.vars.contains_key(name) {
+            return Err(format!("`{}` is already a variable", name))
+        }
+        let index = self.funcs.iter().position(|f| f.name == name);
+        if index.is_none() && self.funcs.len() >= MAX_FUNCS {
+            return Err(String::from("Too many functions"))
+        }
+        // Check that the body only uses the parameters and things defined before it
+        let (ctx, error) = self.context(index.unwrap_or(self.funcs.len()), cancel)?;
+        let expr = body.parse::<Expr>().map_err(|e| e.to_string())?;
+        let bound = params.iter().map(|p| (p.as_str(), 0.0)).collect::<Vec<_>>();
+        let value = expr.eval_with_context((bound, &ctx));
+        if let Some(e) = error.borrow_mut().take() {
+            return Err(e)
+        }
+        value.map_err(|e| e.to_string())?;
+        let func = Function { name: name.to_owned(), params, body: body.to_owned() };
+        match index {
+            Some(i) => self.funcs[i] = func,
+            None => self.funcs.push(func)
+        }
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("`{}` is not a valid name", name))
+    }
+    let builtin = Context::new();
+    let is_builtin = builtin.get_var(name).is_some()
+        || builtin.eval_func(name, &[]) != Err(FuncEvalError::UnknownFunction);
+    if is_builtin || name == "ans" {
+        return Err(format!("`{}` is a built-in name", name))
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+const DECK_SIZE: u8 = 52;
+const RANKS: &[&str] = &["A", "2", "3", "4", "5", "6", "7", "8", "9", "10", "J", "Q", "K"];
+const SUITS: &[&str] = &["♠️", "♥️", "♦️", "♣️"];
+
+/// Format a card, numbered 0 to 51
+pub fn card_name(card: u8) -> String {
+    format!("{}{}", RANKS[(card % 13) as usize], SUITS[(card / 13) as usize])
+}
+
+/// A deck of playing cards. Cards are drawn from the end of the list.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Deck {
+    pub cards: Vec<u8>,
+}
+
+impl Deck {
+    /// A full deck, in order
+    pub fn full() -> Self {
+        Self { cards: (0..DECK_SIZE).collect() }
+    }
+
+    pub fn shuffle<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        self.cards.shuffle(rng);
+    }
+
+    pub fn draw(&mut self, n: usize) -> Result<Vec<u8>, &'static str> {
+        if n > self.cards.len() {
+            return Err("there aren't enough cards left in the deck")
+        }
+        let drawn = self.cards.split_off(self.cards.len() - n);
+        Ok(drawn.into_iter().rev().collect())
+    }
+}
@@ -1,9 +1,29 @@
 use log::{debug, warn};
 use std::env;
+use std::collections::{BTreeMap, HashMap};
 use serenity::prelude::*;
 use serenity::model::prelude::*;
+use serenity::builder::CreateEmbed;
+use serenity::http::Http;
 use itertools::Itertools;
+use chrono::{TimeZone, Utc};
+use rand::seq::SliceRandom;
 use crate::state::*;
+use crate::counting;
+use crate::bits;
+use crate::calc;
+use crate::cards;
+use crate::dice;
+use crate::eightball;
+use crate::exact;
+use crate::fair::Draw;
+use crate::initiative;
+use crate::matrix;
+use crate::plot;
+use crate::polls::{self, Poll, PollMode, PollOption, PollSettings};
+use crate::sandbox::{self, Cancel};
+use crate::units;
+use crate::users;
 use crate::utils;
 
 pub type CommandResult = serenity::Result<Option<i32>>;
@@ -18,18 +38,24 @@ pub struct Command {
 }
 
 pub static COMMANDS: &[Command] = &[
-    VERSION, SAY, PING, COUNT, COUNTTOP, 
-    EVAL, ROLL, FLIP, EIGHTBALL, 
+    VERSION, SAY, PING, COUNT, COUNTSTATS, COUNTTOP, COUNTCOOLDOWN, SEASON, COUNTING,
+    EVAL, CONVERT, BASE, PLOT, ROLL, ODDS, SHEET, INIT, FLIP, EIGHTBALL, CHOOSE, SHUFFLE, RAND, DECK, FAIR,
     VOTE, POLL, WIKIPEDIA, XKCD, MEME, HELP
 ];
 
-pub fn dealias<'a>(name: &'a str) -> &'a str {
+pub fn dealias(name: &str) -> &str {
     match name {
         "calc" | "=" => "eval",
+        "units" => "convert",
+        "graph" => "plot",
         "dice" => "roll",
+        "initiative" => "init",
         "coinflip" => "flip",
         "?" => "help",
         "eightball" => "8ball",
+        "pick" => "choose",
+        "random" => "rand",
+        "cards" => "deck",
         "pong" => "ping",
         "wp" | "wiki" => "wikipedia",
         "imgflip" => "meme",
@@ -37,8 +63,20 @@ pub fn dealias<'a>(name: &'a str) -> &'a str {
     }
 }
 
+/// Bot admins and users with the Manage Server permission can change server settings
+pub async fn is_guild_admin(ctx: &Context, msg: &Message, state: &State) -> serenity::Result<bool> {
+    if state.is_admin(msg.author.id) {
+        return Ok(true)
+    }
+    match msg.guild_id {
+        Some(guild) => utils::has_permission(&ctx.http, guild, msg.author.id, Permissions::MANAGE_GUILD).await,
+        None => Ok(false)
+    }
+}
+
 pub async fn shutdown(ctx: &Context, msg: &Message, state: &mut State, code: i32) -> CommandResult {
     debug!("Shutdown requested by {}#{}", msg.author.name, msg.author.discriminator);
+    state.audit(msg.author.id, if code == 0 { "stopped the bot" } else { "restarted the bot" }, None);
     msg.channel_id.say(&ctx.http, ":wave: Cya!").await?;
     state.force_dirty();
     ctx.invisible().await;
@@ -84,7 +122,7 @@ pub async fn status(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
     Ok(None)
 }
 
-pub async fn add_cmd(rest: &str, state: &mut State) -> CommandResult {
+pub async fn add_cmd(msg: &Message, rest: &str, state: &mut State) -> CommandResult {
     let idx = match rest.find(" ") {
         Some(x) => x,
         None => return Ok(None)
@@ -93,22 +131,25 @@ pub async fn add_cmd(rest: &str, state: &mut State) -> CommandResult {
     let text = &rest[idx..];
     debug!("Command added: {}", name);
     state.add_cmd(name, text);
+    state.audit(msg.author.id, &format!("added the command `{}`", name), None);
     Ok(None)
 }
 
-pub async fn rm_cmd(rest: &str, state: &mut State) -> CommandResult {
+pub async fn rm_cmd(msg: &Message, rest: &str, state: &mut State) -> CommandResult {
     debug!("Command removed: {}", rest);
     state.rm_cmd(rest);
+    state.audit(msg.author.id, &format!("removed the command `{}`", rest), None);
     Ok(None)
 }
 
 pub async fn ban_unban(ctx: &Context, msg: &Message, state: &mut State, ban: bool) -> CommandResult {
-    if msg.mentions.len() > 0 {
+    if !msg.mentions.is_empty() {
         let user = &msg.mentions[0];
         let result = match ban {
             true => match state.ban(user.id) {
                 Ok(()) => {
                     debug!("User {}#{} banned by {}#{}", user.name, user.discriminator, msg.author.name, msg.author.discriminator);
+                    state.audit(msg.author.id, "banned", Some(user.id));
                     format!(":crab: Banned {}#{}", user.name, user.discriminator)
                 },
                 Err(e) => format!(":x: {}", e),
@@ -116,6 +157,7 @@ pub async fn ban_unban(ctx: &Context, msg: &Message, state: &mut State, ban: boo
             false => match state.unban(user.id) {
                 Ok(()) => {
                     debug!("User {}#{} unbanned by {}#{}", user.name, user.discriminator, msg.author.name, msg.author.discriminator);
+                    state.audit(msg.author.id, "unbanned", Some(user.id));
                     format!(":crab: Unbanned {}#{}", user.name, user.discriminator)
                 },
                 Err(e) => format!(":x: {}", e),
@@ -125,210 +167,1708 @@ pub async fn ban_unban(ctx: &Context, msg: &Message, state: &mut State, ban: boo
     } else {
         msg.channel_id.say(&ctx.http, ":x: No user specified").await?;
     }
-    Ok(None)
+    Ok(None)
+}
+
+pub async fn banned(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
+    let (context, channel, ids) = (ctx.clone(), msg.channel_id, state.get_banned());
+    // Look up users on a separate task so that the state isn't locked during requests
+    tokio::task::spawn(async move {
+        if let Err(e) = send_banned(&context, channel, ids).await {
+            warn!("Error in banned async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+async fn send_banned(ctx: &Context, channel: ChannelId, ids: Vec<UserId>) -> serenity::Result<()> {
+    let mut lines = Vec::new();
+    for id in ids {
+        let line = match users::fetch_user_unlocked(ctx, id).await {
+            Ok(user) => format!("{} ({})", user.tag(), id),
+            Err(_) => format!("Unknown user ({})", id)
+        };
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        channel.say(&ctx.http, ":crab: No users are banned").await?;
+    } else {
+        lines.sort();
+        channel.send_message(&ctx.http, |m| m.embed(|e| e
+            .title("Banned users")
+            .color(utils::HELP_COLOR)
+            .description(lines.join("\n"))
+        )).await?;
+    }
+    Ok(())
+}
+
+/// How many audit log entries `audit` shows
+const AUDIT_PAGE: usize = 20;
+
+/// Show the most recent admin actions, newest first
+pub async fn audit(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
+    let entries = state.get_audit_log().iter()
+        .rev()
+        .take(AUDIT_PAGE)
+        .cloned()
+        .collect::<Vec<AuditEntry>>();
+    if entries.is_empty() {
+        msg.channel_id.say(&ctx.http, ":scroll: The audit log is empty").await?;
+        return Ok(None)
+    }
+    let (context, channel, guild) = (ctx.clone(), msg.channel_id, msg.guild_id);
+    // Look up names on a separate task so that the state isn't locked during requests
+    tokio::task::spawn(async move {
+        if let Err(e) = send_audit(&context, channel, guild, entries).await {
+            warn!("Error in audit async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+async fn send_audit(ctx: &Context, channel: ChannelId, guild: Option<GuildId>, entries: Vec<AuditEntry>) -> serenity::Result<()> {
+    let ids = entries.iter()
+        .flat_map(|entry| std::iter::once(entry.user).chain(entry.target))
+        .unique()
+        .collect::<Vec<UserId>>();
+    let names = users::display_names_unlocked(ctx, guild, &ids).await?;
+    let now = utils::now_millis();
+    let lines = entries.iter()
+        .map(|entry| {
+            let ago = utils::format_duration(now.saturating_sub(entry.time));
+            match entry.target {
+                Some(target) => format!("`{} ago` **{}** {} **{}**", ago, names[&entry.user], entry.action, names[&target]),
+                None => format!("`{} ago` **{}** {}", ago, names[&entry.user], entry.action)
+            }
+        })
+        .collect::<Vec<String>>();
+    channel.send_message(&ctx.http, |m| m.embed(|e| e
+        .title("Audit log")
+        .color(utils::HELP_COLOR)
+        .description(lines.join("\n"))
+    )).await?;
+    Ok(())
+}
+
+pub static VERSION: Command = Command {
+    short: "Show version information",
+    aliases: &[],
+    usage: &["version"],
+    description: "Show version information.",
+    examples: &[],
+};
+pub async fn version(ctx: &Context, msg: &Message) -> CommandResult {
+    msg.channel_id.say(&ctx.http, "TriBot v0.1 by TriMill#6898\n<https://github.com/trimill/tribot>").await?;
+    Ok(None)
+}
+
+pub static SAY: Command = Command {
+    short: "Make the bot say something",
+    aliases: &[],
+    usage: &["say <message>"],
+    description: "Make the bot say something.",
+    examples: &[]
+};
+pub async fn say(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
+    if !rest.is_empty() {
+        msg.channel_id.say(&ctx.http, rest).await?;
+    }
+    Ok(None)
+}
+
+pub static PING: Command = Command {
+    short: "Ping the bot, showing the ping time",
+    aliases: &["pong"],
+    usage: &["ping"],
+    description: "Ping the bot, showing the time between sending the message and the bot recieving it.",
+    examples: &[]
+};
+pub async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
+    let now = chrono::offset::Utc::now().naive_utc();
+    let mtime = msg.timestamp.naive_utc();
+    let diff = (now - mtime).num_milliseconds();
+    msg.channel_id.say(&ctx.http, format!(":ping_pong: Pong! in {}ms", diff)).await?;
+    Ok(None)
+}
+
+pub static COUNT: Command = Command {
+    short: "Increase your count by 1",
+    aliases: &[],
+    usage: &["count", "count <@user>"],
+    description: "Increase your count by 1. This can be done once per hour per user by default, see `;countcooldown`. Mention a user to see their count and rank instead. View the leaderboard with `;counttop`",
+    examples: &["count", "count @TriMill"],
+};
+pub async fn count(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
+    if let Some(user) = msg.mentions.first() {
+        return count_lookup(ctx, msg, state, user).await
+    }
+    match state.count_up(msg.author.id, msg.guild_id) {
+        Ok(achievements) => {
+            let mut reply = format!(":hash: Count increased to {}! You can count again in {}.",
+                        state.get_count(msg.author.id),
+                        utils::format_duration(state.count_timeout(msg.guild_id)));
+            for achievement in achievements {
+                reply += &format!("\n:trophy: Achievement unlocked: **{}**", achievement.name());
+            }
+            msg.channel_id.say(&ctx.http, reply).await?
+        },
+        Err(n) => msg.channel_id.say(&ctx.http,
+                format!(":x: You must wait {} before doing that!", 
+                        utils::timeformat(n))).await?
+    };
+    Ok(None)
+}
+
+async fn count_lookup(ctx: &Context, msg: &Message, state: &mut State, user: &User) -> CommandResult {
+    state.users_mut().update_user(user);
+    let (count, counts) = (state.get_count(user.id), state.get_count_all());
+    let (context, channel, guild, user) = (ctx.clone(), msg.channel_id, msg.guild_id, user.id);
+    // Look up the server's members on a separate task so that the state isn't locked during requests
+    tokio::task::spawn(async move {
+        if let Err(e) = send_count_lookup(&context, channel, guild, user, count, counts).await {
+            warn!("Error in count async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+async fn send_count_lookup(ctx: &Context, channel: ChannelId, guild: Option<GuildId>, user: UserId,
+        count: u64, counts: Vec<(UserId, u64)>) -> serenity::Result<()> {
+    let name = users::display_names_unlocked(ctx, guild, &[user]).await?.remove(&user).unwrap_or_default();
+    if count == 0 {
+        channel.say(&ctx.http, format!(":hash: {} hasn't counted yet.", name)).await?;
+        return Ok(())
+    }
+    let mut places = Vec::new();
+    if let Some(guild) = guild {
+        let members = users::guild_members_unlocked(ctx, guild).await?;
+        let guild_rank = counts.iter()
+            .filter(|(u,_)| members.contains(u))
+            .position(|(u,_)| *u == user);
+        if let Some(i) = guild_rank {
+            places.push(format!("**#{}** in this server", i+1));
+        }
+    }
+    if let Some(i) = counts.iter().position(|(u,_)| *u == user) {
+        places.push(format!("**#{}** globally", i+1));
+    }
+    channel.say(&ctx.http, format!(":hash: {} has a count of **{}** ({})",
+        name, count, places.join(", "))).await?;
+    Ok(())
+}
+
+pub static COUNTSTATS: Command = Command {
+    short: "View counting statistics",
+    aliases: &[],
+    usage: &["countstats", "countstats <@user>"],
+    description: "View your or another user's counting streak, best streak, achievements, and rank history, with a graph of their counts over the last 30 days. Counting on consecutive days (UTC) builds a streak.",
+    examples: &["countstats", "countstats @TriMill"],
+};
+pub async fn countstats(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
+    let user = msg.mentions.first().unwrap_or(&msg.author);
+    let name = users::display_name(&ctx.http, state.users_mut(), msg.guild_id, user.id).await?;
+    let stats = match state.get_count_stats(user.id) {
+        Some(x) => x,
+        None => {
+            msg.channel_id.say(&ctx.http, format!(":hash: {} has no counting history yet.", name)).await?;
+            return Ok(None)
+        }
+    };
+    let today = counting::day_of(utils::now_millis());
+    let count = match state.get_count_rank(user.id) {
+        Some(rank) => format!("**{}** (#{} globally)", state.get_count(user.id), rank),
+        None => format!("**{}**", state.get_count(user.id))
+    };
+    let achievements = match stats.achievements.len() {
+        0 => "None yet".to_owned(),
+        _ => stats.achievements.iter()
+            .map(|a| format!(":trophy: {}", a.name()))
+            .join("\n")
+    };
+    let ranks = stats.rank_history.iter()
+        .rev().take(8).rev()
+        .map(|(_,r)| format!("#{}", r))
+        .join(" \u{2192} ");
+    let graph = utils::sparkline(&stats.daily_counts(today, 30));
+    msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+        .title(format!("Counting stats for {}", name))
+        .color(utils::WEB_COLOR)
+        .field("Count", count, true)
+        .field("Streak", format!("{} days (best {})", stats.streak(today), stats.best_streak), true)
+        .field("Achievements", achievements, false)
+        .field("Rank history", ranks, false)
+        .field("Last 30 days", format!("`{}`", graph), false)
+    )).await?;
+    Ok(None)
+}
+
+pub static COUNTCOOLDOWN: Command = Command {
+    short: "View or change the count cooldown",
+    aliases: &[],
+    usage: &["countcooldown", "countcooldown <duration>", "countcooldown reset"],
+    description: "View how long users must wait between counts in this server. Users with the Manage Server permission can change it, using a duration such as `2h` or `1h30m`, or reset it to the default of one hour. The cooldown is kept separately in each server, and can't be shorter than a minute.",
+    examples: &["countcooldown", "countcooldown 2h", "countcooldown reset"],
+};
+pub async fn countcooldown(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: The count cooldown can only be changed in a server").await?;
+            return Ok(None)
+        }
+    };
+    if rest.is_empty() {
+        msg.channel_id.say(&ctx.http, format!(":hash: The count cooldown is {}", 
+            utils::format_duration(state.count_timeout(Some(guild))))).await?;
+        return Ok(None)
+    }
+    if !is_guild_admin(ctx, msg, state).await? {
+        msg.channel_id.say(&ctx.http, ":x: You need the Manage Server permission to do that!").await?;
+        return Ok(None)
+    }
+    let timeout = match rest {
+        "reset" => None,
+        _ => match utils::parse_duration(rest) {
+            Some(t) => Some(t),
+            None => {
+                msg.channel_id.say(&ctx.http, ":x: Invalid duration. See `;help countcooldown`").await?;
+                return Ok(None)
+            }
+        }
+    };
+    if let Err(e) = state.set_count_timeout(guild, timeout) {
+        msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+        return Ok(None)
+    }
+    debug!("Count cooldown in {} set to {:?} by {}#{}", guild, timeout, msg.author.name, msg.author.discriminator);
+    msg.channel_id.say(&ctx.http, format!(":hash: Count cooldown set to {}", 
+        utils::format_duration(state.count_timeout(Some(guild))))).await?;
+    Ok(None)
+}
+
+pub static SEASON: Command = Command {
+    short: "View or manage counting seasons",
+    aliases: &[],
+    usage: &["season", "season list", "season start <name>", "season schedule <duration>", "season schedule off"],
+    description: "View the current counting season or list past seasons. Bot admins can start a new season, which archives the leaderboard and resets all counts, or schedule seasons to roll over automatically, at most once a day. View a past season with `;counttop season <name>`.",
+    examples: &["season", "season start Winter 2021", "season schedule 30d"],
+};
+pub async fn season(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    let admin = state.is_admin(msg.author.id);
+    match sub {
+        "" => {
+            let season = state.current_season();
+            let mut body = match &season.name {
+                Some(name) => format!(":calendar: The current season is **{}**", name),
+                None => ":calendar: The current season is unnamed".to_owned()
+            };
+            if season.started > 0 {
+                body += &format!(", started {} ago", 
+                    utils::format_duration(utils::now_millis() - season.started));
+            }
+            if let Some(next) = season.next_rollover {
+                body += &format!(". The next season starts in {}", 
+                    utils::format_duration(next.saturating_sub(utils::now_millis())));
+            }
+            msg.channel_id.say(&ctx.http, body).await?;
+        },
+        "list" => {
+            let body = state.get_seasons().iter()
+                .rev()
+                .map(|s| format!("**{}** ({} players)", s.name, s.standings.len()))
+                .join("\n");
+            match body.as_str() {
+                "" => msg.channel_id.say(&ctx.http, ":calendar: There are no past seasons").await?,
+                _ => msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+                    .title("Past seasons")
+                    .color(utils::WEB_COLOR)
+                    .description(body))).await?
+            };
+        },
+        "start" if admin => {
+            let name = match arg {
+                "" => None,
+                _ => Some(arg)
+            };
+            match state.start_season(name) {
+                Ok(archived) => {
+                    debug!("Season {} ended by {}#{}", archived, msg.author.name, msg.author.discriminator);
+                    msg.channel_id.say(&ctx.http, format!(":calendar: **{}** has ended and all counts have been reset. View the final standings with `;counttop season {}`", archived, archived)).await?;
+                },
+                Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?; }
+            }
+        },
+        "schedule" if admin => {
+            let interval = match arg {
+                "off" => None,
+                _ => match utils::parse_duration(arg) {
+                    Some(i) => Some(i),
+                    None => {
+                        msg.channel_id.say(&ctx.http, ":x: Invalid duration. See `;help season`").await?;
+                        return Ok(None)
+                    }
+                }
+            };
+            if let Err(e) = state.schedule_seasons(interval) {
+                msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+                return Ok(None)
+            }
+            match interval {
+                Some(i) => msg.channel_id.say(&ctx.http, format!(":calendar: A new season will start every {}", utils::format_duration(i))).await?,
+                None => msg.channel_id.say(&ctx.http, ":calendar: Seasons will no longer roll over automatically").await?
+            };
+        },
+        "start" | "schedule" => {
+            msg.channel_id.say(&ctx.http, ":x: You aren't authorised to do that!").await?;
+        },
+        _ => {
+            msg.channel_id.say(&ctx.http, ":x: Invalid argument. See `;help season`").await?;
+        }
+    }
+    Ok(None)
+}
+
+pub static COUNTING: Command = Command {
+    short: "Play the counting channel game",
+    aliases: &[],
+    usage: &["counting", "counting stats", "counting stats <@user>", "counting channel", "counting off", "counting expressions <on|off>"],
+    description: "In the counting channel, users take turns posting the next number. Nobody may count twice in a row, and a wrong number resets the count to zero. `counting` shows the current count and high score, and `counting stats` shows the top contributors. Users with the Manage Server permission can make the current channel the counting channel, turn the game off (keeping the high score and stats), or allow expressions (such as `2*3`) that evaluate to the next number.",
+    examples: &["counting", "counting stats @TriMill", "counting channel", "counting expressions on"],
+};
+pub async fn counting(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: The counting game can only be played in a server").await?;
+            return Ok(None)
+        }
+    };
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    if matches!(sub, "channel" | "off" | "expressions") && !is_guild_admin(ctx, msg, state).await? {
+        msg.channel_id.say(&ctx.http, ":x: You need the Manage Server permission to do that!").await?;
+        return Ok(None)
+    }
+    match sub {
+        "channel" => {
+            state.set_counting_channel(guild, Some(msg.channel_id));
+            msg.channel_id.say(&ctx.http, ":1234: This is now the counting channel. Start counting from **1**!").await?;
+        },
+        "off" => {
+            state.set_counting_channel(guild, None);
+            msg.channel_id.say(&ctx.http, ":1234: The counting game has been turned off").await?;
+        },
+        "expressions" => {
+            let enabled = match arg {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    msg.channel_id.say(&ctx.http, ":x: Invalid argument. See `;help counting`").await?;
+                    return Ok(None)
+                }
+            };
+            match state.set_counting_expressions(guild, enabled) {
+                Ok(()) if enabled => msg.channel_id.say(&ctx.http, ":1234: Expressions are now allowed in the counting channel").await?,
+                Ok(()) => msg.channel_id.say(&ctx.http, ":1234: Only plain numbers are now allowed in the counting channel").await?,
+                Err(e) => msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?
+            };
+        },
+        "" | "stats" => {
+            let game = match state.get_counting_game(guild) {
+                Some(g) => g,
+                None => {
+                    msg.channel_id.say(&ctx.http, ":x: This server has no counting channel").await?;
+                    return Ok(None)
+                }
+            };
+            if sub.is_empty() && game.disabled {
+                msg.channel_id.say(&ctx.http, format!(":1234: The counting game is off. The high score is **{}**.",
+                    game.high_score)).await?;
+                return Ok(None)
+            } else if sub.is_empty() {
+                msg.channel_id.say(&ctx.http, format!(":1234: The count in <#{}> is at **{}**. The high score is **{}**.",
+                    game.channel, game.current, game.high_score)).await?;
+                return Ok(None)
+            }
+            if let Some(user) = msg.mentions.first() {
+                let player = game.players.get(&user.id).cloned().unwrap_or_default();
+                let name = users::display_name(&ctx.http, state.users_mut(), Some(guild), user.id).await?;
+                msg.channel_id.say(&ctx.http, format!(":1234: {} has counted **{}** numbers and made **{}** mistakes",
+                    name, player.correct, player.mistakes)).await?;
+                return Ok(None)
+            }
+            let mut players = game.players.iter()
+                .map(|(u,p)| (*u, p.clone()))
+                .collect::<Vec<(UserId, counting::CountingPlayer)>>();
+            players.sort_by_key(|(_,p)| u64::MAX - p.correct);
+            let mut body = String::new();
+            for (i, (id, player)) in players.iter().take(utils::PAGE_SIZE).enumerate() {
+                let name = users::display_name(&ctx.http, state.users_mut(), Some(guild), *id).await?;
+                body += &format!("**#{}** {} (**{}** counted, **{}** mistakes)\n", i+1, name, player.correct, player.mistakes);
+            }
+            if body.is_empty() {
+                body += "Nobody has counted yet.";
+            }
+            msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+                .title("Top counters")
+                .color(utils::WEB_COLOR)
+                .description(body))).await?;
+        },
+        _ => {
+            msg.channel_id.say(&ctx.http, ":x: Invalid argument. See `;help counting`").await?;
+        }
+    }
+    Ok(None)
+}
+
+/// Handle a message sent in a guild's counting channel
+pub async fn counting_message(ctx: &Context, msg: &Message, guild: GuildId, number: f64, state: &mut State) -> serenity::Result<()> {
+    let result = match state.submit_count(guild, msg.author.id, number) {
+        Some(r) => r,
+        None => return Ok(())
+    };
+    let high_score = state.get_counting_game(guild).map(|g| g.high_score).unwrap_or(0);
+    let reason = match result {
+        counting::CountingResult::Correct => {
+            msg.react(&ctx.http, '\u{2705}').await?;
+            return Ok(())
+        },
+        counting::CountingResult::WrongNumber { expected, reached } => 
+            format!("ruined it at **{}**! The next number was **{}**", reached, expected),
+        counting::CountingResult::TwiceInARow { reached } => 
+            format!("ruined it at **{}**! You can't count twice in a row", reached),
+    };
+    msg.react(&ctx.http, '\u{274C}').await?;
+    msg.channel_id.say(&ctx.http, format!(":x: {} {}. The high score is **{}**. Start again from **1**.",
+        msg.author.mention(), reason, high_score)).await?;
+    Ok(())
+}
+
+pub static COUNTTOP: Command = Command {
+    short: "View the top players by count",
+    aliases: &[],
+    usage: &["counttop", "counttop global", "counttop [global] season <name>"],
+    description: "View the top players by count in this server, as well as your place on the leaderboard. Use `global` to include every user, and `season` to view the final standings of a past season. React with :arrow_backward: and :arrow_forward: to change pages.",
+    examples: &["counttop", "counttop global", "counttop season Season 1"],
+};
+pub async fn counttop(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let mut words = rest.split_whitespace().collect::<Vec<&str>>();
+    let guild = match words.first() {
+        Some(&"global") => {
+            words.remove(0);
+            None
+        },
+        _ => msg.guild_id
+    };
+    let season = match words.split_first() {
+        None => None,
+        Some((&"season", name)) if !name.is_empty() => Some(name.join(" ")),
+        _ => {
+            msg.channel_id.say(&ctx.http, ":x: Invalid argument. See `;help counttop`").await?;
+            return Ok(None)
+        }
+    };
+    let (season, entries) = match season {
+        Some(name) => match state.get_season(&name) {
+            Some(s) => (Some(s.name.clone()), s.standings.clone()),
+            None => {
+                msg.channel_id.say(&ctx.http, format!(":x: No season named `{}`. Use `;season list` to see past seasons.", name)).await?;
+                return Ok(None)
+            }
+        },
+        None => (state.current_season().name.clone(), state.get_count_all())
+    };
+    let leaderboard = Leaderboard {
+        guild,
+        season,
+        requester: msg.author.id,
+        entries,
+        page: 0,
+    };
+    let (context, channel) = (ctx.clone(), msg.channel_id);
+    // Look up the server's members and names on a separate task so that the
+    // state isn't locked during requests
+    tokio::task::spawn(async move {
+        if let Err(e) = send_counttop(&context, channel, leaderboard).await {
+            warn!("Error in counttop async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+async fn send_counttop(ctx: &Context, channel: ChannelId, mut leaderboard: Leaderboard) -> serenity::Result<()> {
+    if let Some(guild) = leaderboard.guild {
+        let members = users::guild_members_unlocked(ctx, guild).await?;
+        leaderboard.entries.retain(|(u,_)| members.contains(u));
+    }
+    let names = users::display_names_unlocked(ctx, leaderboard.guild, &leaderboard_ids(&leaderboard)).await?;
+    let top_msg = channel.send_message(&ctx.http, |m| m.embed(|e|
+            leaderboard_embed(e, &leaderboard, &names))).await?;
+    if leaderboard.pages(utils::PAGE_SIZE) > 1 {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.add_leaderboard(top_msg.id, leaderboard);
+        }
+        top_msg.react(&ctx.http, ReactionType::Unicode(utils::PAGE_PREV.to_owned())).await?;
+        top_msg.react(&ctx.http, ReactionType::Unicode(utils::PAGE_NEXT.to_owned())).await?;
+    }
+    Ok(())
+}
+
+pub async fn counttop_turn_page(ctx: &Context, reaction: &Reaction) -> serenity::Result<()> {
+    if let Some(user) = reaction.user_id {
+        let res = ctx.http.delete_reaction(reaction.channel_id.into(), reaction.message_id.into(),
+            Some(user.into()), &reaction.emoji).await;
+        if let Err(e) = res {
+            warn!("Could not remove reaction: {:?}", e);
+        }
+    }
+    // Only hold the lock while changing the page, not while editing the message
+    let leaderboard = {
+        let mut data = ctx.data.write().await;
+        let leaderboard = match data.get_mut::<State>().and_then(|s| s.leaderboard_mut(reaction.message_id)) {
+            Some(x) => x,
+            None => return Ok(())
+        };
+        let last_page = leaderboard.pages(utils::PAGE_SIZE) - 1;
+        let page = match &reaction.emoji {
+            ReactionType::Unicode(s) if s == utils::PAGE_PREV => leaderboard.page.saturating_sub(1),
+            ReactionType::Unicode(s) if s == utils::PAGE_NEXT => (leaderboard.page + 1).min(last_page),
+            _ => leaderboard.page
+        };
+        if page == leaderboard.page {
+            return Ok(())
+        }
+        leaderboard.page = page;
+        leaderboard.clone()
+    };
+    let names = users::display_names_unlocked(ctx, leaderboard.guild, &leaderboard_ids(&leaderboard)).await?;
+    reaction.channel_id.edit_message(&ctx.http, reaction.message_id, |m| m.embed(|e|
+        leaderboard_embed(e, &leaderboard, &names))).await?;
+    Ok(())
+}
+
+/// The users on the current page of a leaderboard, and its requester
+fn leaderboard_ids(leaderboard: &Leaderboard) -> Vec<UserId> {
+    leaderboard.entries.iter()
+        .skip(leaderboard.page * utils::PAGE_SIZE)
+        .take(utils::PAGE_SIZE)
+        .map(|(u,_)| *u)
+        .chain(std::iter::once(leaderboard.requester))
+        .collect()
+}
+
+fn leaderboard_embed<'a>(e: &'a mut CreateEmbed, leaderboard: &Leaderboard, names: &HashMap<UserId, String>) -> &'a mut CreateEmbed {
+    let start = leaderboard.page * utils::PAGE_SIZE;
+    let name = |id: &UserId| names.get(id).cloned().unwrap_or_else(|| id.to_string());
+    let mut body = leaderboard.entries.iter()
+        .enumerate()
+        .skip(start)
+        .take(utils::PAGE_SIZE)
+        .map(|(i,(id,count))| 
+             format!("**#{}** {} (**{}**)", i+1, name(id), count))
+        .collect::<Vec<String>>()
+        .join("\n");
+    if body.is_empty() {
+        body += "Nobody has counted yet.";
+    }
+    match leaderboard.rank_of(leaderboard.requester) {
+        Some(rank) if (rank-1) / utils::PAGE_SIZE == leaderboard.page => (),
+        Some(rank) => {
+            let count = leaderboard.entries[rank-1].1;
+            body += &format!("\n...\n**#{}** {} (**{}**)", 
+                rank, name(&leaderboard.requester), count);
+        },
+        None => {
+            body += &format!("\n...\n{} hasn't counted yet.", name(&leaderboard.requester));
+        }
+    }
+    let scope = match leaderboard.guild {
+        Some(_) => "this server",
+        None => "global"
+    };
+    e.title(match &leaderboard.season {
+        Some(season) => format!("Top count ({}, {})", scope, season),
+        None => format!("Top count ({})", scope)
+    });
+    e.color(utils::WEB_COLOR);
+    e.description(body);
+    e.footer(|f| f.text(format!("Page {}/{}", 
+        leaderboard.page+1, leaderboard.pages(utils::PAGE_SIZE))));
+    e
+}
+
+pub static EVAL: Command = Command {
+    short: "Evaluate an expression",
+    aliases: &["calc", "="],
+    usage: &["eval <expr>", "eval <name> = <expr>", "eval <name>(<params>) = <expr>", "eval vars", "eval clear", "eval exact <expr>", "eval mode <exact|float>", "eval precision <places>", "eval <value> <unit> to <unit>"],
+    description: "Evaluate a mathematical expression. See <https://docs.rs/meval/0.2.0/meval/#supported-expressions> for the operators and functions.\nYou can assign variables (`x = 2`) and define functions (`f(x) = x^2`), which are kept until `eval clear`. `eval vars` lists them, and `ans` holds the last result.\n`eval exact` uses big integers and exact fractions, with `!` and functions such as `gcd`, `powmod`, `binom` and `isprime`, but not your variables or functions. `eval mode exact` makes it the default, and `eval precision` sets the decimal places shown.\nComplex numbers (`2+3i`), matrices (`[1, 2; 3, 4]`) and lists work too, with functions such as `det`, `inv`, `conj`, `mean`, `median` and `stdev`.\n`<value> <unit> to <unit>` converts units, see `;help convert`. Expressions with `0x`, `0b` or `0o` numbers or bitwise operators use 64 bit integers, see `;help base`.\nExpressions can be up to 500 characters long and must finish within 3 seconds.",
+    examples: &["eval sin(3/4*pi)", "eval 0.5 + sqrt(5)/2", "eval floor(e^3)+1", "eval x = 3.2", "eval f(x) = x^2 + 1", "eval f(x) * ans", "eval exact 2^100 + 1/3", "eval exact binom(100, 50)", "eval precision 50", "eval 0xff & ~0b1010", "eval 1 << 40", "eval (1+2i)*(3-i)", "eval inv([1, 2; 3, 4])", "eval stdev([2, 4, 4, 5, 7])", "eval 5 km/h to m/s"]
+};
+pub async fn eval(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let user = msg.author.id;
+    match rest.trim() {
+        "vars" => {
+            let workspace = state.get_workspace(user).cloned().unwrap_or_default();
+            if workspace.is_empty() {
+                msg.channel_id.say(&ctx.http, ":abacus: You haven't defined anything yet").await?;
+                return Ok(None)
+            }
+            let mut lines = Vec::new();
+            if let Some(ans) = workspace.ans {
+                lines.push(format!("`ans = {}`", ans));
+            }
+            lines.extend(workspace.vars.iter().map(|(k,v)| format!("`{} = {}`", k, v)));
+            lines.extend(workspace.funcs.iter().map(|f| format!("`{}({}) = {}`", f.name, f.params.join(", "), f.body)));
+            say_or(ctx, msg.channel_id, lines.join("\n"),
+                String::from(":x: Too many definitions to display, use `;eval clear` to start again")).await?;
+        },
+        "clear" => {
+            state.clear_workspace(user);
+            msg.channel_id.say(&ctx.http, ":abacus: Your variables and functions have been cleared").await?;
+        },
+        "mode exact" | "mode float" => {
+            let exact = rest.trim() == "mode exact";
+            state.set_exact_mode(user, exact);
+            match exact {
+                true => msg.channel_id.say(&ctx.http, ":abacus: Now evaluating with exact arithmetic").await?,
+                false => msg.channel_id.say(&ctx.http, ":abacus: Now evaluating with floating point arithmetic").await?
+            };
+        },
+        x if x == "precision" || x.starts_with("precision ") => {
+            match x["precision".len()..].trim().parse::<usize>() {
+                Ok(p) if p <= exact::MAX_PRECISION => {
+                    state.set_precision(user, p);
+                    msg.channel_id.say(&ctx.http, format!(":abacus: Exact results will be shown to {} decimal places", p)).await?;
+                },
+                _ => { msg.channel_id.say(&ctx.http, format!(":x: Precision must be a whole number up to {}", exact::MAX_PRECISION)).await?; }
+            }
+        },
+        input => {
+            if let Err(e) = sandbox::check_input(input) {
+                msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+                return Ok(None)
+            }
+            let workspace = state.get_workspace(user).cloned().unwrap_or_default();
+            let (context, channel, input) = (ctx.clone(), msg.channel_id, input.to_owned());
+            // Evaluate on a separate task so that the state isn't locked while it runs
+            tokio::task::spawn(async move {
+                let text = match sandbox::run(move |cancel| evaluate(&input, workspace, cancel)).await {
+                    Ok((text, Some(workspace))) => {
+                        let stored = match context.data.write().await.get_mut::<State>() {
+                            Some(state) => state.update_workspace(user, workspace),
+                            None => Ok(())
+                        };
+                        match stored {
+                            Ok(()) => text,
+                            Err(e) => format!("{}\n:warning: Note: {}", text, e)
+                        }
+                    },
+                    Ok((text, None)) => text,
+                    Err(e) => format!(":x: {}", e)
+                };
+                let fallback = String::from(":x: The result is too long to display");
+                if let Err(e) = say_or(&context, channel, text, fallback).await {
+                    warn!("Error in eval async block: {:?}", e);
+                }
+            });
+        }
+    }
+    Ok(None)
+}
+
+pub static CONVERT: Command = Command {
+    short: "Convert between units",
+    aliases: &["units"],
+    usage: &["convert <value> <unit> to <unit>"],
+    description: "Convert a value between units of length, area, volume, mass, time, speed, temperature, data, energy and power. Units can be combined with `*`, `/` and powers, such as `km/h` or `m/s^2`, and most units accept SI prefixes. Data units also accept binary prefixes such as `KiB`. The value can be any expression that `eval` understands. Temperatures are converted as absolute temperatures unless they are combined with other units.",
+    examples: &["convert 72 F to C", "convert 3 cups to ml", "convert 5 km/h to m/s", "convert 1 GiB to MB", "convert 1 kWh to kcal", "convert 6 ft to m"]
+};
+pub async fn convert(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
+    if let Err(e) = sandbox::check_input(rest) {
+        msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+        return Ok(None)
+    }
+    let (context, channel, input) = (ctx.clone(), msg.channel_id, rest.to_owned());
+    tokio::task::spawn(async move {
+        let text = sandbox::run(move |cancel| convert_text(&input, cancel)).await
+            .unwrap_or_else(|e| format!(":x: {}", e));
+        if let Err(e) = channel.say(&context.http, text).await {
+            warn!("Error in convert async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+pub static BASE: Command = Command {
+    short: "Convert numbers between bases",
+    aliases: &[],
+    usage: &["base <value>", "base <value> from <base>", "base <value> to <bases>", "base <value> as <width>"],
+    description: "Show a 64 bit integer in decimal, hexadecimal, octal and binary. The value can use `0x`, `0b` and `0o` numbers and the bitwise operators `&`, `|`, `~`, `<<` and `>>` like `eval`, where `^` is exclusive or and `**` is used for powers, or be written in any base from 2 to 36 with `from`. `to` picks which bases to show, separated by commas. `as` truncates the value to a signed or unsigned width (`i8`, `u8`, `i16`, `u16`, `i32`, `u32`, `i64` or `u64`), and bases other than decimal show the bits of the value, so negative numbers are shown in two's complement.",
+    examples: &["base 255", "base 0xdead_beef", "base -1 as u16", "base zz from 36 to 10, 2", "base 1 << 12 to 16"]
+};
+pub async fn base(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
+    if let Err(e) = sandbox::check_input(rest) {
+        msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+        return Ok(None)
+    }
+    let (context, channel, input) = (ctx.clone(), msg.channel_id, rest.to_owned());
+    tokio::task::spawn(async move {
+        let text = match sandbox::run(move |cancel| base_text(&input, cancel)).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(e)) | Err(e) => format!(":x: {}", e)
+        };
+        if let Err(e) = channel.say(&context.http, text).await {
+            warn!("Error in base async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+/// Parse `<value> [from <base>] [to <bases>] [as <width>]` and show the value
+fn base_text(input: &str, cancel: &Cancel) -> Result<String, String> {
+    let mut rest = input.trim();
+    let mut take = |keyword: &str| match rest.rfind(keyword) {
+        Some(i) => {
+            let arg = rest[i + keyword.len()..].trim().to_owned();
+            rest = rest[..i].trim();
+            Some(arg)
+        },
+        None => None
+    };
+    let parse_base = |s: &str| match s.trim().parse::<u32>() {
+        Ok(b) if (2..=36).contains(&b) => Ok(b),
+        _ => Err(format!("`{}` isn't a base from 2 to 36", s.trim()))
+    };
+    let width = match take(" as ") {
+        Some(w) => bits::Width::parse(&w).ok_or_else(|| format!("`{}` isn't a width, use one of `i8`, `u8`, `i16`, `u16`, `i32`, `u32`, `i64` or `u64`", w))?,
+        None => bits::Width::DEFAULT
+    };
+    let bases = match take(" to ") {
+        Some(b) => b.split(',').map(parse_base).collect::<Result<Vec<u32>, String>>()?,
+        None => bits::DEFAULT_BASES.to_vec()
+    };
+    let value = match take(" from ") {
+        Some(b) => bits::parse_radix(rest, parse_base(&b)?)?,
+        None => bits::eval(rest, &BTreeMap::new(), cancel)?
+    };
+    let (table, fits) = bits::table(value, width, &bases);
+    let warning = match fits {
+        true => "",
+        false => "\n:warning: The value doesn't fit, so it has been truncated"
+    };
+    Ok(format!(":1234: `{}` as `{}`:\n```\n{}\n```{}", rest, width.name(), table, warning))
+}
+
+/// Evaluate an `eval` expression, returning the reply and the workspace if it changed
+fn evaluate(input: &str, mut workspace: calc::Workspace, cancel: &Cancel) -> (String, Option<calc::Workspace>) {
+    if units::is_conversion(input, cancel) {
+        return (convert_text(input, cancel), None)
+    }
+    if !input.starts_with("exact ") && bits::is_programmer(input) {
+        let mut vars = workspace.vars.clone();
+        if let Some(ans) = workspace.ans {
+            vars.insert(String::from("ans"), ans);
+        }
+        return match bits::eval(input, &vars, cancel) {
+            Ok(x) => {
+                workspace.ans = Some(x as f64);
+                (format!("Result: `{}` (`{:#x}`)", x, x), Some(workspace))
+            },
+            Err(e) => (format!(":x: {}", e), None)
+        }
+    }
+    if input.starts_with("exact ") || workspace.exact {
+        let expr = input.strip_prefix("exact ").unwrap_or(input);
+        // Variables hold floating point values, so they can't be used or
+        // assigned exactly
+        if expr.contains('=') {
+            let text = match workspace.exact {
+                true => ":x: Variables and functions can't be defined in exact mode, switch back with `;eval mode float`",
+                false => ":x: Variables and functions can't be defined in exact mode"
+            };
+            return (String::from(text), None)
+        }
+        let text = match exact::eval(expr, cancel) {
+            Ok(v) => format!("Result: `{}`", exact::format(&v, workspace.precision())),
+            Err(e) => format!(":x: Error evaluating exactly: {}", e)
+        };
+        return (text, None)
+    }
+    let result = workspace.eval(input, cancel);
+    // Fall back to complex numbers and matrices for anything meval can't do,
+    // such as `(1+2i)*(3-i)`, `det([1, 2; 3, 4])` or `sqrt(-1)`
+    let fallback = match &result {
+        Ok(calc::Outcome::Value(v)) if !v.is_nan() => None,
+        Ok(calc::Outcome::Value(_)) | Err(_) => {
+            let mut vars = workspace.vars.clone();
+            if let Some(ans) = workspace.ans.filter(|a| !a.is_nan()) {
+                vars.insert(String::from("ans"), ans);
+            }
+            Some(matrix::eval(input, &vars, cancel))
+        },
+        Ok(_) => None
+    };
+    match (result, fallback) {
+        (_, Some(Ok(m))) => {
+            let limit = utils::MAX_MESSAGE_LEN - "Result:\n".len();
+            let text = match matrix::format(&m, limit) {
+                Some(s) if s.starts_with("```") => format!("Result:\n{}", s),
+                Some(s) => format!("Result: {}", s),
+                None => String::from(":x: The result is too large to display")
+            };
+            match m.real() {
+                Some(x) => {
+                    workspace.ans = Some(x);
+                    (text, Some(workspace))
+                },
+                None => (text, None)
+            }
+        },
+        // meval's errors are more helpful, except for matrices which it can't parse at all
+        (Err(_), Some(Err(e))) if input.contains('[') => (format!(":x: {}", e), None),
+        (Ok(calc::Outcome::Value(v)), _) => (format!("Result: `{}`", v), Some(workspace)),
+        (Ok(calc::Outcome::Assigned(name, v)), _) => (format!("`{}` = `{}`", name, v), Some(workspace)),
+        (Ok(calc::Outcome::Defined(name)), _) => (format!("Defined function `{}`", name), Some(workspace)),
+        (Err(e), _) => (format!(":x: {}", e), None)
+    }
+}
+
+fn convert_text(input: &str, cancel: &Cancel) -> String {
+    match units::convert(input, cancel) {
+        Ok((value, unit)) => {
+            let from = input[..input.rfind(" to ").unwrap()].trim();
+            format!(":straight_ruler: `{}` = `{} {}`", from, utils::format_number(value), unit)
+        },
+        Err(e) => format!(":x: Could not convert: {}", e)
+    }
+}
+
+pub static PLOT: Command = Command {
+    short: "Plot functions",
+    aliases: &["graph"],
+    usage: &["plot <expr>", "plot <expr>; <expr>... from <a> to <b>"],
+    description: "Plot up to six expressions in `x` on the same graph. Expressions are separated by `;` and support everything that `eval` does, see `;help eval`. The range defaults to -10 to 10, and the vertical range is chosen to fit the values, leaving out spikes near asymptotes. Gaps are left where an expression is undefined or jumps.",
+    examples: &["plot sin(x)", "plot x^2; 2*x + 1 from -3 to 3", "plot tan(x) from -pi to pi", "plot sqrt(x) from 0 to 100"]
+};
+pub async fn plot(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
+    let args = sandbox::check_input(rest).and_then(|_| plot::parse_args(rest));
+    let (exprs, lo, hi) = match args {
+        Ok(args) => args,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: Could not plot: {}", e)).await?;
+            return Ok(None)
+        }
+    };
+    let (context, channel) = (ctx.clone(), msg.channel_id);
+    tokio::task::spawn(async move {
+        let image = sandbox::run(move |cancel| plot::plot(&exprs, lo, hi, cancel)).await
+            .and_then(|image| image);
+        let sent = match image {
+            Ok(png) => channel.send_files(&context.http, vec![(png.as_slice(), "plot.png")], |m| m).await,
+            Err(e) => channel.say(&context.http, format!(":x: Could not plot: {}", e)).await
+        };
+        if let Err(e) = sent {
+            warn!("Error in plot async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+
+pub static ROLL: Command = Command {
+    short: "Roll dice",
+    aliases: &["dice"],
+    usage: &["roll <dice>", "roll nosort <dice>", "roll stats <dice>", "roll <name>", "roll save <name> <dice>", "roll edit <name> <dice>", "roll delete <name>", "roll list"],
+    description: "Roll dice. Supports dice with arbitrary sides, constants, `+ - * /` and parentheses, as well as keeping or dropping the highest or lowest dice (`kh`, `kl`, `dh`, `dl`), exploding dice (`!`), rerolls (`r`, or `ro` to reroll once), success counting (`>=`, `>`, `<=`, `<`, `=`) and Fudge dice (`dF`). Dropped and rerolled dice are crossed out, exploded dice are marked with `!` and successes are bold. See <https://en.wikipedia.org/wiki/Dice_notation> for dice notation information. Total number of dice must not exceed 2048. Use `roll stats` to calculate the odds instead of rolling, see `;help odds`.\nIn a server, rolls can be saved under a name with `roll save` and rolled again with `roll <name>`. Stats on your character sheet can be used in any roll as `@<stat>`, see `;help sheet`.",
+    examples: &["roll 2d6", "roll 1d20-1", "roll 4d6kh3", "roll 2d20kl1+5", "roll 3d6!", "roll 4d6r1", "roll 6d10>=7", "roll 4dF", "roll (2d8+1d6)*2", "roll save attack 1d20+@str", "roll attack"]
+};
+pub async fn roll(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    if matches!(sub, "save" | "edit" | "delete" | "list") {
+        return roll_saved(ctx, msg, sub, arg, state).await
+    }
+    if sub == "stats" {
+        return odds(ctx, msg, arg, state).await
+    }
+    let sort = sub != "nosort";
+    let dicestr = match sort {
+        false => arg,
+        true => rest,
+    }.trim();
+    if dicestr.is_empty() {
+        msg.channel_id.say(&ctx.http, ":game_die: No dice rolled").await?;
+        return Ok(None)
+    }
+    let sheet = msg.guild_id
+        .and_then(|g| state.get_sheet(g, msg.author.id))
+        .cloned()
+        .unwrap_or_default();
+    let (label, dicestr) = match sheet.get_roll(dicestr) {
+        Some(saved) => (format!("{} (`{}`)", dicestr, saved), saved.as_str()),
+        None => (format!("`{}`", dicestr), dicestr)
+    };
+    let prepared = sandbox::check_input(dicestr)
+        .and_then(|_| prepare_roll(msg, state, dicestr, sort, &sheet.stats).map_err(String::from));
+    let (expr, mut rng, id) = match prepared {
+        Ok(x) => x,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: Error rolling dice: {}", e)).await?;
+            return Ok(None)
+        }
+    };
+    let (context, channel) = (ctx.clone(), msg.channel_id);
+    tokio::task::spawn(async move {
+        let sent = match sandbox::run(move |cancel| dice::roll(&expr, &mut rng, sort, cancel)).await {
+            Ok(Ok(roll)) => {
+                let result = format!(":game_die: {}: {} = **{}**{}", label, roll.text, roll.total, result_id(&id));
+                let fallback = format!(":game_die: Too many rolls to display. Total: **{}**{}", roll.total, result_id(&id));
+                say_or(&context, channel, result, fallback).await
+            },
+            Ok(Err(e)) => channel.say(&context.http, format!(":x: Error rolling dice: {}", e)).await.map(|_| ()),
+            Err(e) => channel.say(&context.http, format!(":x: Error rolling dice: {}", e)).await.map(|_| ())
+        };
+        if let Err(e) = sent {
+            warn!("Error in roll async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+/// Parse dice and get the RNG to roll them with, using the guild's seed if
+/// it has seeded randomness on
+fn prepare_roll(msg: &Message, state: &mut State, dicestr: &str, sort: bool, stats: &BTreeMap<String, i64>) -> Result<(dice::Expr, Random, Option<String>), &'static str> {
+    let expr = dice::parse_with(dicestr, stats)?;
+    let draw = Draw::Roll { dice: dicestr.to_owned(), stats: stats.clone(), sort };
+    let (rng, id) = state.random(msg.guild_id, draw);
+    Ok((expr, rng, id))
+}
+
+/// Send `text`, or `fallback` if `text` is too long to fit in a message
+async fn say_or(ctx: &Context, channel: ChannelId, text: String, fallback: String) -> serenity::Result<()> {
+    match text.len() > utils::MAX_MESSAGE_LEN {
+        true => channel.say(&ctx.http, fallback).await?,
+        false => channel.say(&ctx.http, text).await?
+    };
+    Ok(())
+}
+
+/// Format the ID of a seeded result, to go at the end of a message
+fn result_id(id: &Option<String>) -> String {
+    match id {
+        Some(id) => format!(" `#{}`", id),
+        None => String::new()
+    }
+}
+
+async fn roll_saved(ctx: &Context, msg: &Message, sub: &str, arg: &str, state: &mut State) -> CommandResult {
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: Rolls can only be saved in a server").await?;
+            return Ok(None)
+        }
+    };
+    let user = msg.author.id;
+    let (name, expr) = match arg.find(' ') {
+        Some(i) => (&arg[..i], arg[i..].trim()),
+        None => (arg, "")
+    };
+    let exists = state.get_sheet(guild, user).and_then(|s| s.get_roll(name)).is_some();
+    match sub {
+        "save" | "edit" if name.is_empty() || expr.is_empty() => {
+            msg.channel_id.say(&ctx.http, format!(":x: Usage: `;roll {} <name> <dice>`", sub)).await?;
+        },
+        "edit" if !exists => {
+            msg.channel_id.say(&ctx.http, format!(":x: You have no saved roll named `{}`", name)).await?;
+        },
+        "save" | "edit" => match state.edit_sheet(guild, user, |s| s.save_roll(name, expr)) {
+            Ok(()) => { msg.channel_id.say(&ctx.http, format!(":floppy_disk: Saved `{}` as `{}`", name.to_lowercase(), expr)).await?; },
+            Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: Could not save roll: {}", e)).await?; }
+        },
+        "delete" => match state.edit_sheet(guild, user, |s| s.delete_roll(name)) {
+            true => { msg.channel_id.say(&ctx.http, format!(":wastebasket: Deleted `{}`", name.to_lowercase())).await?; },
+            false => { msg.channel_id.say(&ctx.http, format!(":x: You have no saved roll named `{}`", name)).await?; }
+        },
+        _ => return sheet(ctx, msg, "", state).await
+    }
+    Ok(None)
+}
+
+pub static SHEET: Command = Command {
+    short: "Manage your character sheet",
+    aliases: &[],
+    usage: &["sheet", "sheet set <stat> <value>", "sheet delete <stat>"],
+    description: "Show your character sheet for this server, which holds your saved rolls and stats. Stats are whole numbers that can be used in dice rolls as `@<stat>`, and are set with `sheet set` and removed with `sheet delete`. Setting an existing stat changes its value. Names may contain letters, numbers and underscores.",
+    examples: &["sheet", "sheet set dex 3", "sheet set str -1", "sheet delete dex"]
+};
+pub async fn sheet(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: Character sheets can only be used in a server").await?;
+            return Ok(None)
+        }
+    };
+    let user = msg.author.id;
+    let args = rest.split_whitespace().collect::<Vec<_>>();
+    match args.as_slice() {
+        ["set", name, value] => {
+            let value = match value.parse::<i64>() {
+                Ok(v) => v,
+                Err(_) => {
+                    msg.channel_id.say(&ctx.http, ":x: Stats must be whole numbers").await?;
+                    return Ok(None)
+                }
+            };
+            match state.edit_sheet(guild, user, |s| s.set_stat(name, value)) {
+                Ok(()) => { msg.channel_id.say(&ctx.http, format!(":scroll: Set `@{}` to **{}**", name.trim_start_matches('@').to_lowercase(), value)).await?; },
+                Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: Could not set stat: {}", e)).await?; }
+            }
+        },
+        ["delete", name] => match state.edit_sheet(guild, user, |s| s.delete_stat(name)) {
+            true => { msg.channel_id.say(&ctx.http, format!(":wastebasket: Deleted `@{}`", name.trim_start_matches('@').to_lowercase())).await?; },
+            false => { msg.channel_id.say(&ctx.http, format!(":x: You have no stat named `{}`", name)).await?; }
+        },
+        [] => {
+            let sheet = state.get_sheet(guild, user).cloned().unwrap_or_default();
+            let stats = match sheet.stats.is_empty() {
+                true => String::from("None"),
+                false => sheet.stats.iter().map(|(k,v)| format!("`@{}` **{}**", k, v)).join("\n")
+            };
+            let rolls = match sheet.rolls.is_empty() {
+                true => String::from("None"),
+                false => sheet.rolls.iter().map(|(k,v)| format!("`{}` `{}`", k, v)).join("\n")
+            };
+            let name = users::display_name(&ctx.http, state.users_mut(), Some(guild), user).await?;
+            msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+                .title(format!("Character sheet for {}", name))
+                .color(utils::HELP_COLOR)
+                .field("Stats", stats, true)
+                .field("Saved rolls", rolls, true))).await?;
+        },
+        _ => { msg.channel_id.say(&ctx.http, ":x: Invalid arguments. See `;help sheet`").await?; }
+    }
+    Ok(None)
+}
+
+pub static ODDS: Command = Command {
+    short: "Calculate the odds of a dice roll",
+    aliases: &[],
+    usage: &["odds <dice>", "odds <dice> vs <target>"],
+    description: "Calculate the exact distribution of results for dice notation accepted by `;roll`, showing the mean, standard deviation, minimum and maximum, and a histogram of the chance of each result (`=`) and of rolling at least that result (`>=`). If a target is given, also show the chance of meeting it. Exploding dice are followed up to 16 explosions deep.",
+    examples: &["odds 2d6", "odds 4d6kh3", "odds 1d20+5 vs 15", "odds 6d10>=7"]
+};
+pub async fn odds(ctx: &Context, msg: &Message, rest: &str, state: &State) -> CommandResult {
+    let (dicestr, target) = match rest.rfind(" vs ") {
+        Some(i) => match rest[i+4..].trim().parse::<i64>() {
+            Ok(t) => (rest[..i].trim(), Some(t)),
+            Err(_) => {
+                msg.channel_id.say(&ctx.http, ":x: Invalid target. See `;help odds`").await?;
+                return Ok(None)
+            }
+        },
+        None => (rest.trim(), None)
+    };
+    let stats = msg.guild_id
+        .and_then(|g| state.get_sheet(g, msg.author.id))
+        .map(|s| s.stats.clone())
+        .unwrap_or_default();
+    let expr = match sandbox::check_input(dicestr).and_then(|_| dice::parse_with(dicestr, &stats).map_err(String::from)) {
+        Ok(e) => e,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: Error calculating odds: {}", e)).await?;
+            return Ok(None)
+        }
+    };
+    let (context, channel, dicestr) = (ctx.clone(), msg.channel_id, dicestr.to_owned());
+    tokio::task::spawn(async move {
+        let result = sandbox::run(move |cancel| dice::distribution(&expr, cancel)).await
+            .and_then(|d| d.map_err(String::from));
+        let sent = match result {
+            Ok(dist) => {
+                let mut body = format!("Mean **{:.2}**, standard deviation **{:.2}**, min **{}**, max **{}**",
+                    dist.mean(), dist.stddev(), dist.min(), dist.max());
+                if let Some(t) = target {
+                    body += &format!("\nChance of at least {}: **{:.2}%**", t, dist.at_least(t) * 100.0);
+                }
+                body += &format!("\n```\n{}```", dist.histogram(24));
+                channel.send_message(&context.http, |m| m.embed(|e| e
+                    .title(format!("Odds for {}", dicestr))
+                    .color(utils::HELP_COLOR)
+                    .description(body))).await
+            },
+            Err(e) => channel.say(&context.http, format!(":x: Error calculating odds: {}", e)).await
+        };
+        if let Err(e) = sent {
+            warn!("Error in odds async block: {:?}", e);
+        }
+    });
+    Ok(None)
+}
+
+pub static INIT: Command = Command {
+    short: "Track initiative order",
+    aliases: &["initiative"],
+    usage: &["init", "init add <name> <dice>", "init next", "init remove <name>", "init delay <name>", "init delay <name> <initiative>", "init clear"],
+    description: "Track turn order for a tabletop game in this channel. `init add` rolls initiative for a combatant, using your stats and saved rolls (see `;help sheet`), and `init next` moves on to the next turn, starting combat at round 1 if it hasn't started yet. `init delay` moves a combatant to just after the combatant after them, or to a lower initiative if one is given, and `init clear` ends combat. The turn order is shown in a pinned message that is kept up to date, and `init` on its own posts it again.",
+    examples: &["init add Goblin 1d20+2", "init add Aria 1d20+@dex", "init next", "init delay Aria", "init delay Goblin 5", "init remove Goblin", "init clear"]
+};
+pub async fn init(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    let (name, arg) = match arg.find(' ') {
+        Some(i) => (&arg[..i], arg[i..].trim()),
+        None => (arg, "")
+    };
+    let channel = msg.channel_id;
+    if sub != "add" && state.get_initiative(channel).is_none() {
+        channel.say(&ctx.http, ":x: There is no initiative in this channel. Start with `;init add <name> <dice>`").await?;
+        return Ok(None)
+    }
+    match sub {
+        "" => return update_initiative_status(ctx, channel, state, true).await.map(|_| None),
+        "add" => {
+            if name.is_empty() || arg.is_empty() {
+                channel.say(&ctx.http, ":x: Usage: `;init add <name> <dice>`").await?;
+                return Ok(None)
+            }
+            let sheet = msg.guild_id
+                .and_then(|g| state.get_sheet(g, msg.author.id))
+                .cloned()
+                .unwrap_or_default();
+            let dicestr = sheet.get_roll(arg).map(String::as_str).unwrap_or(arg);
+            let prepared = sandbox::check_input(dicestr)
+                .and_then(|_| prepare_roll(msg, state, dicestr, true, &sheet.stats).map_err(String::from));
+            let (expr, rng, id) = match prepared {
+                Ok(x) => x,
+                Err(e) => {
+                    channel.say(&ctx.http, format!(":x: Error rolling dice: {}", e)).await?;
+                    return Ok(None)
+                }
+            };
+            let (context, name) = (ctx.clone(), name.to_owned());
+            // Roll on the worker pool, then add the combatant once the roll is done
+            tokio::task::spawn(async move {
+                if let Err(e) = add_combatant(&context, channel, name, expr, rng, id).await {
+                    warn!("Error in init async block: {:?}", e);
+                }
+            });
+            return Ok(None)
+        },
+        "next" => {
+            let tracker = state.initiative_mut(channel);
+            match tracker.next().map(|c| c.name.clone()) {
+                Ok(name) => {
+                    let text = format!(":crossed_swords: Round {}: it's **{}**'s turn", tracker.round, name);
+                    channel.say(&ctx.http, text).await?;
+                },
+                Err(e) => {
+                    channel.say(&ctx.http, format!(":x: Could not advance: {}", e)).await?;
+                    return Ok(None)
+                }
+            }
+        },
+        "remove" => match state.initiative_mut(channel).remove(name) {
+            Ok(c) => { channel.say(&ctx.http, format!(":crossed_swords: Removed **{}**", c.name)).await?; },
+            Err(e) => {
+                channel.say(&ctx.http, format!(":x: Could not remove combatant: {}", e)).await?;
+                return Ok(None)
+            }
+        },
+        "delay" => {
+            let initiative = match arg {
+                "" => None,
+                _ => match arg.parse::<i64>() {
+                    Ok(i) => Some(i),
+                    Err(_) => {
+                        channel.say(&ctx.http, ":x: Invalid initiative. See `;help init`").await?;
+                        return Ok(None)
+                    }
+                }
+            };
+            let tracker = state.initiative_mut(channel);
+            let text = match tracker.delay(name, initiative) {
+                Ok(c) => format!(":crossed_swords: **{}** delays to initiative **{}**", c.name, c.initiative),
+                Err(e) => {
+                    channel.say(&ctx.http, format!(":x: Could not delay: {}", e)).await?;
+                    return Ok(None)
+                }
+            };
+            let text = match tracker.current() {
+                Some(c) => format!("{}. It's **{}**'s turn", text, c.name),
+                None => text
+            };
+            channel.say(&ctx.http, text).await?;
+        },
+        "clear" => {
+            if let Some(status) = state.end_initiative(channel).and_then(|t| t.status) {
+                if let Err(e) = channel.unpin(&ctx.http, status).await {
+                    warn!("Could not unpin initiative status: {:?}", e);
+                }
+            }
+            channel.say(&ctx.http, ":crossed_swords: Combat is over").await?;
+            return Ok(None)
+        },
+        _ => {
+            channel.say(&ctx.http, ":x: Invalid subcommand. See `;help init`").await?;
+            return Ok(None)
+        }
+    }
+    update_initiative_status(ctx, channel, state, false).await?;
+    Ok(None)
+}
+
+/// Roll initiative for a new combatant and add them to the tracker
+async fn add_combatant(ctx: &Context, channel: ChannelId, name: String, expr: dice::Expr, mut rng: Random, id: Option<String>) -> serenity::Result<()> {
+    let roll = match sandbox::run(move |cancel| dice::roll(&expr, &mut rng, true, cancel)).await {
+        Ok(Ok(roll)) => roll,
+        Ok(Err(e)) => return channel.say(&ctx.http, format!(":x: Error rolling dice: {}", e)).await.map(|_| ()),
+        Err(e) => return channel.say(&ctx.http, format!(":x: Error rolling dice: {}", e)).await.map(|_| ())
+    };
+    let mut data = ctx.data.write().await;
+    let state = match data.get_mut::<State>() {
+        Some(s) => s,
+        None => return Ok(())
+    };
+    if let Err(e) = state.add_combatant(channel, &name, roll.total) {
+        return channel.say(&ctx.http, format!(":x: Could not add combatant: {}", e)).await.map(|_| ())
+    }
+    let result = format!(":crossed_swords: **{}** rolled initiative: {} = **{}**{}", name, roll.text, roll.total, result_id(&id));
+    let fallback = format!(":crossed_swords: **{}** rolled initiative: **{}**{}", name, roll.total, result_id(&id));
+    say_or(ctx, channel, result, fallback).await?;
+    update_initiative_status(ctx, channel, state, false).await
 }
 
-pub static VERSION: Command = Command {
-    short: "Show version information",
-    aliases: &[],
-    usage: &["version"],
-    description: "Show version information.",
-    examples: &[],
-};
-pub async fn version(ctx: &Context, msg: &Message) -> CommandResult {
-    msg.channel_id.say(&ctx.http, "TriBot v0.1 by TriMill#6898\n<https://github.com/trimill/tribot>").await?;
-    Ok(None)
+/// Edit the initiative status message, or post and pin a new one if there
+/// isn't one, it was deleted, or `repost` is set
+async fn update_initiative_status(ctx: &Context, channel: ChannelId, state: &mut State, repost: bool) -> serenity::Result<()> {
+    let tracker = match state.get_initiative(channel) {
+        Some(t) => t,
+        None => return Ok(())
+    };
+    if let (Some(status), false) = (tracker.status, repost) {
+        let edit = channel.edit_message(&ctx.http, status, |m| m.embed(|e| initiative_embed(e, tracker))).await;
+        if edit.is_ok() {
+            return Ok(())
+        }
+    }
+    let old = tracker.status;
+    let status = channel.send_message(&ctx.http, |m| m.embed(|e| initiative_embed(e, tracker))).await?;
+    if let Some(old) = old {
+        if let Err(e) = channel.unpin(&ctx.http, old).await {
+            warn!("Could not unpin initiative status: {:?}", e);
+        }
+    }
+    if let Err(e) = status.pin(&ctx.http).await {
+        warn!("Could not pin initiative status: {:?}", e);
+    }
+    state.initiative_mut(channel).status = Some(status.id);
+    Ok(())
 }
 
-pub static SAY: Command = Command {
-    short: "Make the bot say something",
-    aliases: &[],
-    usage: &["say <message>"],
-    description: "Make the bot say something.",
-    examples: &[]
+fn initiative_embed<'a>(e: &'a mut CreateEmbed, tracker: &initiative::Initiative) -> &'a mut CreateEmbed {
+    let mut body = tracker.combatants.iter()
+        .enumerate()
+        .map(|(i,c)| match tracker.started() && i == tracker.turn {
+            true => format!(":arrow_forward: **{}** ({})", c.name, c.initiative),
+            false => format!(":black_small_square: {} ({})", c.name, c.initiative)
+        })
+        .join("\n");
+    if body.is_empty() {
+        body += "No combatants. Add one with `;init add <name> <dice>`.";
+    }
+    e.title(match tracker.started() {
+        true => format!("Initiative (round {})", tracker.round),
+        false => String::from("Initiative (not started)")
+    })
+    .color(utils::HELP_COLOR)
+    .description(body)
+}
+
+pub static FLIP: Command = Command {
+    short: "Flip coins",
+    aliases: &["coinflip"],
+    usage: &["flip", "flip <n>"],
+    description: "Flip the number of coins specified, or one by default. Number of coins must not exceed 2048.",
+    examples: &["flip 6", "flip"]
 };
-pub async fn say(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    if rest.len() > 0 {
-        msg.channel_id.say(&ctx.http, rest).await?;
+pub async fn flip(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let n = match rest {
+        "" => 1,
+        _ => match rest.parse::<u32>() {
+            Ok(n) => n,
+            Err(_) => return Ok(None)
+        }
+    };
+    if n > 2048 {
+        msg.channel_id.say(&ctx.http, ":x: Too many coins").await?;
+        return Ok(None)
     }
+    let ((heads, tails), id) = {
+        let (mut rng, id) = state.random(msg.guild_id, Draw::Flip(n));
+        (utils::flip_coins(n, &mut rng), id)
+    };
+    let res = match (n, heads) {
+        (1, 1) => format!(":coin: Heads!{}", result_id(&id)),
+        (1, _) => format!(":coin: Tails!{}", result_id(&id)),
+        _ => format!(":coin: Flipped {} coins, got {} heads and {} tails.{}", n, heads, tails, result_id(&id))
+    };
+    msg.channel_id.say(&ctx.http, res).await?;
     Ok(None)
 }
 
-pub static PING: Command = Command {
-    short: "Ping the bot, showing the ping time",
-    aliases: &["pong"],
-    usage: &["ping"],
-    description: "Ping the bot, showing the time between sending the message and the bot recieving it.",
-    examples: &[]
+pub static EIGHTBALL: Command = Command {
+    short: "Ask the Magic Eight Ball a question",
+    aliases: &["eightball"],
+    usage: &["8ball <question>", "8ball answers", "8ball answers add [positive|neutral|negative] [x<weight>] <answer>", "8ball answers remove <number>", "8ball answers reset"],
+    description: "Ask the Magic Eight Ball a yes/no question, returning a ~~random~~extremely accurate answer. The colour of the answer shows whether it is positive, neutral or negative.\nEach server can change its answers. `8ball answers` lists them, and users with the Manage Server permission can add answers, remove them by number, or reset them to the defaults. New answers are neutral unless a category is given, and answers with a higher weight are picked more often.",
+    examples: &["8ball do people secretly dislike me but are too afraid to tell me so they just pretend they like me", "8ball answers add positive x2 Absolutely!", "8ball answers remove 3"]
 };
-pub async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
-    let now = chrono::offset::Utc::now().naive_utc();
-    let mtime = msg.timestamp.naive_utc();
-    let diff = (now - mtime).num_milliseconds();
-    msg.channel_id.say(&ctx.http, format!(":ping_pong: Pong! in {}ms", diff)).await?;
+pub async fn eightball(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    if rest.is_empty() {
+        msg.channel_id.say(&ctx.http, ":8ball: You must ask the Magic Eight Ball a question.").await?;
+        return Ok(None)
+    }
+    // Only treat the message as a subcommand if it matches one exactly, so
+    // questions starting with "answers" are still answered
+    let sub = rest.strip_prefix("answers").map(str::trim_start);
+    let sub_name = sub.and_then(|s| s.split(' ').next());
+    if sub == Some("") || (rest.starts_with("answers ") && matches!(sub_name, Some("add" | "remove" | "reset"))) {
+        return eightball_answers(ctx, msg, sub.unwrap_or_default(), state).await
+    }
+    let answers = state.get_answers(msg.guild_id);
+    let (answer, id) = {
+        let (mut rng, id) = state.random_answer(msg.guild_id, &answers);
+        (eightball::pick(&answers, &mut rng).clone(), id)
+    };
+    msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+        .color(answer.category.color())
+        .description(format!(":8ball: {}{}", answer.text, result_id(&id))))).await?;
     Ok(None)
 }
 
-pub static COUNT: Command = Command {
-    short: "Increase your count by 1",
-    aliases: &[],
-    usage: &["count"],
-    description: "Increase your count by 1. This can be done once per hour per user. View the global leaderboard with `;counttop`",
-    examples: &[],
-};
-pub async fn count(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
-    match state.count_up(msg.author.id) {
-        0 => msg.channel_id.say(&ctx.http, 
-                format!(":hash: Count increased to {}! You can count again in 1hr.",
-                        state.get_count(msg.author.id))).await?,
-        n => msg.channel_id.say(&ctx.http,
-                format!(":x: You must wait {} before doing that!", 
-                        utils::timeformat(n))).await?
+async fn eightball_answers(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    if sub.is_empty() {
+        let answers = state.get_answers(msg.guild_id);
+        let lines = answers.iter()
+            .enumerate()
+            .map(|(i,a)| match a.weight {
+                1 => format!("`{}.` {} ({})", i+1, a.text, a.category.name()),
+                w => format!("`{}.` {} ({}, x{})", i+1, a.text, a.category.name(), w)
+            })
+            .collect::<Vec<String>>();
+        // Split the list over several embeds if it's too long for one
+        let mut pages = vec![String::new()];
+        for line in lines {
+            if pages.last().unwrap().len() + line.len() > utils::MAX_MESSAGE_LEN {
+                pages.push(String::new());
+            }
+            let page = pages.last_mut().unwrap();
+            *page += &line;
+            *page += "\n";
+        }
+        for page in pages {
+            msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
+                .title("Magic Eight Ball answers")
+                .color(utils::HELP_COLOR)
+                .description(page))).await?;
+        }
+        return Ok(None)
+    }
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: Answers can only be changed in a server").await?;
+            return Ok(None)
+        }
     };
+    if !is_guild_admin(ctx, msg, state).await? {
+        msg.channel_id.say(&ctx.http, ":x: You need the Manage Server permission to do that!").await?;
+        return Ok(None)
+    }
+    match sub {
+        "add" => {
+            let mut words = arg.splitn(2, ' ').peekable();
+            let category = match words.peek().and_then(|w| eightball::Category::parse(&w.to_lowercase())) {
+                Some(c) => { words.next(); c },
+                None => eightball::Category::Neutral
+            };
+            let arg = words.next().unwrap_or("").trim();
+            let (weight, text) = match arg.find(' ') {
+                Some(i) if arg.starts_with('x') => match arg[1..i].parse::<u32>() {
+                    Ok(w) => (w, arg[i..].trim()),
+                    Err(_) => (1, arg)
+                },
+                _ => (1, arg)
+            };
+            let result = eightball::Answer::new(text, category, weight)
+                .and_then(|answer| state.add_answer(guild, answer));
+            match result {
+                Ok(()) => { msg.channel_id.say(&ctx.http, format!(":8ball: Added {} answer \"{}\"", category.name(), text)).await?; },
+                Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: Could not add answer: {}", e)).await?; }
+            }
+        },
+        "remove" => {
+            let result = match arg.parse::<usize>() {
+                Ok(n) if n > 0 => state.remove_answer(guild, n-1),
+                _ => Err("Invalid answer number")
+            };
+            match result {
+                Ok(answer) => { msg.channel_id.say(&ctx.http, format!(":8ball: Removed \"{}\"", answer.text)).await?; },
+                Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?; }
+            }
+        },
+        "reset" => {
+            state.reset_answers(guild);
+            msg.channel_id.say(&ctx.http, ":8ball: The answers have been reset to the defaults").await?;
+        },
+        _ => { msg.channel_id.say(&ctx.http, ":x: Invalid subcommand. See `;help 8ball`").await?; }
+    }
     Ok(None)
 }
 
-pub static COUNTTOP: Command = Command {
-    short: "View the top players by count",
-    aliases: &[],
-    usage: &["counttop"],
-    description: "View the top players by count, as well as your place on the leaderboard.",
-    examples: &[],
+pub static CHOOSE: Command = Command {
+    short: "Choose from a list",
+    aliases: &["pick"],
+    usage: &["choose <a>; <b>; ..."],
+    description: "Choose one item from a list at random. Items are separated by semicolons, or by commas or spaces if there are no semicolons.",
+    examples: &["choose pizza; tacos; curry", "choose red, green, blue", "choose heads tails"]
 };
-pub async fn counttop(ctx: &Context, msg: &Message, state: &mut State) -> CommandResult {
-    let user_count = state.get_count(msg.author.id);
-    let counttop = state.get_count_all();
-    let top10 = counttop.iter().map(|(a,b)| (*a,*b)).take(10).collect::<Vec<(UserId, u64)>>();
-    let mut counttop_fmt = Vec::new();
-
-    for (id, count) in &top10 {
-        let user = ctx.http.get_user((*id).into()).await?;
-        counttop_fmt.push((user.name, count));
+pub async fn choose(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let items = utils::split_items(rest);
+    if items.len() < 2 {
+        msg.channel_id.say(&ctx.http, ":x: Give at least two items to choose from").await?;
+        return Ok(None)
     }
-
-    let mut body = counttop_fmt.into_iter()
-        .enumerate()
-        .map(|(i,(user,count))| 
-             format!("**#{}** {} (**{}**)", i+1, user, count))
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    if top10.iter().find(|&(u,_)| *u == msg.author.id).is_none() {
-        let user_idx = counttop.iter().position(|(u,_)| u == &msg.author.id).unwrap_or(0);
-        body += "\n...\n";
-        body += &format!("**#{}** {}: (**{}**)", 
-                        user_idx+1, msg.author.name, user_count);
+    if items.len() > utils::MAX_ITEMS {
+        msg.channel_id.say(&ctx.http, ":x: Too many items").await?;
+        return Ok(None)
     }
-
-    msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| e
-            .title("Top count (global)")
-            .color(utils::WEB_COLOR)
-            .description(body)
-            )).await?;
+    let (choice, id) = {
+        let (mut rng, id) = state.random(msg.guild_id, Draw::choose(&items));
+        (items.choose(&mut rng).unwrap().clone(), id)
+    };
+    say_or(ctx, msg.channel_id,
+        format!(":point_right: **{}**{}", choice, result_id(&id)),
+        format!(":x: The chosen item is too long to display{}", result_id(&id))).await?;
     Ok(None)
 }
 
-pub static EVAL: Command = Command {
-    short: "Evaluate an expression",
-    aliases: &["calc", "="],
-    usage: &["eval <expr>"],
-    description: "Evaluate a mathematical expression. Common operators and functions are supported. See <https://docs.rs/meval/0.2.0/meval/#supported-expressions> for more information.",
-    examples: &["eval sin(3/4*pi)", "eval 0.5 + sqrt(5)/2", "eval floor(e^3)+1"]
+pub static SHUFFLE: Command = Command {
+    short: "Shuffle a list",
+    aliases: &[],
+    usage: &["shuffle <a>; <b>; ..."],
+    description: "Shuffle a list of items into a random order. Items are separated by semicolons, or by commas or spaces if there are no semicolons.",
+    examples: &["shuffle Alice; Bob; Carol; Dave", "shuffle 1 2 3 4 5"]
 };
-pub async fn eval(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    match meval::eval_str(rest) {
-        Ok(result) => msg.channel_id.say(&ctx.http, format!("Result: `{}`", result)).await?,
-        Err(_) => msg.channel_id.say(&ctx.http, ":x: Error parsing expression").await?
+pub async fn shuffle(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let mut items = utils::split_items(rest);
+    if items.len() < 2 {
+        msg.channel_id.say(&ctx.http, ":x: Give at least two items to shuffle").await?;
+        return Ok(None)
+    }
+    if items.len() > utils::MAX_ITEMS {
+        msg.channel_id.say(&ctx.http, ":x: Too many items").await?;
+        return Ok(None)
+    }
+    let id = {
+        let (mut rng, id) = state.random(msg.guild_id, Draw::shuffle(&items));
+        items.shuffle(&mut rng);
+        id
     };
+    let list = items.iter()
+        .enumerate()
+        .map(|(i,x)| format!("**{}.** {}", i+1, x))
+        .join("\n");
+    say_or(ctx, msg.channel_id,
+        format!(":twisted_rightwards_arrows: Shuffled{}\n{}", result_id(&id), list),
+        format!(":x: Too many items to display{}", result_id(&id))).await?;
     Ok(None)
 }
 
-
-pub static ROLL: Command = Command {
-    short: "Roll dice",
-    aliases: &["dice"],
-    usage: &["roll <dice>"],
-    description: "Roll dice. Supports dice with arbitrary sides and constants. See <https://en.wikipedia.org/wiki/Dice_notation> for dice notation information. Total number of dice must not exceed 2048.",
-    examples: &["roll 2d6", "roll 1d20-1", "roll 2d8+1d6"]
+pub static RAND: Command = Command {
+    short: "Generate a random number",
+    aliases: &["random"],
+    usage: &["rand <max>", "rand <min> <max>"],
+    description: "Generate a random whole number between the minimum and maximum, including both. The minimum is 1 if it isn't given.",
+    examples: &["rand 10", "rand 0 255", "rand -5 5"]
 };
-pub async fn roll(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    let sort = !rest.starts_with("nosort ");
-    let dicestr = match sort {
-        false => &rest[7..],
-        true => rest,
-    }.replace(" ","");
-    match utils::roll_dice(&dicestr, sort) {
-        Ok(rolls) if rolls.len() > 0 => {
-            let result = format!(":game_die: Rolls: `{}` (Sum: **{}**)", 
-                rolls.iter()
-                    .map(|x| x.to_string())
-                    .intersperse(", ".to_owned())
-                    .collect::<String>(),
-                rolls.iter().fold(0i64, |a, b| a+b)
-            );
-            if result.len() > 2000 {
-                let result = format!(":game_die: Too many rolls to display. Sum: **{}**", 
-                    rolls.iter().fold(0i64, |a, b| a+b)
-                );
-                msg.channel_id.say(&ctx.http, result).await?;
-            } else {
-                msg.channel_id.say(&ctx.http, result).await?;
-            }
+pub async fn rand(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let args = rest.split_whitespace()
+        .map(|x| x.parse::<i64>())
+        .collect::<Result<Vec<i64>, _>>();
+    let (min, max) = match args.as_deref() {
+        Ok([max]) => (1, *max),
+        Ok([min, max]) => (*min, *max),
+        _ => {
+            msg.channel_id.say(&ctx.http, ":x: Invalid arguments. See `;help rand`").await?;
+            return Ok(None)
         }
-        Ok(_) => { msg.channel_id.say(&ctx.http, ":game_die: No dice rolled").await?; },
-        Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: Error rolling dice: {}", e)).await?; }
+    };
+    if min > max {
+        msg.channel_id.say(&ctx.http, ":x: The minimum must not be greater than the maximum").await?;
+        return Ok(None)
     }
+    let (n, id) = {
+        let (mut rng, id) = state.random(msg.guild_id, Draw::Rand(min, max));
+        (utils::random_int(min, max, &mut rng), id)
+    };
+    msg.channel_id.say(&ctx.http, format!(":1234: **{}**{}", n, result_id(&id))).await?;
     Ok(None)
 }
 
-pub static FLIP: Command = Command {
-    short: "Flip coins",
-    aliases: &["coinflip"],
-    usage: &["flip", "flip <n>"],
-    description: "Flip the number of coins specified, or one by default. Number of coins must not exceed 2048.",
-    examples: &["flip 6", "flip"]
+pub static DECK: Command = Command {
+    short: "Draw from a deck of cards",
+    aliases: &["cards"],
+    usage: &["deck", "deck draw [n]", "deck shuffle", "deck reset"],
+    description: "Each channel has its own deck of 52 playing cards, which is shuffled when it is first used. `deck draw` draws one card, or the number given, and cards stay out of the deck until it is reset. `deck shuffle` shuffles the cards left in the deck, and `deck reset` puts every card back and shuffles. `deck` shows how many cards are left.",
+    examples: &["deck draw", "deck draw 5", "deck reset"]
 };
-pub async fn flip(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    use rand::Rng;
-    if rest == "" {
-        let side = match rand::thread_rng().gen() {
-            true => "Heads",
-            false => "Tails"
-        };
-        msg.channel_id.say(&ctx.http, format!(":coin: {}!", side)).await?;
-    } else if let Ok(n) = rest.parse::<u32>() {
-        if n > 2048 {
-            msg.channel_id.say(&ctx.http, ":x: Too many coins").await?;
-        } else {
-            let res = {
-                let mut rng = rand::thread_rng();
-                let (mut heads, mut tails) = (0u32, 0u32);
-                for _ in 0..n {
-                    match rng.gen() {
-                        true => heads += 1,
-                        false => tails += 1,
+pub async fn deck(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let channel = msg.channel_id;
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    let deck = state.get_deck(channel).cloned();
+    match sub {
+        "" => {
+            let left = deck.map(|d| d.cards.len()).unwrap_or(52);
+            channel.say(&ctx.http, format!(":black_joker: There are **{}** cards left in the deck", left)).await?;
+        },
+        "draw" => {
+            let n = match arg {
+                "" => 1,
+                _ => match arg.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        channel.say(&ctx.http, ":x: Invalid number of cards. See `;help deck`").await?;
+                        return Ok(None)
                     }
                 }
-                format!(":coin: Flipped {} coins, got {} heads and {} tails.", n, heads, tails)
             };
-            msg.channel_id.say(&ctx.http, res).await?;
-        }
+            let (mut deck, id) = match deck {
+                Some(d) => (d, None),
+                None => shuffle_deck(msg, state, cards::Deck::full())
+            };
+            let drawn = match deck.draw(n) {
+                Ok(d) => d,
+                Err(e) => {
+                    channel.say(&ctx.http, format!(":x: Could not draw: {}. There are {} cards left, use `;deck reset` to start again", e, deck.cards.len())).await?;
+                    return Ok(None)
+                }
+            };
+            let left = deck.cards.len();
+            state.set_deck(channel, deck);
+            let drawn = drawn.into_iter().map(cards::card_name).join(" ");
+            channel.say(&ctx.http, format!(":black_joker: {} ({} left){}", drawn, left, result_id(&id))).await?;
+        },
+        "shuffle" | "reset" => {
+            let deck = match (sub, deck) {
+                ("shuffle", Some(d)) => d,
+                _ => cards::Deck::full()
+            };
+            let (deck, id) = shuffle_deck(msg, state, deck);
+            let left = deck.cards.len();
+            state.set_deck(channel, deck);
+            channel.say(&ctx.http, format!(":black_joker: Shuffled the deck ({} cards){}", left, result_id(&id))).await?;
+        },
+        _ => { channel.say(&ctx.http, ":x: Invalid subcommand. See `;help deck`").await?; }
     }
     Ok(None)
 }
 
-pub static EIGHTBALL: Command = Command {
-    short: "Ask the Magic Eight Ball a question",
-    aliases: &["eightball"],
-    usage: &["8ball <question>"],
-    description: "Ask the Magic Eight Ball a yes/no question, returning a ~~random~~extremely accurate answer",
-    examples: &["8ball do people secretly dislike me but are too afraid to tell me so they just pretend they like me"]
+fn shuffle_deck(msg: &Message, state: &mut State, mut deck: cards::Deck) -> (cards::Deck, Option<String>) {
+    let (mut rng, id) = state.random(msg.guild_id, Draw::Deck(deck.cards.clone()));
+    deck.shuffle(&mut rng);
+    (deck, id)
+}
+
+pub static FAIR: Command = Command {
+    short: "Verifiable randomness",
+    aliases: &[],
+    usage: &["fair", "fair on", "fair reveal", "fair off", "fair replay <id>"],
+    description: "While seeded randomness is on, every `roll`, `flip`, `8ball`, `choose`, `shuffle`, `rand`, `deck` and `init add` result in this server is drawn from a secret seed and tagged with an ID. The SHA-256 hash of the seed is posted when it is created, so it can't be changed later. Once the seed is revealed, anyone can check that it matches the hash and reproduce any result from its ID with `fair replay`. `fair reveal` reveals the current seed and starts a new one, and `fair off` reveals it and goes back to ordinary randomness. Turning seeded randomness on or off and revealing seeds require the Manage Server permission.",
+    examples: &["fair on", "fair replay 3fa2c1-17", "fair reveal"]
 };
-pub async fn eightball(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    if rest == "" {
-        msg.channel_id.say(&ctx.http, ":8ball: You must ask the Magic Eight Ball a question.").await?;
-    } else {
-        msg.channel_id.say(&ctx.http, format!(":8ball: {}", utils::eight_ball())).await?;
+pub async fn fair(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let guild = match msg.guild_id {
+        Some(g) => g,
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: Seeded randomness can only be used in a server").await?;
+            return Ok(None)
+        }
+    };
+    let (sub, arg) = match rest.find(' ') {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, "")
+    };
+    if matches!(sub, "on" | "reveal" | "off") && !is_guild_admin(ctx, msg, state).await? {
+        msg.channel_id.say(&ctx.http, ":x: You need the Manage Server permission to do that!").await?;
+        return Ok(None)
+    }
+    match sub {
+        "" => {
+            let status = match state.get_fair_seed(guild) {
+                Some(seed) => format!(":lock: Seeded randomness is on. {} results drawn from the seed with SHA-256 hash `{}`", seed.count(), seed.commitment()),
+                None => String::from(":unlock: Seeded randomness is off")
+            };
+            let revealed = state.get_revealed_seeds(guild).iter()
+                .rev()
+                .map(|s| format!("`{}` ({} results)", s.seed(), s.count()))
+                .join("\n");
+            match revealed.is_empty() {
+                true => msg.channel_id.say(&ctx.http, status).await?,
+                false => msg.channel_id.say(&ctx.http, format!("{}\nRevealed seeds:\n{}", status, revealed)).await?
+            };
+        },
+        "on" => match state.start_fair_seed(guild) {
+            Ok(commitment) => { msg.channel_id.say(&ctx.http, format!(":lock: Seeded randomness is now on. The seed's SHA-256 hash is `{}`", commitment)).await?; },
+            Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?; }
+        },
+        "reveal" | "off" => match state.reveal_fair_seed(guild, sub == "reveal") {
+            Ok(seed) => {
+                let mut text = format!(":unlock: The seed was `{}` (SHA-256 hash `{}`), used for {} results", seed.seed(), seed.commitment(), seed.count());
+                match state.get_fair_seed(guild) {
+                    Some(next) => text += &format!("\n:lock: The new seed's SHA-256 hash is `{}`", next.commitment()),
+                    None => text += "\nSeeded randomness is now off"
+                }
+                msg.channel_id.say(&ctx.http, text).await?;
+            },
+            Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?; }
+        },
+        "replay" => match state.replay(guild, arg.trim_start_matches('#')) {
+            Ok(replay) => {
+                let (context, channel) = (ctx.clone(), msg.channel_id);
+                tokio::task::spawn(async move {
+                    let sent = match sandbox::run(move |cancel| replay.run(cancel)).await {
+                        Ok(text) => say_or(&context, channel, format!(":repeat: {}", text), String::from(":x: The result is too long to display")).await,
+                        Err(e) => channel.say(&context.http, format!(":x: Could not replay result: {}", e)).await.map(|_| ())
+                    };
+                    if let Err(e) = sent {
+                        warn!("Error in replay async block: {:?}", e);
+                    }
+                });
+            },
+            Err(e) => { msg.channel_id.say(&ctx.http, format!(":x: Could not replay result: {}", e)).await?; }
+        },
+        _ => { msg.channel_id.say(&ctx.http, ":x: Invalid subcommand. See `;help fair`").await?; }
     }
     Ok(None)
 }
@@ -341,11 +1881,11 @@ pub static WIKIPEDIA: Command = Command {
     examples: &[]
 };
 pub async fn wikipedia(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    if rest.len() == 0 {
+    if rest.is_empty() {
         msg.channel_id.say(&ctx.http, ":x: No query specified. See `;help wikipedia`").await?;
         return Ok(None)
     }
-    let channel_id = msg.channel_id.clone();
+    let channel_id = msg.channel_id;
     let context = ctx.clone();
     let rest = rest.to_owned();
     tokio::task::spawn(async move {
@@ -443,8 +1983,8 @@ pub static MEME: Command = Command {
 pub async fn meme(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
     let uname = env::var("IMGFLIP_USER");
     let passwd = env::var("IMGFLIP_PASSWD");
-    if uname.is_ok() && passwd.is_ok() {
-        let result = utils::imgflip(rest, &uname.unwrap(), &passwd.unwrap()).await;
+    if let (Ok(uname), Ok(passwd)) = (uname, passwd) {
+        let result = utils::imgflip(rest, &uname, &passwd).await;
         if let Ok(res) = result {
             let result = res.clone();
             drop(res);
@@ -477,59 +2017,344 @@ pub async fn meme(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
 pub static VOTE: Command = Command {
     short: "Create a poll with two options",
     aliases: &[],
-    usage: &["vote <question>"],
-    description: "Create a poll with the options :arrow_up: and :arrow_down:. Users may only select one option.",
-    examples: &["vote Are waffles better than pancakes?"]
+    usage: &["vote <question>", "vote [duration] [anonymous] | <question>"],
+    description: "Create a poll with the options :arrow_up: and :arrow_down:. Users may only select one option. Settings go before a `|`. If a duration such as `30m` or `2h` is given, the poll closes after that long, up to 90 days, and the results are announced. See `;help poll` for anonymous polls.",
+    examples: &["vote Are waffles better than pancakes?", "vote 1d | Should we play again next week?"]
 };
-pub async fn vote(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
-    let vote_msg = msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| {
-        e.footer(|f| f.text(format!("{}#{}", msg.author.name, msg.author.discriminator)));
-        e.color(utils::POLL_COLOR);
-        e.title(rest);
-        e
-    })).await?;
-    vote_msg.react(&ctx.http, '\u{2B06}').await?;
-    vote_msg.react(&ctx.http, '\u{2B07}').await?;
+pub async fn vote(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    let (settings, question) = match polls::parse_settings(rest) {
+        Ok(x) => x,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: {}. See `;help vote`.", e)).await?;
+            return Ok(None)
+        }
+    };
+    if settings.mode != PollMode::Single {
+        msg.channel_id.say(&ctx.http, ":x: Votes only have one option to select, use `poll` for other modes. See `;help poll`.").await?;
+        return Ok(None)
+    } else if question.is_empty() {
+        msg.channel_id.say(&ctx.http, ":x: No question given. See `;help vote`.").await?;
+        return Ok(None)
+    }
+    let options = ["\u{2B06}", "\u{2B07}"].iter()
+        .map(|e| PollOption { emoji: ReactionType::Unicode(e.to_string()), text: String::new() })
+        .collect::<Vec<PollOption>>();
+    create_poll(ctx, msg, question, String::new(), options, settings, state).await?;
     Ok(None)
 }
 
 pub static POLL: Command = Command {
     short: "Create a poll with multiple options",
     aliases: &[],
-    usage: &["poll <question>;<options...>"],
-    description: "Create a poll with multiple options. Arguments are separated by semicolons, and the first argument is the poll question. Number of options must be between 1 and 9 inclusive. Users may only select one option.",
-    examples: &["poll Best breakfast food; Waffles; Pancakes; Toast"]
+    usage: &["poll <question>;<options...>", "poll [duration] [mode] [anonymous] | <question>;<options...>", "poll voters <message>"],
+    description: "Create a poll with multiple options. Arguments are separated by semicolons, and the first is the question. There can be 1 to 20 options. Options are numbered, or lettered if there are more than 9, but an option can start with its own emoji instead, which can be a standard emoji or a custom emoji from this server.\nSettings go before a `|`. With a duration such as `30m` or `2h`, the poll closes after that long, up to 90 days, and the results are announced. By default users select one option. `multi <n>` lets users select up to `n` options, `approval` any number, and `ranked` has users react in order of preference, with the winner found by instant-runoff. In `anonymous` polls, which need the bot to have Manage Messages, reactions are removed once they're counted and results are hidden until the poll closes.\n`poll voters` with a poll's message ID or link sends you who voted for what. For anonymous polls, only the poll's creator and server admins can see this.",
+    examples: &["poll Best breakfast food; Waffles; Pancakes; Toast", "poll 2h | Best breakfast food; Waffles; Pancakes", "poll multi 2 | Pizza toppings; Cheese; Mushroom; Pepperoni", "poll 1d ranked | Next game; Chess; Go; Shogi", "poll 1d anonymous | Team captain; Alice; Bob", "poll Lunch; :pizza: Pizza; :sushi: Sushi"]
 };
-pub async fn poll(ctx: &Context, msg: &Message, rest: &str) -> CommandResult {
+pub async fn poll(ctx: &Context, msg: &Message, rest: &str, state: &mut State) -> CommandResult {
+    // Only a single message ID or link is taken as `voters`, so that questions
+    // starting with "voters" still create polls
+    if let Some(id) = rest.strip_prefix("voters ").and_then(|arg| parse_message_ref(arg.trim())) {
+        return poll_voters(ctx, msg, id, state).await
+    }
+    let (settings, rest) = match polls::parse_settings(rest) {
+        Ok(x) => x,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: {}. See `;help poll`.", e)).await?;
+            return Ok(None)
+        }
+    };
     let parts = rest.split(";").collect::<Vec<&str>>();
     if parts.len() < 2 {
         msg.channel_id.say(&ctx.http, ":x: Not enough arguments. See `;help poll`.").await?;
         return Ok(None)
-    } else if parts.len() > 10 {
+    } else if parts.len() > polls::MAX_OPTIONS + 1 {
         msg.channel_id.say(&ctx.http, ":x: Too many arguments. See `;help poll`.").await?;
         return Ok(None)
     }
-    let question = parts[0];
-    let options = &parts[1..];
+    let question = parts[0].trim();
+    let mut options = match polls::parse_options(&parts[1..], &[]) {
+        Ok(o) => o,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, format!(":x: {}. See `;help poll`.", e)).await?;
+            return Ok(None)
+        }
+    };
+    // Options starting with symbols that Discord doesn't accept as emoji are
+    // numbered instead, with the symbol kept in their text
+    let rejected = rejected_emoji(ctx, msg, &options).await?;
+    if !rejected.is_empty() {
+        options = match polls::parse_options(&parts[1..], &rejected) {
+            Ok(o) => o,
+            Err(e) => {
+                msg.channel_id.say(&ctx.http, format!(":x: {}. See `;help poll`.", e)).await?;
+                return Ok(None)
+            }
+        };
+    }
+    // Only custom emoji from this server can be used, since there's no way to
+    // check if the bot is in the server that others are from
+    for option in &options {
+        if let ReactionType::Custom { id, .. } = option.emoji {
+            let found = match msg.guild_id {
+                Some(guild) => ctx.http.get_emoji(guild.0, id.0).await.is_ok(),
+                None => false
+            };
+            if !found {
+                msg.channel_id.say(&ctx.http, format!(":x: {} isn't from this server, so it can't be used in a poll", option.emoji)).await?;
+                return Ok(None)
+            }
+        }
+    }
     let body = options.iter()
-        .enumerate()
-        .map(|(i,x)| format!("{}: {}", utils::NUM_EMOJIS[i+1], x.trim()))
+        .map(|o| match o.text.as_str() {
+            "" => o.emoji.to_string(),
+            text => format!("{}: {}", o.emoji, text)
+        })
         .collect::<Vec<String>>()
         .join("\n");
+    create_poll(ctx, msg, question, body, options, settings, state).await?;
+    Ok(None)
+}
+
+/// Find the options with their own Unicode emoji that Discord won't accept
+/// as reactions, by reacting to `msg` with each one and taking it back
+async fn rejected_emoji(ctx: &Context, msg: &Message, options: &[PollOption]) -> serenity::Result<Vec<usize>> {
+    let mut rejected = Vec::new();
+    for (i, option) in options.iter().enumerate() {
+        let own_unicode = matches!(option.emoji, ReactionType::Unicode(_))
+            && option.emoji != polls::default_emoji(i, options.len());
+        if !own_unicode {
+            continue
+        }
+        match msg.react(&ctx.http, option.emoji.clone()).await {
+            Ok(_) => ctx.http.delete_reaction(msg.channel_id.into(), msg.id.into(), None, &option.emoji).await?,
+            Err(serenity::Error::Http(e)) if e.status_code().is_some_and(|c| c.as_u16() == 400) => rejected.push(i),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(rejected)
+}
+
+/// Send a poll's message, add a reaction for each option, and record it
+async fn create_poll(ctx: &Context, msg: &Message, question: &str, body: String,
+        options: Vec<PollOption>, settings: PollSettings, state: &mut State) -> serenity::Result<()> {
+    if let Err(e) = state.can_add_poll() {
+        msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+        return Ok(())
+    }
+    if settings.anonymous {
+        let allowed = match msg.guild_id {
+            Some(guild) => {
+                let bot = ctx.http.get_current_user().await?.id;
+                utils::has_channel_permission(&ctx.http, guild, msg.channel_id, bot, Permissions::MANAGE_MESSAGES).await?
+            },
+            None => false
+        };
+        if !allowed {
+            msg.channel_id.say(&ctx.http, ":x: Anonymous polls need the bot to have the Manage Messages permission in this channel, so that it can remove reactions").await?;
+            return Ok(())
+        }
+    }
+    let poll = Poll::new(msg, question, options, &settings, utils::now_millis());
+    let description = std::iter::once(body)
+        .chain(poll.instructions().into_iter().map(|n| format!("*{}*", n)))
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n\n");
     let poll_msg = msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| {
-        e.footer(|f| f.text(format!("{}#{}", msg.author.name, msg.author.discriminator)));
+        let creator = format!("{}#{}", msg.author.name, msg.author.discriminator);
+        match poll.closes_at {
+            Some(t) => {
+                e.footer(|f| f.text(format!("{} \u{2022} Closes", creator)));
+                if let Some(time) = Utc.timestamp_millis_opt(t as i64).single() {
+                    e.timestamp(&time);
+                }
+            },
+            None => { e.footer(|f| f.text(creator)); }
+        }
         e.color(utils::POLL_COLOR);
         e.title(question);
-        e.description(body);
+        if !description.is_empty() {
+            e.description(description);
+        }
         e
     })).await?;
-    for i in 1..(options.len() + 1) {
-        poll_msg.react(&ctx.http, 
-            ReactionType::Unicode(utils::NUM_EMOJIS[i].to_owned())).await?;
+    for option in &poll.options {
+        if let Err(e) = poll_msg.react(&ctx.http, option.emoji.clone()).await {
+            warn!("Could not add poll reaction: {:?}", e);
+            poll_msg.delete(&ctx.http).await?;
+            msg.channel_id.say(&ctx.http, format!(":x: Couldn't react with {}, so it can't be used in a poll", option.emoji)).await?;
+            return Ok(())
+        }
+    }
+    if let Err(e) = state.add_poll(poll_msg.id, poll) {
+        poll_msg.delete(&ctx.http).await?;
+        msg.channel_id.say(&ctx.http, format!(":x: {}", e)).await?;
+    }
+    Ok(())
+}
+
+/// Parse a message ID, or a message link
+fn parse_message_ref(s: &str) -> Option<MessageId> {
+    match s.contains(char::is_whitespace) {
+        true => None,
+        false => s.rsplit('/').next().and_then(|id| id.parse::<u64>().ok()).map(MessageId)
+    }
+}
+
+/// Send a user a list of who voted for what in a poll. Only the creator and
+/// server admins may see who voted in anonymous polls.
+async fn poll_voters(ctx: &Context, msg: &Message, id: MessageId, state: &mut State) -> CommandResult {
+    let poll = match state.get_poll(id) {
+        Some(p) => p.clone(),
+        None => {
+            msg.channel_id.say(&ctx.http, ":x: No poll found with that message ID or link").await?;
+            return Ok(None)
+        }
+    };
+    let allowed = !poll.anonymous
+        || msg.author.id == poll.creator
+        || (poll.guild.is_some() && msg.guild_id == poll.guild && is_guild_admin(ctx, msg, state).await?);
+    if !allowed {
+        msg.channel_id.say(&ctx.http, ":x: Only the poll's creator and server admins can see who voted in an anonymous poll").await?;
+        return Ok(None)
     }
+    let (context, author, channel, in_guild) = (ctx.clone(), msg.author.clone(), msg.channel_id, msg.guild_id.is_some());
+    // Look up names on a separate task so that the state isn't locked during requests
+    tokio::task::spawn(async move {
+        if let Err(e) = send_voters(&context, &author, channel, in_guild, &poll).await {
+            warn!("Error in poll voters async block: {:?}", e);
+        }
+    });
     Ok(None)
 }
 
+async fn send_voters(ctx: &Context, author: &User, channel: ChannelId, in_guild: bool, poll: &Poll) -> serenity::Result<()> {
+    let ballots = poll.ballots();
+    let ids = ballots.iter().map(|(user, _)| *user).collect::<Vec<UserId>>();
+    let names = users::display_names_unlocked(ctx, poll.guild, &ids).await?;
+    let mut lines = ballots.iter()
+        .map(|(user, choices)| format!("{}: {}", names[user], choices))
+        .collect::<Vec<String>>();
+    lines.sort();
+    if lines.is_empty() {
+        lines.push(String::from("Nobody has voted yet"));
+    }
+    // Split the list over several embeds if it's too long for one
+    let mut pages = vec![String::new()];
+    for line in lines {
+        if pages.last().unwrap().len() + line.len() > utils::MAX_MESSAGE_LEN {
+            pages.push(String::new());
+        }
+        let page = pages.last_mut().unwrap();
+        *page += &line;
+        *page += "\n";
+    }
+    for page in pages {
+        author.direct_message(&ctx.http, |m| m.embed(|e| e
+            .title(format!("Voters: {}", poll.question))
+            .color(utils::POLL_COLOR)
+            .description(page))).await?;
+    }
+    if in_guild {
+        channel.say(&ctx.http, ":envelope: Sent you the list of voters").await?;
+    }
+    Ok(())
+}
+
+/// Show the final results of a closed poll in its message, and announce them
+/// in its channel
+pub async fn close_poll(http: &Http, msg: MessageId, poll: &Poll) -> serenity::Result<()> {
+    let results = poll.results();
+    // Keep the creator's name from the original footer
+    let creator = match poll.channel.message(http, msg).await {
+        Ok(m) => m.embeds.into_iter()
+            .next()
+            .and_then(|e| e.footer)
+            .map(|f| f.text.split(" \u{2022} ").next().unwrap_or("").to_owned()),
+        Err(e) => {
+            warn!("Could not get poll message: {:?}", e);
+            None
+        }
+    };
+    if let Some(creator) = creator {
+        let edit = poll.channel.edit_message(http, msg, |m| m.embed(|e| {
+            e.footer(|f| f.text(format!("{} \u{2022} Closed", creator)));
+            e.timestamp(&Utc::now());
+            e.color(utils::POLL_COLOR);
+            e.title(&poll.question);
+            e.description(&results);
+            e
+        })).await;
+        if let Err(e) = edit {
+            warn!("Could not edit poll message: {:?}", e);
+        }
+    }
+    poll.channel.send_message(http, |m| m.embed(|e| {
+        e.color(utils::POLL_COLOR);
+        e.title(format!("Poll closed: {}", poll.question));
+        e.description(&results);
+        e
+    })).await?;
+    Ok(())
+}
+
+/// Record a vote from a reaction on a poll, removing the user's reaction for
+/// an option they no longer have selected. In anonymous polls the reaction
+/// is always removed. Without permission to remove reactions, old ones are
+/// left in place.
+pub async fn poll_vote(ctx: &Context, reaction: &Reaction, user: UserId) -> serenity::Result<()> {
+    // Record the vote while locked, but remove the reaction after unlocking
+    let (remove, emoji) = {
+        let mut data = ctx.data.write().await;
+        let poll = match data.get_mut::<State>().and_then(|s| s.poll_mut(reaction.message_id)) {
+            Some(p) if !p.closed => p,
+            _ => return Ok(())
+        };
+        let option = match poll.option(&reaction.emoji) {
+            Some(i) => i,
+            None => return Ok(())
+        };
+        let dropped = poll.vote(user, option);
+        let remove = match (poll.anonymous, dropped) {
+            _ if poll.missing_permissions => return Ok(()),
+            (true, _) => option,
+            (false, Some(prev)) => prev,
+            (false, None) => return Ok(())
+        };
+        // Mark the removal before making it, so that its event is ignored
+        poll.removed_reaction(user, remove);
+        (remove, poll.options[remove].emoji.clone())
+    };
+    let res = ctx.http.delete_reaction(reaction.channel_id.into(), reaction.message_id.into(),
+        Some(user.into()), &emoji).await;
+    if let Err(e) = res {
+        let mut data = ctx.data.write().await;
+        if let Some(poll) = data.get_mut::<State>().and_then(|s| s.poll_mut(reaction.message_id)) {
+            poll.removal_failed(user, remove);
+            if utils::is_missing_permissions(&e) {
+                // Stop trying, the tracked votes are still correct
+                debug!("Missing permissions to remove poll reactions, counting only the latest votes");
+                poll.missing_permissions = true;
+            } else {
+                warn!("Could not remove reaction: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take back a vote when a user removes their reaction from a poll
+pub fn poll_unvote(reaction: &Reaction, user: UserId, state: &mut State) {
+    let poll = match state.poll_mut(reaction.message_id) {
+        Some(p) if !p.closed => p,
+        _ => return
+    };
+    if let Some(option) = poll.option(&reaction.emoji) {
+        poll.unvote(user, option);
+    }
+}
+
 pub static HELP: Command = Command {
     short: "Show help",
     aliases: &["?"],
@@ -560,10 +2385,25 @@ pub async fn send_help_command(ctx: &Context, msg: &Message, rest: &str) -> Comm
         "say" => SAY,
         "ping" => PING,
         "count" => COUNT,
+        "countstats" => COUNTSTATS,
         "counttop" => COUNTTOP,
+        "countcooldown" => COUNTCOOLDOWN,
+        "season" => SEASON,
+        "counting" => COUNTING,
         "roll" => ROLL,
+        "odds" => ODDS,
+        "sheet" => SHEET,
+        "init" => INIT,
+        "choose" => CHOOSE,
+        "shuffle" => SHUFFLE,
+        "rand" => RAND,
+        "deck" => DECK,
+        "fair" => FAIR,
         "flip" => FLIP,
         "eval" => EVAL,
+        "convert" => CONVERT,
+        "base" => BASE,
+        "plot" => PLOT,
         "help" => HELP,
         "8ball" => EIGHTBALL,
         "wikipedia" => WIKIPEDIA,
@@ -579,7 +2419,7 @@ pub async fn send_help_command(ctx: &Context, msg: &Message, rest: &str) -> Comm
     msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| {
         e.title(format!("Help for command `{}`", cmd_name));
         e.color(utils::HELP_COLOR);
-        if cmd.aliases.len() > 0 {
+        if !cmd.aliases.is_empty() {
             e.field("Aliases", cmd.aliases
                 .iter()
                 .map(|x| format!("`{}`", x))
@@ -592,7 +2432,7 @@ pub async fn send_help_command(ctx: &Context, msg: &Message, rest: &str) -> Comm
             .collect::<Vec<String>>()
             .join(" | "), false);
         e.field("Description", cmd.description, false);
-        if cmd.examples.len() > 0 {
+        if !cmd.examples.is_empty() {
             e.field("Examples", cmd.examples
                 .iter()
                 .map(|x| format!("`{}`", x))
@@ -603,3 +2443,20 @@ pub async fn send_help_command(ctx: &Context, msg: &Message, rest: &str) -> Comm
     })).await?;
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Discord rejects embeds with fields longer than this
+    const MAX_FIELD_LEN: usize = 1024;
+
+    #[test]
+    fn help_fits_in_embed_fields() {
+        for cmd in COMMANDS {
+            assert!(cmd.description.chars().count() <= MAX_FIELD_LEN, "description of `{}` is too long", cmd.usage[0]);
+            assert!(cmd.usage.join(" | ").chars().count() <= MAX_FIELD_LEN, "usage of `{}` is too long", cmd.usage[0]);
+            assert!(cmd.examples.join("\n").chars().count() <= MAX_FIELD_LEN, "examples of `{}` are too long", cmd.usage[0]);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use serenity::model::id::{ChannelId, UserId};
+use crate::sandbox;
+
+const DAY: u64 = 24*60*60*1000; // 1 day as millis
+const MAX_HISTORY: usize = 365;
+
+/// Parse a message as a number, or as an expression if `expressions` is set.
+/// Messages that aren't numbers are ignored so that users can still talk, as
+/// are expressions without a digit, such as `e` or `pi`. Expressions are
+/// evaluated on the worker pool, so the state shouldn't be locked.
+pub async fn parse(content: &str, expressions: bool) -> Option<f64> {
+    let content = content.trim();
+    if let Ok(n) = content.parse::<u64>() {
+        return Some(n as f64)
+    }
+    if !expressions || !content.chars().any(|c| c.is_ascii_digit()) || sandbox::check_input(content).is_err() {
+        return None
+    }
+    let content = content.to_owned();
+    sandbox::run(move |_| meval::eval_str(&content)).await.ok()
+        .and_then(Result::ok)
+        .filter(|n| n.is_finite())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Achievement {
+    FirstCount,
+    HundredCounts,
+    MonthStreak,
+}
+
+impl Achievement {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Achievement::FirstCount => "First count",
+            Achievement::HundredCounts => "100 counts",
+            Achievement::MonthStreak => "30-day streak",
+        }
+    }
+}
+
+pub fn day_of(millis: u64) -> u64 {
+    millis / DAY
+}
+
+/// Per-user counting history, updated whenever the user counts
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct CountStats {
+    streak: u32,
+    pub best_streak: u32,
+    last_day: Option<u64>,
+    /// Number of counts made on each day the user counted on
+    pub history: Vec<(u64, u64)>,
+    /// Global rank just after the user's last count on each day they counted on
+    pub rank_history: Vec<(u64, usize)>,
+    pub achievements: Vec<Achievement>,
+}
+
+impl CountStats {
+    /// Start tracking a user who had already made `previous` counts, so that
+    /// milestones they reached before are not announced as newly earned
+    pub fn new(previous: u64) -> Self {
+        let achievements = Self::milestones(previous, 0).iter()
+            .filter(|(_, reached)| *reached)
+            .map(|(a, _)| *a)
+            .collect();
+        Self { achievements, ..Default::default() }
+    }
+
+    fn milestones(count: u64, streak: u32) -> [(Achievement, bool); 3] {
+        [
+            (Achievement::FirstCount, count >= 1),
+            (Achievement::HundredCounts, count >= 100),
+            (Achievement::MonthStreak, streak >= 30),
+        ]
+    }
+
+    /// Record a count made on `day`, returning any newly earned achievements
+    pub fn record(&mut self, day: u64, count: u64, rank: usize) -> Vec<Achievement> {
+        match self.last_day {
+            Some(last) if last == day => (),
+            Some(last) if last + 1 == day => self.streak += 1,
+            _ => self.streak = 1,
+        }
+        self.last_day = Some(day);
+        self.best_streak = self.best_streak.max(self.streak);
+        let today = match self.history.last() {
+            Some((d, n)) if *d == day => n + 1,
+            _ => 1
+        };
+        push_daily(&mut self.history, day, today);
+        push_daily(&mut self.rank_history, day, rank);
+
+        let mut earned = Vec::new();
+        for (achievement, reached) in Self::milestones(count, self.streak).iter() {
+            if *reached && !self.achievements.contains(achievement) {
+                self.achievements.push(*achievement);
+                earned.push(*achievement);
+            }
+        }
+        earned
+    }
+
+    /// The current streak, which is broken if the user didn't count today or yesterday
+    pub fn streak(&self, today: u64) -> u32 {
+        match self.last_day {
+            Some(last) if last + 1 >= today => self.streak,
+            _ => 0
+        }
+    }
+
+    /// Number of counts made on each of the `days` days up to and including `today`
+    pub fn daily_counts(&self, today: u64, days: u64) -> Vec<u64> {
+        let start = (today + 1).saturating_sub(days);
+        (start..=today)
+            .map(|day| self.history.iter()
+                .find(|(d,_)| *d == day)
+                .map(|(_,n)| *n)
+                .unwrap_or(0))
+            .collect()
+    }
+}
+
+fn push_daily<T>(history: &mut Vec<(u64, T)>, day: u64, value: T) {
+    match history.last_mut() {
+        Some((d, v)) if *d == day => *v = value,
+        _ => history.push((day, value))
+    }
+    if history.len() > MAX_HISTORY {
+        history.remove(0);
+    }
+}
+
+/// The season in progress, and when it should automatically end
+#[derive(Default, Serialize, Deserialize)]
+pub struct CurrentSeason {
+    pub name: Option<String>,
+    pub started: u64,
+    pub interval: Option<u64>,
+    pub next_rollover: Option<u64>,
+}
+
+/// A finished season, with the leaderboard as it was when the season ended
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Season {
+    pub name: String,
+    pub started: u64,
+    pub ended: u64,
+    pub standings: Vec<(UserId, u64)>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct CountingPlayer {
+    pub correct: u64,
+    pub mistakes: u64,
+}
+
+pub enum CountingResult {
+    Correct,
+    WrongNumber { expected: u64, reached: u64 },
+    TwiceInARow { reached: u64 },
+}
+
+/// A counting channel, where users take turns posting the next number
+#[derive(Serialize, Deserialize)]
+pub struct CountingGame {
+    pub channel: ChannelId,
+    pub current: u64,
+    pub last_user: Option<UserId>,
+    pub high_score: u64,
+    pub expressions: bool,
+    pub players: HashMap<UserId, CountingPlayer>,
+    /// Set when the game is turned off, keeping the high score and stats
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl CountingGame {
+    pub fn new(channel: ChannelId) -> Self {
+        Self {
+            channel,
+            current: 0,
+            last_user: None,
+            high_score: 0,
+            expressions: false,
+            players: HashMap::new(),
+            disabled: false,
+        }
+    }
+
+    pub fn submit(&mut self, user: UserId, number: f64) -> CountingResult {
+        let expected = self.current + 1;
+        let reached = self.current;
+        let player = self.players.entry(user).or_default();
+        let result = if self.last_user == Some(user) {
+            CountingResult::TwiceInARow { reached }
+        } else if (number - expected as f64).abs() > 1e-9 {
+            CountingResult::WrongNumber { expected, reached }
+        } else {
+            CountingResult::Correct
+        };
+        match result {
+            CountingResult::Correct => {
+                player.correct += 1;
+                self.current = expected;
+                self.last_user = Some(user);
+                self.high_score = self.high_score.max(self.current);
+            },
+            _ => {
+                player.mistakes += 1;
+                self.current = 0;
+                self.last_user = None;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,904 @@
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use crate::sandbox::{self, Cancel};
+
+pub const MAX_DICE: usize = 2048;
+const MAX_SIDES: i64 = 1_000_000_000_000;
+
+const MAX_NAME_LEN: usize = 32;
+const MAX_SAVED: usize = 50;
+const MAX_STAT: i64 = 1_000_000;
+/// Words that can't be used as roll names, since they are `roll` subcommands
+const RESERVED: &[&str] = &["save", "edit", "list", "delete", "stats", "nosort"];
+
+pub type DiceResult<T> = Result<T, &'static str>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sides {
+    Num(i64),
+    Fudge,
+}
+
+impl Sides {
+    pub fn min(&self) -> i64 {
+        match self {
+            Sides::Num(_) => 1,
+            Sides::Fudge => -1,
+        }
+    }
+
+    pub fn max(&self) -> i64 {
+        match self {
+            Sides::Num(n) => *n,
+            Sides::Fudge => 1,
+        }
+    }
+
+    /// Whether every face of the die matches a comparison
+    fn always(&self, cmp: Compare) -> bool {
+        cmp.matches(self.min()) && cmp.matches(self.max())
+    }
+
+    fn roll<R: Rng + ?Sized>(&self, rng: &mut R) -> i64 {
+        match self {
+            Sides::Num(n) => rng.gen_range(1, n+1),
+            Sides::Fudge => rng.gen_range(-1, 2),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+    Eq(i64), Lt(i64), Le(i64), Gt(i64), Ge(i64),
+}
+
+impl Compare {
+    pub fn matches(&self, v: i64) -> bool {
+        match *self {
+            Compare::Eq(n) => v == n,
+            Compare::Lt(n) => v < n,
+            Compare::Le(n) => v <= n,
+            Compare::Gt(n) => v > n,
+            Compare::Ge(n) => v >= n,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keep {
+    Highest(usize), Lowest(usize), DropHighest(usize), DropLowest(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reroll {
+    pub when: Compare,
+    pub once: bool,
+}
+
+/// A group of dice such as `4d6kh3` or `6d10>=7`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiceSpec {
+    pub count: usize,
+    pub sides: Sides,
+    pub keep: Option<Keep>,
+    pub explode: Option<Compare>,
+    pub reroll: Option<Reroll>,
+    pub success: Option<Compare>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add, Sub, Mul, Div,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Dice(DiceSpec),
+    Neg(Box<Expr>),
+    Paren(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+}
+
+/// A single die, and what happened to it
+#[derive(Clone, Debug)]
+pub struct Die {
+    pub value: i64,
+    pub dropped: bool,
+    pub rerolled: bool,
+    pub exploded: bool,
+    pub success: bool,
+}
+
+impl Die {
+    fn new(value: i64) -> Self {
+        Self { value, dropped: false, rerolled: false, exploded: false, success: false }
+    }
+
+    fn counted(&self) -> bool {
+        !self.dropped && !self.rerolled
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Roll {
+    pub total: i64,
+    /// The expression with every group of dice replaced by its rolls
+    pub text: String,
+}
+
+//
+// Parsing
+//
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    stats: &'a BTreeMap<String, i64>,
+}
+
+/// Parse dice notation. Whitespace is ignored and letters are case-insensitive.
+pub fn parse(s: &str) -> DiceResult<Expr> {
+    parse_with(s, &BTreeMap::new())
+}
+
+/// Parse dice notation, replacing references to stats (`@dex`) with their values
+pub fn parse_with(s: &str, stats: &BTreeMap<String, i64>) -> DiceResult<Expr> {
+    let src = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if src.is_empty() {
+        return Err("no dice specified")
+    }
+    let mut parser = Parser { src: src.as_bytes(), pos: 0, stats };
+    let expr = parser.expr()?;
+    if parser.pos < parser.src.len() {
+        return Err("invalid dice notation")
+    }
+    Ok(expr)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> DiceResult<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(b'+') => Op::Add,
+                Some(b'-') => Op::Sub,
+                _ => return Ok(lhs)
+            };
+            self.pos += 1;
+            let rhs = self.term()?;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn term(&mut self) -> DiceResult<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(b'*') | Some(b'x') => Op::Mul,
+                Some(b'/') => Op::Div,
+                _ => return Ok(lhs)
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> DiceResult<Expr> {
+        if self.eat(b'-') {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat(b'+') {
+            self.unary()
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> DiceResult<Expr> {
+        if self.eat(b'(') {
+            let inner = self.expr()?;
+            if !self.eat(b')') {
+                return Err("unmatched parenthesis")
+            }
+            return Ok(Expr::Paren(Box::new(inner)))
+        }
+        if self.eat(b'@') {
+            let start = self.pos;
+            while matches!(self.peek(), Some(c) if is_name_char(c)) {
+                self.pos += 1;
+            }
+            let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+            return match self.stats.get(name) {
+                Some(value) => Ok(Expr::Num(*value)),
+                None if name.is_empty() => Err("missing stat name after `@`"),
+                None => Err("unknown stat")
+            }
+        }
+        let count = match self.peek() {
+            Some(c) if c.is_ascii_digit() => Some(self.number()?),
+            Some(b'd') => None,
+            _ => return Err("invalid dice notation")
+        };
+        if self.peek() != Some(b'd') {
+            return Ok(Expr::Num(count.unwrap()))
+        }
+        self.pos += 1;
+        let count = count.unwrap_or(1);
+        if count < 0 || count as usize > MAX_DICE {
+            return Err("too many dice")
+        }
+        let sides = match self.peek() {
+            Some(b'f') => { self.pos += 1; Sides::Fudge },
+            Some(b'%') => { self.pos += 1; Sides::Num(100) },
+            Some(c) if c.is_ascii_digit() => Sides::Num(self.number()?),
+            _ => return Err("dice must have a number of sides")
+        };
+        match sides {
+            Sides::Num(0) => return Err("dice must have at least one side"),
+            Sides::Num(n) if n > MAX_SIDES => return Err("dice have too many sides"),
+            _ => ()
+        }
+        let mut spec = DiceSpec {
+            count: count as usize,
+            sides,
+            keep: None,
+            explode: None,
+            reroll: None,
+            success: None,
+        };
+        self.modifiers(&mut spec)?;
+        Ok(Expr::Dice(spec))
+    }
+
+    fn modifiers(&mut self, spec: &mut DiceSpec) -> DiceResult<()> {
+        loop {
+            match (self.peek(), self.peek_at(1)) {
+                (Some(b'k'), Some(b'h')) => { self.pos += 2; spec.keep = Some(Keep::Highest(self.count()?)) },
+                (Some(b'k'), Some(b'l')) => { self.pos += 2; spec.keep = Some(Keep::Lowest(self.count()?)) },
+                (Some(b'k'), _) => { self.pos += 1; spec.keep = Some(Keep::Highest(self.count()?)) },
+                (Some(b'd'), Some(b'h')) => { self.pos += 2; spec.keep = Some(Keep::DropHighest(self.count()?)) },
+                (Some(b'd'), Some(b'l')) => { self.pos += 2; spec.keep = Some(Keep::DropLowest(self.count()?)) },
+                (Some(b'!'), _) => {
+                    self.pos += 1;
+                    let when = match self.compare()? {
+                        Some(c) => c,
+                        None => Compare::Ge(spec.sides.max())
+                    };
+                    if spec.sides.always(when) {
+                        return Err("dice would explode forever")
+                    }
+                    spec.explode = Some(when);
+                },
+                (Some(b'r'), _) => {
+                    self.pos += 1;
+                    let once = self.eat(b'o');
+                    let when = match self.compare()? {
+                        Some(c) => c,
+                        None => Compare::Eq(self.signed()?)
+                    };
+                    if spec.sides.always(when) {
+                        return Err("dice would be rerolled forever")
+                    }
+                    spec.reroll = Some(Reroll { when, once });
+                },
+                (Some(b'>'), _) | (Some(b'<'), _) | (Some(b'='), _) => {
+                    spec.success = self.compare()?;
+                },
+                _ => return Ok(())
+            }
+        }
+    }
+
+    fn compare(&mut self) -> DiceResult<Option<Compare>> {
+        let cmp: fn(i64) -> Compare = match (self.peek(), self.peek_at(1)) {
+            (Some(b'>'), Some(b'=')) => { self.pos += 2; Compare::Ge },
+            (Some(b'<'), Some(b'=')) => { self.pos += 2; Compare::Le },
+            (Some(b'>'), _) => { self.pos += 1; Compare::Gt },
+            (Some(b'<'), _) => { self.pos += 1; Compare::Lt },
+            (Some(b'='), _) => { self.pos += 1; Compare::Eq },
+            _ => return Ok(None)
+        };
+        Ok(Some(cmp(self.signed()?)))
+    }
+
+    fn count(&mut self) -> DiceResult<usize> {
+        let n = self.number()?;
+        if n as usize > MAX_DICE {
+            return Err("too many dice")
+        }
+        Ok(n as usize)
+    }
+
+    fn signed(&mut self) -> DiceResult<i64> {
+        match self.eat(b'-') {
+            true => Ok(-self.number()?),
+            false => self.number()
+        }
+    }
+
+    fn number(&mut self) -> DiceResult<i64> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+            .parse::<i64>()
+            .map_err(|_| "could not parse integer")
+    }
+}
+
+//
+// Rolling
+//
+
+/// Roll the dice in an expression, sorting each group of dice if `sort` is set
+pub fn roll<R: Rng + ?Sized>(expr: &Expr, rng: &mut R, sort: bool, cancel: &Cancel) -> DiceResult<Roll> {
+    let mut dice = Dice { rolled: 0, cancel };
+    let (total, text) = eval(expr, rng, sort, &mut dice)?;
+    Ok(Roll { total, text })
+}
+
+/// Number of dice rolled so far in an expression
+struct Dice<'a> {
+    rolled: usize,
+    cancel: &'a Cancel,
+}
+
+fn eval<R: Rng + ?Sized>(expr: &Expr, rng: &mut R, sort: bool, dice: &mut Dice) -> DiceResult<(i64, String)> {
+    match expr {
+        Expr::Num(n) => Ok((*n, n.to_string())),
+        Expr::Neg(inner) => {
+            let (v, text) = eval(inner, rng, sort, dice)?;
+            Ok((v.checked_neg().ok_or("result too large")?, format!("-{}", text)))
+        },
+        Expr::Paren(inner) => {
+            let (v, text) = eval(inner, rng, sort, dice)?;
+            Ok((v, format!("({})", text)))
+        },
+        Expr::Bin(op, lhs, rhs) => {
+            let (l, ltext) = eval(lhs, rng, sort, dice)?;
+            let (r, rtext) = eval(rhs, rng, sort, dice)?;
+            let (v, sym) = match op {
+                Op::Add => (l.checked_add(r), "+"),
+                Op::Sub => (l.checked_sub(r), "-"),
+                Op::Mul => (l.checked_mul(r), "*"),
+                Op::Div if r == 0 => return Err("division by zero"),
+                Op::Div => (l.checked_div(r), "/"),
+            };
+            Ok((v.ok_or("result too large")?, format!("{} {} {}", ltext, sym, rtext)))
+        },
+        Expr::Dice(spec) => {
+            let mut rolled = roll_group(spec, rng, dice)?;
+            let total = score_group(spec, &mut rolled)?;
+            if sort {
+                rolled.sort_by_key(|d| d.value);
+            }
+            let text = rolled.iter()
+                .map(|d| format_die(spec, d))
+                .collect::<Vec<String>>()
+                .join(", ");
+            Ok((total, format!("[{}]", text)))
+        }
+    }
+}
+
+fn roll_group<R: Rng + ?Sized>(spec: &DiceSpec, rng: &mut R, dice: &mut Dice) -> DiceResult<Vec<Die>> {
+    let mut rolled = Vec::new();
+    let mut roll_die = |rng: &mut R| -> DiceResult<i64> {
+        dice.rolled += 1;
+        if dice.rolled > MAX_DICE {
+            return Err("too many dice")
+        }
+        if dice.cancel.is_cancelled() {
+            return Err(sandbox::CANCELLED)
+        }
+        Ok(spec.sides.roll(rng))
+    };
+    for _ in 0..spec.count {
+        let mut pending = 1;
+        while pending > 0 {
+            pending -= 1;
+            let mut value = roll_die(rng)?;
+            if let Some(reroll) = spec.reroll {
+                while reroll.when.matches(value) {
+                    let mut die = Die::new(value);
+                    die.rerolled = true;
+                    rolled.push(die);
+                    value = roll_die(rng)?;
+                    if reroll.once {
+                        break
+                    }
+                }
+            }
+            let mut die = Die::new(value);
+            if spec.explode.map(|e| e.matches(value)).unwrap_or(false) {
+                die.exploded = true;
+                pending += 1;
+            }
+            rolled.push(die);
+        }
+    }
+    Ok(rolled)
+}
+
+fn score_group(spec: &DiceSpec, rolled: &mut [Die]) -> DiceResult<i64> {
+    if let Some(keep) = spec.keep {
+        let mut order = (0..rolled.len())
+            .filter(|i| rolled[*i].counted())
+            .collect::<Vec<usize>>();
+        order.sort_by_key(|i| std::cmp::Reverse(rolled[*i].value));
+        let n = order.len();
+        let dropped = match keep {
+            Keep::Highest(k) => order[k.min(n)..].to_vec(),
+            Keep::Lowest(k) => order[..n - k.min(n)].to_vec(),
+            Keep::DropHighest(k) => order[..k.min(n)].to_vec(),
+            Keep::DropLowest(k) => order[n - k.min(n)..].to_vec(),
+        };
+        for i in dropped {
+            rolled[i].dropped = true;
+        }
+    }
+    match spec.success {
+        Some(cmp) => {
+            let mut successes = 0;
+            for die in rolled.iter_mut().filter(|d| d.counted()) {
+                if cmp.matches(die.value) {
+                    die.success = true;
+                    successes += 1;
+                }
+            }
+            Ok(successes)
+        },
+        None => rolled.iter()
+            .filter(|d| d.counted())
+            .try_fold(0i64, |a, d| a.checked_add(d.value))
+            .ok_or("result too large")
+    }
+}
+
+fn format_die(spec: &DiceSpec, die: &Die) -> String {
+    let mut text = match (spec.sides, die.value) {
+        (Sides::Fudge, v) if v > 0 => "+".to_owned(),
+        (Sides::Fudge, v) if v < 0 => "-".to_owned(),
+        (_, v) => v.to_string(),
+    };
+    if die.exploded {
+        text += "!";
+    }
+    if die.success {
+        text = format!("**{}**", text);
+    }
+    if !die.counted() {
+        text = format!("~~{}~~", text);
+    }
+    text
+}
+
+//
+// Probability distributions
+//
+
+type Dist = BTreeMap<i64, f64>;
+
+const MAX_WORK: u64 = 5_000_000;
+const MAX_STAT_SIDES: i64 = 10_000;
+const EXPLODE_DEPTH: usize = 16;
+
+/// The exact distribution of results of a dice expression
+pub struct Distribution {
+    probs: Dist,
+}
+
+struct Budget<'a> {
+    work: u64,
+    cancel: &'a Cancel,
+}
+
+impl Budget<'_> {
+    fn spend(&mut self, work: usize) -> DiceResult<()> {
+        self.work += work as u64;
+        if self.cancel.is_cancelled() {
+            return Err(sandbox::CANCELLED)
+        }
+        match self.work > MAX_WORK {
+            true => Err("expression is too complex to calculate exactly"),
+            false => Ok(())
+        }
+    }
+}
+
+/// Calculate the distribution of an expression by convolving the
+/// distributions of its parts. Explosions are followed 16 levels deep.
+pub fn distribution(expr: &Expr, cancel: &Cancel) -> DiceResult<Distribution> {
+    let probs = dist(expr, &mut Budget { work: 0, cancel })?;
+    let total = probs.values().sum::<f64>();
+    let probs = probs.into_iter()
+        .filter(|(_,p)| *p > 0.0)
+        .map(|(v,p)| (v, p / total))
+        .collect();
+    Ok(Distribution { probs })
+}
+
+impl Distribution {
+    pub fn mean(&self) -> f64 {
+        self.probs.iter().map(|(v,p)| *v as f64 * p).sum()
+    }
+
+    pub fn stddev(&self) -> f64 {
+        let mean = self.mean();
+        self.probs.iter()
+            .map(|(v,p)| (*v as f64 - mean).powi(2) * p)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    pub fn min(&self) -> i64 {
+        *self.probs.keys().next().unwrap()
+    }
+
+    pub fn max(&self) -> i64 {
+        *self.probs.keys().next_back().unwrap()
+    }
+
+    /// Probability that the result is at least `n`
+    pub fn at_least(&self, n: i64) -> f64 {
+        self.probs.range(n..).fold(0.0, |a, (_,p)| a + p).min(1.0)
+    }
+
+    /// Render a histogram with one row per result, or per range of results if there are
+    /// more than `rows` possible results, showing P(=) and P(>=) for each row
+    pub fn histogram(&self, rows: usize) -> String {
+        let (min, max) = (self.min(), self.max());
+        let width = ((max - min) as u64 / rows as u64 + 1) as i64;
+        let mut bins = Vec::new();
+        let mut start = min;
+        while start <= max {
+            let end = start.saturating_add(width - 1).min(max);
+            let p = self.probs.range(start..=end).map(|(_,p)| p).sum::<f64>();
+            bins.push((start, end, p));
+            if end == max {
+                break
+            }
+            start = end + 1;
+        }
+        let labels = bins.iter()
+            .map(|(s,e,_)| match s == e {
+                true => s.to_string(),
+                false => format!("{}-{}", s, e)
+            })
+            .collect::<Vec<String>>();
+        let label_width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+        let max_p = bins.iter().map(|(_,_,p)| *p).fold(0.0, f64::max);
+        let mut result = format!("{:>w$} {:20} {:>7} {:>7}\n", "", "", "=", ">=", w = label_width);
+        for ((start, _, p), label) in bins.iter().zip(labels) {
+            let bar = "\u{2588}".repeat((p / max_p * 20.0).round() as usize);
+            result += &format!("{:>w$} {:20} {:>6.2}% {:>6.2}%\n", 
+                label, bar, p * 100.0, self.at_least(*start) * 100.0, w = label_width);
+        }
+        result
+    }
+}
+
+fn dist(expr: &Expr, budget: &mut Budget) -> DiceResult<Dist> {
+    match expr {
+        Expr::Num(n) => Ok(std::iter::once((*n, 1.0)).collect()),
+        Expr::Neg(inner) => dist(inner, budget)?.into_iter()
+            .map(|(v,p)| v.checked_neg().map(|v| (v,p)))
+            .collect::<Option<Dist>>()
+            .ok_or("result too large"),
+        Expr::Paren(inner) => dist(inner, budget),
+        Expr::Bin(op, lhs, rhs) => {
+            let l = dist(lhs, budget)?;
+            let r = dist(rhs, budget)?;
+            if *op == Op::Div && r.contains_key(&0) {
+                return Err("division by zero")
+            }
+            combine(&l, &r, budget, |a, b| match op {
+                Op::Add => a.checked_add(b),
+                Op::Sub => a.checked_sub(b),
+                Op::Mul => a.checked_mul(b),
+                Op::Div => a.checked_div(b),
+            })
+        },
+        Expr::Dice(spec) => group_dist(spec, budget),
+    }
+}
+
+fn combine(a: &Dist, b: &Dist, budget: &mut Budget, f: impl Fn(i64, i64) -> Option<i64>) -> DiceResult<Dist> {
+    budget.spend(a.len() * b.len())?;
+    let mut result = Dist::new();
+    for (x, p) in a {
+        for (y, q) in b {
+            let v = f(*x, *y).ok_or("result too large")?;
+            *result.entry(v).or_insert(0.0) += p * q;
+        }
+    }
+    Ok(result)
+}
+
+/// Distribution of a single die's face, after any rerolls
+fn face_dist(spec: &DiceSpec) -> DiceResult<Dist> {
+    let (min, max) = (spec.sides.min(), spec.sides.max());
+    if max - min >= MAX_STAT_SIDES {
+        return Err("dice have too many sides to calculate")
+    }
+    let n = (max - min + 1) as f64;
+    let faces = min..=max;
+    Ok(match spec.reroll {
+        None => faces.map(|v| (v, 1.0 / n)).collect(),
+        Some(reroll) => {
+            let matching = faces.clone().filter(|v| reroll.when.matches(*v)).count() as f64;
+            faces.map(|v| {
+                let kept = match reroll.when.matches(v) {
+                    true => 0.0,
+                    false => 1.0 / n,
+                };
+                match reroll.once {
+                    true => (v, kept + matching / n / n),
+                    false => (v, kept * n / (n - matching)),
+                }
+            }).collect()
+        }
+    })
+}
+
+/// What a die with a given face adds to the group's total
+fn score(spec: &DiceSpec, v: i64) -> i64 {
+    match spec.success {
+        Some(cmp) => cmp.matches(v) as i64,
+        None => v
+    }
+}
+
+fn group_dist(spec: &DiceSpec, budget: &mut Budget) -> DiceResult<Dist> {
+    let face = face_dist(spec)?;
+    match spec.keep {
+        Some(keep) if spec.explode.is_none() => keep_dist(spec, keep, &face, budget),
+        Some(_) => Err("cannot calculate odds for exploding dice that are kept or dropped"),
+        None => {
+            let die = chain_dist(spec, &face, budget)?;
+            let mut total: Dist = std::iter::once((0, 1.0)).collect();
+            for _ in 0..spec.count {
+                total = combine(&total, &die, budget, i64::checked_add)?;
+            }
+            Ok(total)
+        }
+    }
+}
+
+/// Distribution of a die's score, including any dice it explodes into
+fn chain_dist(spec: &DiceSpec, face: &Dist, budget: &mut Budget) -> DiceResult<Dist> {
+    let scored = |exploding: bool| face.iter()
+        .filter(|(v,_)| spec.explode.map(|e| e.matches(**v)).unwrap_or(false) == exploding)
+        .fold(Dist::new(), |mut d, (v,p)| {
+            *d.entry(score(spec, *v)).or_insert(0.0) += p;
+            d
+        });
+    let base = scored(false);
+    if spec.explode.is_none() {
+        return Ok(base)
+    }
+    let exploding = scored(true);
+    let mut chain = base.clone();
+    for _ in 0..EXPLODE_DEPTH {
+        chain = combine(&exploding, &chain, budget, i64::checked_add)?;
+        for (v, p) in &base {
+            *chain.entry(*v).or_insert(0.0) += p;
+        }
+    }
+    Ok(chain)
+}
+
+/// Distribution of the sum of the kept dice. Faces are considered from the
+/// most to the least likely to be kept, tracking how many dice have been
+/// assigned a face so far and the total of those that are kept.
+fn keep_dist(spec: &DiceSpec, keep: Keep, face: &Dist, budget: &mut Budget) -> DiceResult<Dist> {
+    let n = spec.count;
+    let (k, highest) = match keep {
+        Keep::Highest(k) => (k.min(n), true),
+        Keep::Lowest(k) => (k.min(n), false),
+        Keep::DropHighest(d) => (n - d.min(n), false),
+        Keep::DropLowest(d) => (n - d.min(n), true),
+    };
+    let mut faces = face.iter().map(|(v,p)| (*v, *p)).collect::<Vec<(i64, f64)>>();
+    if highest {
+        faces.reverse();
+    }
+    let ln_fact = (0..=n).scan(0.0, |acc, i| {
+        if i > 0 {
+            *acc += (i as f64).ln();
+        }
+        Some(*acc)
+    }).collect::<Vec<f64>>();
+    let mut remaining = 1.0;
+    let mut states: HashMap<(usize, i64), f64> = std::iter::once(((0, 0), 1.0)).collect();
+    for (v, p) in faces {
+        let q = match remaining > 0.0 {
+            true => (p / remaining).min(1.0),
+            false => 1.0
+        };
+        remaining -= p;
+        let mut next = HashMap::new();
+        budget.spend(states.len() * (n + 1))?;
+        for ((assigned, sum), prob) in states {
+            let left = n - assigned;
+            for j in 0..=left {
+                let pmf = binomial_pmf(&ln_fact, left, j, q);
+                if pmf == 0.0 {
+                    continue
+                }
+                let kept = j.min(k - assigned.min(k)) as i64;
+                let sum = sum.checked_add(kept * score(spec, v)).ok_or("result too large")?;
+                *next.entry((assigned + j, sum)).or_insert(0.0) += prob * pmf;
+            }
+        }
+        states = next;
+    }
+    Ok(states.into_iter()
+        .filter(|((assigned,_),_)| *assigned == n)
+        .fold(Dist::new(), |mut d, ((_,sum),p)| {
+            *d.entry(sum).or_insert(0.0) += p;
+            d
+        }))
+}
+
+fn binomial_pmf(ln_fact: &[f64], n: usize, j: usize, q: f64) -> f64 {
+    if q <= 0.0 {
+        return (j == 0) as u8 as f64
+    } else if q >= 1.0 {
+        return (j == n) as u8 as f64
+    }
+    let ln_choose = ln_fact[n] - ln_fact[j] - ln_fact[n-j];
+    (ln_choose + j as f64 * q.ln() + (n-j) as f64 * (1.0-q).ln()).exp()
+}
+
+//
+// Character sheets
+//
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_'
+}
+
+fn check_name(name: &str) -> DiceResult<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !name.bytes().all(is_name_char) {
+        return Err("names must be up to 32 letters, numbers or underscores")
+    }
+    Ok(())
+}
+
+/// A user's saved rolls and stats in a guild
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Sheet {
+    pub rolls: BTreeMap<String, String>,
+    pub stats: BTreeMap<String, i64>,
+}
+
+impl Sheet {
+    pub fn is_empty(&self) -> bool {
+        self.rolls.is_empty() && self.stats.is_empty()
+    }
+
+    pub fn get_roll(&self, name: &str) -> Option<&String> {
+        self.rolls.get(&name.to_lowercase())
+    }
+
+    /// Save a roll, replacing any existing roll with the same name. The
+    /// expression must be valid with the stats currently on the sheet.
+    pub fn save_roll(&mut self, name: &str, expr: &str) -> DiceResult<()> {
+        let name = name.to_lowercase();
+        check_name(&name)?;
+        if RESERVED.contains(&name.as_str()) || parse(&name).is_ok() {
+            return Err("that name is reserved")
+        }
+        if !self.rolls.contains_key(&name) && self.rolls.len() >= MAX_SAVED {
+            return Err("too many saved rolls")
+        }
+        parse_with(expr, &self.stats)?;
+        self.rolls.insert(name, expr.to_owned());
+        Ok(())
+    }
+
+    pub fn delete_roll(&mut self, name: &str) -> bool {
+        self.rolls.remove(&name.to_lowercase()).is_some()
+    }
+
+    pub fn set_stat(&mut self, name: &str, value: i64) -> DiceResult<()> {
+        let name = name.trim_start_matches('@').to_lowercase();
+        check_name(&name)?;
+        if value.abs() > MAX_STAT {
+            return Err("stat value is too large")
+        }
+        if !self.stats.contains_key(&name) && self.stats.len() >= MAX_SAVED {
+            return Err("too many stats")
+        }
+        self.stats.insert(name, value);
+        Ok(())
+    }
+
+    pub fn delete_stat(&mut self, name: &str) -> bool {
+        self.stats.remove(&name.trim_start_matches('@').to_lowercase()).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn roll_seeded(s: &str, seed: u64) -> DiceResult<Roll> {
+        roll(&parse(s)?, &mut StdRng::seed_from_u64(seed), false, &Cancel::default())
+    }
+
+    #[test]
+    fn totals_in_range() {
+        for seed in 0..200 {
+            let total = roll_seeded("3d6", seed).unwrap().total;
+            assert!((3..=18).contains(&total), "{}", total);
+            let kept = roll_seeded("4d6kh3", seed).unwrap().total;
+            assert!((3..=18).contains(&kept), "{}", kept);
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(roll_seeded("2d1 + 3", 0).unwrap().total, 5);
+        assert_eq!(roll_seeded("(1 + 2) * 3 - 4 / 2", 0).unwrap().total, 7);
+        assert!(roll_seeded("1 / 0", 0).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(roll_seeded("2000d6 + 2000d6", 0).unwrap_err(), "too many dice");
+        assert!(parse("3000d6").is_err());
+        assert!(parse("2d1!").is_err());
+    }
+
+    #[test]
+    fn distribution_of_two_dice() {
+        let dist = distribution(&parse("2d6").unwrap(), &Cancel::default()).unwrap();
+        assert_eq!(dist.min(), 2);
+        assert_eq!(dist.max(), 12);
+        assert!((dist.mean() - 7.0).abs() < 1e-9);
+        assert!((dist.at_least(12) - 1.0 / 36.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn same_seed_same_roll() {
+        let a = roll_seeded("4d6kh3 + 2", 7).unwrap();
+        let b = roll_seeded("4d6kh3 + 2", 7).unwrap();
+        assert_eq!(a.total, b.total);
+        assert_eq!(a.text, b.text);
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = Cancel::default();
+        cancel.cancel();
+        let result = roll(&parse("2d6").unwrap(), &mut StdRng::seed_from_u64(0), false, &cancel);
+        assert_eq!(result.unwrap_err(), sandbox::CANCELLED);
+    }
+}
@@ -0,0 +1,129 @@
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+pub const MAX_ANSWERS: usize = 50;
+const MAX_ANSWER_LEN: usize = 100;
+const MAX_WEIGHT: u32 = 100;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Category {
+    Positive,
+    Neutral,
+    Negative,
+}
+
+impl Category {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "positive" | "yes" => Some(Category::Positive),
+            "neutral" | "maybe" => Some(Category::Neutral),
+            "negative" | "no" => Some(Category::Negative),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Positive => "positive",
+            Category::Neutral => "neutral",
+            Category::Negative => "negative",
+        }
+    }
+
+    pub fn color(&self) -> u32 {
+        match self {
+            Category::Positive => 0x33aa44,
+            Category::Neutral => 0xccaa22,
+            Category::Negative => 0xcc3333,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Answer {
+    pub text: String,
+    pub category: Category,
+    pub weight: u32,
+}
+
+impl Answer {
+    pub fn new(text: &str, category: Category, weight: u32) -> Result<Self, &'static str> {
+        if text.is_empty() || text.chars().count() > MAX_ANSWER_LEN {
+            return Err("answers must be between 1 and 100 characters")
+        }
+        if weight == 0 || weight > MAX_WEIGHT {
+            return Err("weights must be between 1 and 100")
+        }
+        Ok(Self { text: text.to_owned(), category, weight })
+    }
+}
+
+const DEFAULT_ANSWERS: &[(&str, Category)] = &[
+    ("It is certain.", Category::Positive), ("It is decidedly so.", Category::Positive),
+    ("Without a doubt.", Category::Positive), ("Yes - definitely.", Category::Positive),
+    ("You may rely on it.", Category::Positive), ("As I see it, yes.", Category::Positive),
+    ("Most likely.", Category::Positive), ("Outlook good.", Category::Positive),
+    ("Yes.", Category::Positive), ("Signs point to yes.", Category::Positive),
+
+    ("Reply hazy, try again.", Category::Neutral), ("Ask again later.", Category::Neutral),
+    ("Better not tell you now.", Category::Neutral), ("Cannot predict now.", Category::Neutral),
+    ("Concentrate and ask again.", Category::Neutral),
+
+    ("Don't count on it.", Category::Negative), ("My reply is no.", Category::Negative),
+    ("My sources say no.", Category::Negative), ("Outlook not so good.", Category::Negative),
+    ("Very doubtful.", Category::Negative),
+];
+
+pub fn default_answers() -> Vec<Answer> {
+    DEFAULT_ANSWERS.iter()
+        .map(|(text, category)| Answer { text: (*text).to_owned(), category: *category, weight: 1 })
+        .collect()
+}
+
+/// Pick an answer at random, with each answer's chance proportional to its weight
+pub fn pick<'a, R: Rng + ?Sized>(answers: &'a [Answer], rng: &mut R) -> &'a Answer {
+    let total = answers.iter().map(|a| a.weight).sum::<u32>();
+    let mut n = rng.gen_range(0, total);
+    for answer in answers {
+        if n < answer.weight {
+            return answer
+        }
+        n -= answer.weight;
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn same_seed_same_answer() {
+        let answers = default_answers();
+        let a = pick(&answers, &mut StdRng::seed_from_u64(5)).text.clone();
+        let b = pick(&answers, &mut StdRng::seed_from_u64(5)).text.clone();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn weights() {
+        let answers = vec![
+            Answer::new("Often", Category::Positive, 99).unwrap(),
+            Answer::new("Rarely", Category::Negative, 1).unwrap(),
+        ];
+        let mut rng = StdRng::seed_from_u64(0);
+        let often = (0..1000).filter(|_| pick(&answers, &mut rng).text == "Often").count();
+        assert!(often > 950, "{}", often);
+        let single = vec![Answer::new("Only", Category::Neutral, 3).unwrap()];
+        assert_eq!(pick(&single, &mut rng).text, "Only");
+    }
+
+    #[test]
+    fn invalid_answers() {
+        assert!(Answer::new("", Category::Neutral, 1).is_err());
+        assert!(Answer::new("Yes", Category::Neutral, 0).is_err());
+        assert!(Answer::new("Yes", Category::Neutral, 101).is_err());
+    }
+}
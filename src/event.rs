@@ -5,11 +5,15 @@ use serenity::{
         prelude::*,
     },
     client::bridge::gateway::ShardManager,
+    http::Http,
     prelude::*,
-    utils::Colour
 };
+use std::sync::Arc;
+use std::time::Duration;
 use crate::state::*;
 use crate::commands;
+use crate::counting;
+use crate::users;
 use crate::utils;
 
 pub struct ShardManagerKey;
@@ -32,6 +36,11 @@ impl EventHandler for Handler {
                     return
                 }
             };
+            match (msg.guild_id, &msg.member) {
+                (Some(guild), Some(member)) => state.users_mut()
+                    .update_member(guild, &msg.author, member.nick.clone()),
+                _ => state.users_mut().update_user(&msg.author)
+            }
             let banned = state.is_banned(msg.author.id);
             if !banned {
                 let result = run_command(&ctx, &msg, state).await;
@@ -39,10 +48,28 @@ impl EventHandler for Handler {
                     Err(e) => warn!("Error running command: {:?}", e),
                     Ok(code) => exitcode = code
                 }
-                match state.save_if_dirty() {
-                    Ok(true) => info!("State saved"),
-                    Ok(false) => (),
-                    Err(e) => error!("Attempt to save dirty state failed: {:?}", e)
+                save_state(state);
+            }
+        } else if let (false, Some(guild)) = (msg.author.bot, msg.guild_id) {
+            let expressions = match ctx.data.read().await.get::<State>() {
+                Some(state) if state.is_counting_channel(guild, msg.channel_id) =>
+                    state.get_counting_game(guild).map(|g| g.expressions),
+                _ => None
+            };
+            // Parse the message before locking, since expressions take time to evaluate
+            let number = match expressions {
+                Some(expressions) => counting::parse(&msg.content, expressions).await,
+                None => None
+            };
+            if let Some(number) = number {
+                let mut data = ctx.data.write().await;
+                if let Some(state) = data.get_mut::<State>() {
+                    if !state.is_banned(msg.author.id) {
+                        if let Err(e) = commands::counting_message(&ctx, &msg, guild, number, state).await {
+                            warn!("Error in counting channel: {:?}", e);
+                        }
+                        save_state(state);
+                    }
                 }
             }
         }
@@ -62,59 +89,129 @@ impl EventHandler for Handler {
     }
 
     async fn ready(&self, ctx: Context, ready: Ready) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().set_current_user(&ready.user);
+        }
         ctx.online().await;
         ctx.set_activity(Activity::playing("your mother")).await;
         info!("Ready");
         info!("Guild count: {}", ready.guilds.len());
     }
 
-    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
-        let bot_user = ctx.http.get_current_user().await.unwrap();
-        if reaction.user_id.unwrap() == bot_user.id {
-            return 
+    async fn user_update(&self, ctx: Context, user: CurrentUser) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().set_current_user(&user);
         }
-        let reactor = reaction.user_id.unwrap().to_user(&ctx.http).await;
-        if let Err(e) = reactor {
-            warn!("Could not get reactor user: {:?}", e);
-            return
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().set_guild(&guild);
         }
-        let reactor = reactor.unwrap();
-        if reactor.bot {
-            return
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, guild_id: GuildId, member: Member) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().update_member(guild_id, &member.user, member.nick);
+        }
+    }
+
+    async fn guild_member_update(&self, ctx: Context, event: GuildMemberUpdateEvent) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().update_member(event.guild_id, &event.user, event.nick);
         }
-        let message = ctx.http.get_message(reaction.channel_id.into(), reaction.message_id.into()).await;
-        if let Err(e) = message {
-            warn!("Could not get reaction message: {:?}", e);
+    }
+
+    async fn guild_member_removal(&self, ctx: Context, guild_id: GuildId, user: User) {
+        if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+            state.users_mut().remove_member(guild_id, user.id);
+        }
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let user_id = match reaction.user_id {
+            Some(x) => x,
+            None => return
+        };
+        // Most reactions aren't on messages the bot cares about, so check
+        // that first without taking the write lock or making any requests
+        let (is_leaderboard, is_poll) = match ctx.data.read().await.get::<State>() {
+            Some(state) => (state.has_leaderboard(reaction.message_id),
+                state.get_poll(reaction.message_id).is_some()),
+            None => {
+                error!("Could not load state data");
+                return
+            }
+        };
+        if !is_leaderboard && !is_poll {
             return
         }
-        let message = message.unwrap();
-        if message.author.id == bot_user.id
-        && message.embeds.len() > 0
-        && message.embeds[0].colour == Colour::from(utils::POLL_COLOR) {
-            let user_id = reactor.id;
-            let my_emoji = reaction.emoji;
-            let emojis = message.reactions.into_iter()
-                .map(|x| x.reaction_type)
-                .filter(|x| x != &my_emoji)
-                .collect::<Vec<ReactionType>>();
-            for emoji in emojis {
-                let reactions = ctx.http.get_reaction_users(
-                    message.channel_id.into(), message.id.into(),
-                    &emoji, 50, None).await;
-                match reactions {
-                    Ok(r) if r.iter().find(|x| x.id == user_id).is_some() => {
-                        let res = ctx.http.delete_reaction(
-                            message.channel_id.into(), message.id.into(),
-                            Some(user_id.into()), &emoji).await;
-                        if let Err(e) = res {
-                            warn!("Could not remove reaction: {:?}", e);
-                        } else {
-                            debug!("Removed reaction");
-                        }
-                    },
-                    Ok(_) => (),
-                    Err(e) => warn!("Error retrieving reactions: {:?}", e)
-                }
+        // This ignores the bot's own reactions too
+        match users::fetch_user_unlocked(&ctx, user_id).await {
+            Ok(reactor) if reactor.bot => return,
+            Ok(_) => (),
+            Err(e) => {
+                warn!("Could not get reactor user: {:?}", e);
+                return
+            }
+        }
+        if is_leaderboard {
+            if let Err(e) = commands::counttop_turn_page(&ctx, &reaction).await {
+                warn!("Error changing leaderboard page: {:?}", e);
+            }
+        } else if let Err(e) = commands::poll_vote(&ctx, &reaction, user_id).await {
+            warn!("Error recording poll vote: {:?}", e);
+        }
+    }
+
+    async fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        let user_id = match reaction.user_id {
+            Some(x) => x,
+            None => return
+        };
+        let is_poll = match ctx.data.read().await.get::<State>() {
+            Some(state) => state.get_poll(reaction.message_id).is_some(),
+            None => return
+        };
+        if is_poll {
+            if let Some(state) = ctx.data.write().await.get_mut::<State>() {
+                commands::poll_unvote(&reaction, user_id, state);
+            }
+        }
+    }
+}
+
+fn save_state(state: &mut State) {
+    match state.save_if_dirty() {
+        Ok(true) => info!("State saved"),
+        Ok(false) => (),
+        Err(e) => error!("Attempt to save dirty state failed: {:?}", e)
+    }
+}
+
+/// Run scheduled tasks, such as season rollovers and closing polls, once a minute
+pub async fn run_scheduler(data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let mut guard = data.write().await;
+        let state = match guard.get_mut::<State>() {
+            Some(x) => x,
+            None => continue
+        };
+        if let Some(season) = state.rollover_season(utils::now_millis()) {
+            info!("Season {} ended, starting a new season", season);
+        }
+        let closed = state.close_due_polls(utils::now_millis());
+        save_state(state);
+        drop(guard);
+        for (id, poll) in closed {
+            match commands::close_poll(&http, id, &poll).await {
+                Ok(()) => if let Some(state) = data.write().await.get_mut::<State>() {
+                    state.poll_announced(id);
+                },
+                // Tried again next time
+                Err(e) => warn!("Error closing poll: {:?}", e)
             }
         }
     }
@@ -140,29 +237,46 @@ pub async fn run_command(ctx: &Context, msg: &Message, state: &mut State) -> com
         "stop" if sender_admin => shutdown(ctx, msg, state, 0).await,
         "restart" if sender_admin => shutdown(ctx, msg, state, 5).await,
         "ban" if sender_admin => ban_unban(ctx, msg, state, true).await,
+        "banned" if sender_admin => banned(ctx, msg, state).await,
+        "audit" if sender_admin => audit(ctx, msg, state).await,
         "unban" if sender_admin => ban_unban(ctx, msg, state, false).await,
         "activity" if sender_admin => activity(ctx, msg, rest).await,
         "status" if sender_admin => status(ctx, msg, rest).await,
-        "add" if sender_admin => add_cmd(rest, state).await,
-        "rm" if sender_admin => rm_cmd(rest, state).await,
-        "ban" | "unban" | "force_save" | "stop" | "restart"
+        "add" if sender_admin => add_cmd(msg, rest, state).await,
+        "rm" if sender_admin => rm_cmd(msg, rest, state).await,
+        "ban" | "unban" | "banned" | "audit" | "force_save" | "stop" | "restart"
             | "activity" | "status" | "add" | "rm"
             => no_perms(ctx, msg).await,
         "version" => version(ctx, msg).await,
         "say" => say(ctx, msg, rest).await,
         "ping" => ping(ctx, msg).await,
         "count" => count(ctx, msg, state).await,
-        "counttop" => counttop(ctx, msg, state).await,
-        "roll" => roll(ctx, msg, rest).await,
-        "8ball" => eightball(ctx, msg, rest).await,
+        "countstats" => countstats(ctx, msg, state).await,
+        "counttop" => counttop(ctx, msg, rest, state).await,
+        "countcooldown" => countcooldown(ctx, msg, rest, state).await,
+        "season" => season(ctx, msg, rest, state).await,
+        "counting" => counting(ctx, msg, rest, state).await,
+        "roll" => roll(ctx, msg, rest, state).await,
+        "odds" => odds(ctx, msg, rest, state).await,
+        "sheet" => sheet(ctx, msg, rest, state).await,
+        "init" => init(ctx, msg, rest, state).await,
+        "choose" => choose(ctx, msg, rest, state).await,
+        "shuffle" => shuffle(ctx, msg, rest, state).await,
+        "rand" => rand(ctx, msg, rest, state).await,
+        "deck" => deck(ctx, msg, rest, state).await,
+        "fair" => fair(ctx, msg, rest, state).await,
+        "8ball" => eightball(ctx, msg, rest, state).await,
         "wikipedia" => wikipedia(ctx, msg, rest).await,
         "xkcd" => xkcd(ctx, msg, rest).await,
         "meme" => meme(ctx, msg, rest).await,
-        "flip" => flip(ctx, msg, rest).await,
-        "eval" => eval(ctx, msg, rest).await,
-        "vote" => vote(ctx, msg, rest).await,
-        "poll" => poll(ctx, msg, rest).await,
-        "help" if rest == "" => send_help(ctx, msg).await,
+        "flip" => flip(ctx, msg, rest, state).await,
+        "eval" => eval(ctx, msg, rest, state).await,
+        "convert" => convert(ctx, msg, rest).await,
+        "base" => base(ctx, msg, rest).await,
+        "plot" => plot(ctx, msg, rest).await,
+        "vote" => vote(ctx, msg, rest, state).await,
+        "poll" => poll(ctx, msg, rest, state).await,
+        "help" if rest.is_empty() => send_help(ctx, msg).await,
         "help" => send_help_command(ctx, msg, rest).await,
         _ => match state.run_custom_cmd(cmd) {
             Some(x) => {
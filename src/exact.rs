@@ -0,0 +1,493 @@
+use num_bigint::{BigInt, Sign};
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use crate::sandbox::{self, Cancel};
+
+pub const DEFAULT_PRECISION: usize = 10;
+pub const MAX_PRECISION: usize = 1000;
+/// Longest number that is written out in full
+const MAX_DIGITS: usize = 1900;
+/// Largest number of bits a power may have
+const MAX_BITS: u64 = 1 << 20;
+const MAX_FACTORIAL: u64 = 20000;
+
+pub type ExactResult<T> = Result<T, &'static str>;
+
+//
+// Parsing and evaluation
+//
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    cancel: &'a Cancel,
+}
+
+/// Evaluate an expression with exact rational arithmetic
+pub fn eval(s: &str, cancel: &Cancel) -> ExactResult<BigRational> {
+    let src = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if src.is_empty() {
+        return Err("no expression given")
+    }
+    let mut parser = Parser { src: src.as_bytes(), pos: 0, cancel };
+    let value = parser.expr()?;
+    if parser.pos < parser.src.len() {
+        return Err("unexpected character")
+    }
+    Ok(value)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_cancel(&self) -> ExactResult<()> {
+        match self.cancel.is_cancelled() {
+            true => Err(sandbox::CANCELLED),
+            false => Ok(())
+        }
+    }
+
+    fn expr(&mut self) -> ExactResult<BigRational> {
+        let mut lhs = self.term()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat(b'+') {
+                lhs += self.term()?;
+            } else if self.eat(b'-') {
+                lhs -= self.term()?;
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn term(&mut self) -> ExactResult<BigRational> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat(b'*') {
+                lhs *= self.unary()?;
+            } else if self.eat(b'/') {
+                let rhs = self.unary()?;
+                if rhs.is_zero() {
+                    return Err("division by zero")
+                }
+                lhs /= rhs;
+            } else if self.eat(b'%') {
+                lhs = modulo(&lhs, &self.unary()?)?;
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn unary(&mut self) -> ExactResult<BigRational> {
+        if self.eat(b'-') {
+            Ok(-self.unary()?)
+        } else if self.eat(b'+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> ExactResult<BigRational> {
+        let base = self.postfix()?;
+        if self.eat(b'^') {
+            // Right associative, and binds tighter than a unary minus on the left
+            let exp = self.unary()?;
+            return pow(&base, &exp)
+        }
+        Ok(base)
+    }
+
+    fn postfix(&mut self) -> ExactResult<BigRational> {
+        let mut value = self.atom()?;
+        while self.eat(b'!') {
+            value = BigRational::from_integer(factorial(&integer(&value)?, self.cancel)?);
+        }
+        Ok(value)
+    }
+
+    fn atom(&mut self) -> ExactResult<BigRational> {
+        if self.eat(b'(') {
+            let inner = self.expr()?;
+            if !self.eat(b')') {
+                return Err("unmatched parenthesis")
+            }
+            return Ok(inner)
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.call(),
+            _ => Err("unexpected character")
+        }
+    }
+
+    fn digits(&mut self) -> &'a str {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+    }
+
+    /// Parse a decimal number such as `12`, `0.1` or `1.5e-3` exactly
+    fn number(&mut self) -> ExactResult<BigRational> {
+        let whole = self.digits();
+        let frac = match self.eat(b'.') {
+            true => self.digits(),
+            false => ""
+        };
+        if whole.is_empty() && frac.is_empty() {
+            return Err("invalid number")
+        }
+        let mantissa = format!("{}{}", whole, frac).parse::<BigInt>().map_err(|_| "invalid number")?;
+        let mut exp = -(frac.len() as i64);
+        let next = self.src.get(self.pos + 1).copied();
+        let after = self.src.get(self.pos + 2).copied();
+        let has_exp = self.peek() == Some(b'e') && match next {
+            Some(b'-') | Some(b'+') => matches!(after, Some(c) if c.is_ascii_digit()),
+            Some(c) => c.is_ascii_digit(),
+            None => false
+        };
+        if has_exp {
+            self.pos += 1;
+            let negative = self.eat(b'-');
+            if !negative {
+                self.eat(b'+');
+            }
+            let e = self.digits().parse::<i64>().map_err(|_| "exponent is too large")?;
+            exp += if negative { -e } else { e };
+        }
+        if exp.unsigned_abs() > MAX_DIGITS as u64 * 10 {
+            return Err("exponent is too large")
+        }
+        let scale = BigInt::from(10).pow(exp.unsigned_abs() as u32);
+        Ok(match exp >= 0 {
+            true => BigRational::from_integer(mantissa * scale),
+            false => BigRational::new(mantissa, scale)
+        })
+    }
+
+    fn call(&mut self) -> ExactResult<BigRational> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        if !self.eat(b'(') {
+            return Err("unknown variable, variables can't be used in exact mode")
+        }
+        let mut args = Vec::new();
+        if !self.eat(b')') {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(b')') {
+                    break
+                }
+                if !self.eat(b',') {
+                    return Err("expected `,` or `)`")
+                }
+            }
+        }
+        call(name, &args, self.cancel)
+    }
+}
+
+fn call(name: &str, args: &[BigRational], cancel: &Cancel) -> ExactResult<BigRational> {
+    let int = |i: usize| integer(&args[i]);
+    let from_int = BigRational::from_integer;
+    let arity = match name {
+        "abs" | "floor" | "ceil" | "round" | "fact" | "factorial" | "isprime"
+            | "num" | "numer" | "den" | "denom" => 1,
+        "gcd" | "lcm" | "mod" | "binom" | "choose" | "ncr" | "npr" => 2,
+        "powmod" => 3,
+        _ => return Err("unknown function, your own functions can't be used in exact mode")
+    };
+    if args.len() != arity {
+        return Err("wrong number of arguments")
+    }
+    Ok(match name {
+        "abs" => args[0].abs(),
+        "floor" => args[0].floor(),
+        "ceil" => args[0].ceil(),
+        "round" => args[0].round(),
+        "num" | "numer" => from_int(args[0].numer().clone()),
+        "den" | "denom" => from_int(args[0].denom().clone()),
+        "fact" | "factorial" => from_int(factorial(&int(0)?, cancel)?),
+        "isprime" => match is_prime(&int(0)?, cancel)? {
+            true => BigRational::one(),
+            false => BigRational::zero()
+        },
+        "gcd" => from_int(int(0)?.gcd(&int(1)?)),
+        "lcm" => from_int(int(0)?.lcm(&int(1)?)),
+        "mod" => modulo(&args[0], &args[1])?,
+        "binom" | "choose" | "ncr" => from_int(binomial(&int(0)?, &int(1)?, cancel)?),
+        "npr" => {
+            let (n, k) = (int(0)?, int(1)?);
+            from_int(binomial(&n, &k, cancel)? * factorial(&k, cancel)?)
+        },
+        "powmod" => {
+            let (b, e, m) = (int(0)?, int(1)?, int(2)?);
+            if e.is_negative() {
+                return Err("powmod needs a non-negative exponent")
+            }
+            if m.is_zero() {
+                return Err("division by zero")
+            }
+            from_int(b.modpow(&e, &m.abs()))
+        },
+        _ => unreachable!()
+    })
+}
+
+fn integer(x: &BigRational) -> ExactResult<BigInt> {
+    match x.is_integer() {
+        true => Ok(x.to_integer()),
+        false => Err("expected an integer")
+    }
+}
+
+/// The remainder of `a / b`, with the same sign as `b`
+fn modulo(a: &BigRational, b: &BigRational) -> ExactResult<BigRational> {
+    if b.is_zero() {
+        return Err("division by zero")
+    }
+    Ok(a - b * (a / b).floor())
+}
+
+fn pow(base: &BigRational, exp: &BigRational) -> ExactResult<BigRational> {
+    let exp = integer(exp).map_err(|_| "exponents must be integers in exact mode")?;
+    if base.is_zero() {
+        return match exp.sign() {
+            Sign::Minus => Err("division by zero"),
+            Sign::NoSign => Ok(BigRational::one()),
+            Sign::Plus => Ok(BigRational::zero())
+        }
+    }
+    let bits = base.numer().bits().max(base.denom().bits());
+    let e = exp.abs().to_u64().filter(|e| e.saturating_mul(bits) <= MAX_BITS);
+    let e = match e {
+        Some(e) => e as i32,
+        // A base of 1 or -1 can be raised to any power
+        None if base.abs().is_one() => match exp.is_even() {
+            true => 2,
+            false => 1
+        },
+        None => return Err("result is too large")
+    };
+    Ok(match exp.is_negative() {
+        true => base.pow(-e),
+        false => base.pow(e)
+    })
+}
+
+fn factorial(n: &BigInt, cancel: &Cancel) -> ExactResult<BigInt> {
+    if n.is_negative() {
+        return Err("factorial of a negative number")
+    }
+    let n = n.to_u64().filter(|n| *n <= MAX_FACTORIAL).ok_or("factorial is too large")?;
+    let mut result = BigInt::one();
+    for i in 1..=n {
+        if cancel.is_cancelled() {
+            return Err(sandbox::CANCELLED)
+        }
+        result *= i;
+    }
+    Ok(result)
+}
+
+fn binomial(n: &BigInt, k: &BigInt, cancel: &Cancel) -> ExactResult<BigInt> {
+    if k.is_negative() || k > n {
+        return Ok(BigInt::zero())
+    }
+    let k = (n - k).min(k.clone());
+    let k = k.to_u64().filter(|k| *k <= MAX_FACTORIAL).ok_or("binomial is too large")?;
+    let mut result = BigInt::one();
+    for i in 0..k {
+        if cancel.is_cancelled() {
+            return Err(sandbox::CANCELLED)
+        }
+        result = result * (n - i) / (i + 1);
+    }
+    Ok(result)
+}
+
+/// Miller-Rabin with the first 13 primes as bases, which is exact below 3.3e24
+/// and correct with overwhelming probability above that
+fn is_prime(n: &BigInt, cancel: &Cancel) -> ExactResult<bool> {
+    const BASES: &[u32] = &[2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41];
+    if *n < BigInt::from(2) {
+        return Ok(false)
+    }
+    for b in BASES {
+        let b = BigInt::from(*b);
+        if *n == b {
+            return Ok(true)
+        }
+        if n.is_multiple_of(&b) {
+            return Ok(false)
+        }
+    }
+    let one = BigInt::one();
+    let n1 = n - 1u32;
+    let mut d = n1.clone();
+    let mut s = 0;
+    while d.is_even() {
+        d >>= 1;
+        s += 1;
+    }
+    'bases: for b in BASES {
+        let mut x = BigInt::from(*b).modpow(&d, n);
+        if x == one || x == n1 {
+            continue
+        }
+        for _ in 1..s {
+            if cancel.is_cancelled() {
+                return Err(sandbox::CANCELLED)
+            }
+            x = x.modpow(&BigInt::from(2), n);
+            if x == n1 {
+                continue 'bases
+            }
+        }
+        return Ok(false)
+    }
+    Ok(true)
+}
+
+//
+// Formatting
+//
+
+/// Format a result as an integer or fraction, with its decimal expansion to
+/// `precision` places. Numbers too long for a message are written in
+/// scientific notation with `precision` significant figures.
+pub fn format(x: &BigRational, precision: usize) -> String {
+    if x.is_integer() {
+        let s = x.numer().to_string();
+        return match s.len() > MAX_DIGITS {
+            true => scientific(x, precision),
+            false => s
+        }
+    }
+    let fraction = format!("{}/{}", x.numer(), x.denom());
+    let small = x.abs() < BigRational::new(BigInt::one(), BigInt::from(10).pow(precision as u32));
+    let (decimal, exact) = match small || x.numer().bits() > MAX_DIGITS as u64 * 3 {
+        true => (scientific(x, precision), false),
+        false => decimal(x, precision)
+    };
+    let decimal = match decimal.len() > MAX_DIGITS {
+        true => scientific(x, precision),
+        false => decimal
+    };
+    let approx = if exact { "=" } else { "≈" };
+    match fraction.len() + decimal.len() > MAX_DIGITS {
+        true => format!("≈ {}", decimal),
+        false => format!("{} {} {}", fraction, approx, decimal)
+    }
+}
+
+/// Write a number to `places` decimal places, trimming trailing zeros, and
+/// whether the result is exact
+fn decimal(x: &BigRational, places: usize) -> (String, bool) {
+    let scale = BigInt::from(10).pow(places as u32);
+    let scaled = x.abs() * BigRational::from_integer(scale);
+    let exact = scaled.is_integer();
+    let digits = scaled.round().to_integer().to_string();
+    let digits = format!("{:0>width$}", digits, width = places + 1);
+    let (whole, frac) = digits.split_at(digits.len() - places);
+    let frac = frac.trim_end_matches('0');
+    let sign = if x.is_negative() { "-" } else { "" };
+    match frac.is_empty() {
+        true => (format!("{}{}", sign, whole), exact),
+        false => (format!("{}{}.{}", sign, whole, frac), exact)
+    }
+}
+
+/// Write a non-zero number as `d.ddd…e±n` with `figures` significant figures
+fn scientific(x: &BigRational, figures: usize) -> String {
+    let figures = figures.max(1);
+    let abs = x.abs();
+    // Estimate the exponent from the number of digits, then correct it
+    let mut exp = abs.numer().to_string().len() as i64 - abs.denom().to_string().len() as i64;
+    let ten = BigRational::from_integer(BigInt::from(10));
+    let scaled = |exp: i64| match exp >= 0 {
+        true => &abs / ten.pow(exp as i32),
+        false => &abs * ten.pow(-exp as i32)
+    };
+    if scaled(exp) < BigRational::one() {
+        exp -= 1;
+    }
+    let mut mantissa = (scaled(exp) * ten.pow(figures as i32 - 1)).round().to_integer().to_string();
+    if mantissa.len() > figures {
+        // Rounding carried into another digit, such as 9.99 to 10.0
+        exp += 1;
+        mantissa.truncate(figures);
+    }
+    let (first, rest) = mantissa.split_at(1);
+    let rest = rest.trim_end_matches('0');
+    let sign = if x.is_negative() { "-" } else { "" };
+    match rest.is_empty() {
+        true => format!("{}{}e{}", sign, first, exp),
+        false => format!("{}{}.{}e{}", sign, first, rest, exp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(s: &str) -> ExactResult<String> {
+        eval(s, &Cancel::default()).map(|v| v.to_string())
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(eval_str("1/3 + 1/6").unwrap(), "1/2");
+        assert_eq!(eval_str("0.1 + 0.2").unwrap(), "3/10");
+        assert_eq!(eval_str("-2^2").unwrap(), "-4");
+        assert_eq!(eval_str("2^-2").unwrap(), "1/4");
+        assert_eq!(eval_str("7 % 3").unwrap(), "1");
+    }
+
+    #[test]
+    fn big_integers() {
+        assert_eq!(eval_str("2^100").unwrap(), "1267650600228229401496703205376");
+        assert_eq!(eval_str("20!").unwrap(), "2432902008176640000");
+        assert_eq!(eval_str("binom(10, 3)").unwrap(), "120");
+        assert_eq!(eval_str("isprime(2^61 - 1)").unwrap(), "1");
+        assert_eq!(eval_str("isprime(91)").unwrap(), "0");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_str("1/0").unwrap_err(), "division by zero");
+        assert_eq!(eval_str("(-1)!").unwrap_err(), "factorial of a negative number");
+        assert_eq!(eval_str("100000000000000000000!").unwrap_err(), "factorial is too large");
+        assert!(eval_str("x + 1").is_err());
+        assert!(eval_str("(1").is_err());
+    }
+
+    #[test]
+    fn formatting() {
+        let third = eval("1/3", &Cancel::default()).unwrap();
+        assert!(format(&third, 5).contains("0.33333"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use rand::seq::SliceRandom;
+use crate::cards::{self, Deck};
+use crate::eightball::{self, Answer};
+use crate::sandbox::Cancel;
+use crate::utils;
+
+/// Number of results kept per seed so that they can be replayed
+const MAX_DRAWS: usize = 1000;
+/// Length of the commitment prefix used in result IDs
+const ID_PREFIX_LEN: usize = 6;
+/// Longest list of items, in bytes, that is stored with a `choose` or
+/// `shuffle` draw. Longer lists only have their length stored.
+const MAX_STORED_ITEMS_LEN: usize = 200;
+/// Length of the hashes used to identify 8-ball answer sets
+const ANSWER_SET_HASH_LEN: usize = 16;
+
+/// What a random result was used for, kept so that it can be replayed
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Draw {
+    Roll { dice: String, stats: BTreeMap<String, i64>, sort: bool },
+    Flip(u32),
+    /// An answer from the original 8-ball answers, before servers could change them
+    EightBall,
+    /// An answer from the answer set with the given hash
+    EightBallFrom(String),
+    Choose(Vec<String>),
+    Shuffle(Vec<String>),
+    /// Choosing from a list too long to store, replayed by position
+    ChooseCount(usize),
+    /// Shuffling a list too long to store, replayed by position
+    ShuffleCount(usize),
+    Rand(i64, i64),
+    /// Shuffling a deck, with the cards in the order they were in beforehand
+    Deck(Vec<u8>),
+}
+
+impl Draw {
+    /// The draw for choosing one of `items`, storing them if they are short
+    pub fn choose(items: &[String]) -> Self {
+        match items.iter().map(String::len).sum::<usize>() <= MAX_STORED_ITEMS_LEN {
+            true => Draw::Choose(items.to_vec()),
+            false => Draw::ChooseCount(items.len())
+        }
+    }
+
+    /// The draw for shuffling `items`, storing them if they are short
+    pub fn shuffle(items: &[String]) -> Self {
+        match items.iter().map(String::len).sum::<usize>() <= MAX_STORED_ITEMS_LEN {
+            true => Draw::Shuffle(items.to_vec()),
+            false => Draw::ShuffleCount(items.len())
+        }
+    }
+
+    /// Describe the result of this draw using `rng`
+    pub fn replay<R: Rng + ?Sized>(&self, rng: &mut R, answer_sets: &HashMap<String, Vec<Answer>>, cancel: &Cancel) -> String {
+        match self {
+            Draw::Roll { dice, stats, sort } => match utils::roll_dice(dice, *sort, stats, rng, cancel) {
+                Ok(roll) => format!("`roll {}`: {} = **{}**", dice, roll.text, roll.total),
+                Err(e) => format!("`roll {}`: error rolling dice: {}", dice, e)
+            },
+            Draw::Flip(n) => {
+                let (heads, tails) = utils::flip_coins(*n, rng);
+                format!("`flip {}`: {} heads and {} tails", n, heads, tails)
+            },
+            Draw::EightBall => {
+                let answers = eightball::default_answers();
+                format!("`8ball`: {}", answers[rng.gen_range(0, answers.len())].text)
+            },
+            Draw::EightBallFrom(hash) => match answer_sets.get(hash) {
+                Some(answers) => format!("`8ball`: {}", eightball::pick(answers, rng).text),
+                None => "`8ball`: the answers it was drawn from are missing".to_owned()
+            },
+            Draw::Choose(items) => format!("`choose`: **{}**", items.choose(rng).unwrap()),
+            Draw::Shuffle(items) => {
+                let mut items = items.clone();
+                items.shuffle(rng);
+                format!("`shuffle`: {}", items.join(", "))
+            },
+            Draw::ChooseCount(n) => {
+                let positions = (1..=*n).collect::<Vec<_>>();
+                format!("`choose`: item **{}** of {}", positions.choose(rng).unwrap(), n)
+            },
+            Draw::ShuffleCount(n) => {
+                let mut positions = (1..=*n).collect::<Vec<_>>();
+                positions.shuffle(rng);
+                format!("`shuffle`: items {}", positions.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "))
+            },
+            Draw::Rand(min, max) => format!("`rand {} {}`: **{}**", min, max, utils::random_int(*min, *max, rng)),
+            Draw::Deck(cards) => {
+                let mut deck = Deck { cards: cards.clone() };
+                deck.shuffle(rng);
+                let order = deck.cards.iter().rev().map(|c| cards::card_name(*c)).collect::<Vec<_>>();
+                format!("`deck shuffle`: {}", order.join(" "))
+            }
+        }
+    }
+}
+
+/// A secret seed for a guild. Result `n` is drawn from ChaCha20 seeded with
+/// the seed, using `n` as the stream, so once the seed is revealed anyone can
+/// reproduce every result. The SHA-256 hash of the seed is published when it
+/// is created, so it can't be swapped out afterwards.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FairSeed {
+    seed: [u8; 32],
+    next: u64,
+    draws: VecDeque<(u64, Draw)>,
+    pub revealed: bool,
+    /// 8-ball answer sets used by the recorded draws, stored once each
+    #[serde(default)]
+    answer_sets: HashMap<String, Vec<Answer>>,
+}
+
+impl FairSeed {
+    pub fn new() -> Self {
+        let mut seed = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut seed);
+        Self { seed, next: 0, draws: VecDeque::new(), revealed: false, answer_sets: HashMap::new() }
+    }
+
+    pub fn seed(&self) -> String {
+        hex(&self.seed)
+    }
+
+    pub fn commitment(&self) -> String {
+        hex(&Sha256::digest(&self.seed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.next
+    }
+
+    fn id(&self, n: u64) -> String {
+        format!("{}-{}", &self.commitment()[..ID_PREFIX_LEN], n)
+    }
+
+    fn rng(&self, n: u64) -> ChaCha20Rng {
+        let mut rng = ChaCha20Rng::from_seed(self.seed);
+        rng.set_stream(n);
+        rng
+    }
+
+    /// Record a draw, returning its ID and the RNG to draw it with
+    pub fn draw(&mut self, draw: Draw) -> (String, ChaCha20Rng) {
+        let n = self.next;
+        self.next += 1;
+        self.draws.push_back((n, draw));
+        if self.draws.len() > MAX_DRAWS {
+            if let Some((_, Draw::EightBallFrom(hash))) = self.draws.pop_front() {
+                if !self.draws.iter().any(|(_,d)| matches!(d, Draw::EightBallFrom(h) if *h == hash)) {
+                    self.answer_sets.remove(&hash);
+                }
+            }
+        }
+        (self.id(n), self.rng(n))
+    }
+
+    /// Store an 8-ball answer set, returning the draw for an answer from it
+    pub fn eight_ball(&mut self, answers: &[Answer]) -> Draw {
+        let json = serde_json::to_vec(answers).expect("answers can always be serialized");
+        let hash = hex(&Sha256::digest(&json))[..ANSWER_SET_HASH_LEN].to_owned();
+        self.answer_sets.entry(hash.clone()).or_insert_with(|| answers.to_vec());
+        Draw::EightBallFrom(hash)
+    }
+
+    /// Whether an ID refers to a result drawn from this seed
+    pub fn matches(&self, id: &str) -> bool {
+        id.split('-').next() == Some(&self.commitment()[..ID_PREFIX_LEN])
+    }
+
+    /// Find a result from its ID so that it can be reproduced. Only possible
+    /// once the seed is revealed.
+    pub fn replay(&self, id: &str) -> Result<Replay, &'static str> {
+        if !self.revealed {
+            return Err("the seed for that result hasn't been revealed yet")
+        }
+        let n = id.split('-').nth(1)
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or("invalid result ID")?;
+        let draw = self.draws.iter()
+            .find(|(i,_)| *i == n)
+            .map(|(_,d)| d)
+            .ok_or("that result is too old or doesn't exist")?;
+        let answer_sets = match draw {
+            Draw::EightBallFrom(hash) => self.answer_sets.get_key_value(hash).into_iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            _ => HashMap::new()
+        };
+        Ok(Replay { draw: draw.clone(), rng: self.rng(n), answer_sets })
+    }
+}
+
+/// A recorded draw with everything needed to reproduce it, which can be
+/// moved to the worker pool since rolls can take a while
+pub struct Replay {
+    draw: Draw,
+    rng: ChaCha20Rng,
+    answer_sets: HashMap<String, Vec<Answer>>,
+}
+
+impl Replay {
+    pub fn run(mut self, cancel: &Cancel) -> String {
+        self.draw.replay(&mut self.rng, &self.answer_sets, cancel)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_matches_draw() {
+        let mut seed = FairSeed::new();
+        let (id, mut rng) = seed.draw(Draw::Flip(20));
+        let (heads, tails) = utils::flip_coins(20, &mut rng);
+        assert!(seed.matches(&id));
+        assert!(seed.replay(&id).is_err());
+        seed.revealed = true;
+        assert_eq!(seed.replay(&id).unwrap().run(&Cancel::default()), format!("`flip 20`: {} heads and {} tails", heads, tails));
+    }
+
+    #[test]
+    fn draws_are_independent() {
+        let mut seed = FairSeed::new();
+        let (first, _) = seed.draw(Draw::Rand(1, 100));
+        let (second, _) = seed.draw(Draw::Rand(1, 100));
+        assert_ne!(first, second);
+        assert_eq!(seed.count(), 2);
+        assert!(!FairSeed::new().matches(&first));
+    }
+
+    #[test]
+    fn eight_ball_replay() {
+        let mut seed = FairSeed::new();
+        let answers = vec![Answer::new("Certainly", eightball::Category::Positive, 1).unwrap()];
+        let draw = seed.eight_ball(&answers);
+        let (id, _) = seed.draw(draw);
+        seed.revealed = true;
+        assert_eq!(seed.replay(&id).unwrap().run(&Cancel::default()), "`8ball`: Certainly");
+    }
+
+    #[test]
+    fn old_draws_load() {
+        let draw = serde_json::from_str::<Draw>(r#""EightBall""#).unwrap();
+        assert!(matches!(draw, Draw::EightBall));
+    }
+
+    #[test]
+    fn long_lists_are_not_stored() {
+        let short = vec![String::from("a"), String::from("b")];
+        assert!(matches!(Draw::choose(&short), Draw::Choose(_)));
+        let long = vec!["x".repeat(150), "y".repeat(150)];
+        assert!(matches!(Draw::shuffle(&long), Draw::ShuffleCount(2)));
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use serenity::model::id::MessageId;
+
+const MAX_COMBATANTS: usize = 50;
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Combatant {
+    pub name: String,
+    pub initiative: i64,
+}
+
+/// Turn order for a channel. Combatants are kept sorted by initiative, with
+/// ties going in the order they were added.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Initiative {
+    pub combatants: Vec<Combatant>,
+    /// Index of the combatant whose turn it is
+    pub turn: usize,
+    /// The current round, or 0 if combat hasn't started
+    pub round: u32,
+    /// The message showing the turn order, which is edited on every change
+    pub status: Option<MessageId>,
+}
+
+impl Initiative {
+    pub fn started(&self) -> bool {
+        self.round > 0
+    }
+
+    pub fn current(&self) -> Option<&Combatant> {
+        match self.started() {
+            true => self.combatants.get(self.turn),
+            false => None
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.combatants.iter().position(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The position a combatant with `initiative` would be inserted at
+    fn slot(&self, initiative: i64) -> usize {
+        self.combatants.iter()
+            .position(|c| c.initiative < initiative)
+            .unwrap_or(self.combatants.len())
+    }
+
+    pub fn add(&mut self, name: &str, initiative: i64) -> Result<(), &'static str> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err("names must be between 1 and 32 characters")
+        }
+        if self.find(name).is_some() {
+            return Err("there is already a combatant with that name")
+        }
+        if self.combatants.len() >= MAX_COMBATANTS {
+            return Err("too many combatants")
+        }
+        let pos = self.slot(initiative);
+        if self.started() && pos <= self.turn {
+            self.turn += 1;
+        }
+        self.combatants.insert(pos, Combatant { name: name.to_owned(), initiative });
+        Ok(())
+    }
+
+    /// Remove a combatant. If it was their turn, it becomes the next combatant's turn.
+    pub fn remove(&mut self, name: &str) -> Result<Combatant, &'static str> {
+        let pos = self.find(name).ok_or("no combatant with that name")?;
+        let combatant = self.combatants.remove(pos);
+        if self.combatants.is_empty() {
+            self.turn = 0;
+            self.round = 0;
+        } else if self.started() && pos < self.turn {
+            self.turn -= 1;
+        } else if self.started() && self.turn >= self.combatants.len() {
+            self.turn = 0;
+            self.round += 1;
+        }
+        Ok(combatant)
+    }
+
+    /// Move a combatant later in the turn order, either to a lower initiative
+    /// or just after the combatant after them
+    pub fn delay(&mut self, name: &str, initiative: Option<i64>) -> Result<&Combatant, &'static str> {
+        let pos = self.find(name).ok_or("no combatant with that name")?;
+        if self.started() && pos < self.turn {
+            return Err("that combatant has already had their turn this round")
+        }
+        let initiative = match initiative {
+            Some(i) if i > self.combatants[pos].initiative => return Err("combatants can only delay to a lower initiative"),
+            Some(i) => i,
+            None => self.combatants.get(pos+1).ok_or("that combatant already goes last")?.initiative
+        };
+        // Everyone before the combatant has at least their initiative, so they
+        // can only move later and the current turn index stays the same
+        let mut combatant = self.combatants.remove(pos);
+        combatant.initiative = initiative;
+        let new_pos = self.slot(initiative);
+        self.combatants.insert(new_pos, combatant);
+        Ok(&self.combatants[new_pos])
+    }
+
+    /// Advance to the next turn, starting combat if it hasn't started
+    pub fn next(&mut self) -> Result<&Combatant, &'static str> {
+        if self.combatants.is_empty() {
+            return Err("there are no combatants")
+        }
+        if !self.started() {
+            self.round = 1;
+            self.turn = 0;
+        } else {
+            self.turn += 1;
+            if self.turn >= self.combatants.len() {
+                self.turn = 0;
+                self.round += 1;
+            }
+        }
+        Ok(&self.combatants[self.turn])
+    }
+}
@@ -3,9 +3,24 @@ use std::env;
 use serenity::{
     prelude::*
 };
+mod bits;
+mod calc;
+mod cards;
 mod commands;
+mod counting;
+mod dice;
+mod eightball;
 mod event;
+mod exact;
+mod fair;
+mod initiative;
+mod matrix;
+mod plot;
+mod polls;
+mod sandbox;
 mod state;
+mod units;
+mod users;
 mod utils;
 use crate::event::*;
 use crate::state::*;
@@ -27,6 +42,7 @@ async fn main() {
         data.insert::<State>(state);
         data.insert::<ShardManagerKey>(shmgr1);
     }
+    tokio::task::spawn(run_scheduler(client.data.clone(), client.cache_and_http.http.clone()));
     tokio::task::spawn(async move {
         if let Err(e) = tokio::signal::ctrl_c().await {
             error!("Error setting Ctrl+C handler: {:?}", e);
@@ -42,10 +58,7 @@ async fn main() {
 }
 
 fn init_logger() -> Result<(), fern::InitError> {
-    let level_env_var = match env::args().nth(1) {
-        Some(x) => Some(x.to_lowercase()),
-        None => None
-    };
+    let level_env_var = env::args().nth(1).map(|x| x.to_lowercase());
     let is_default = level_env_var.is_none();
     let level = match level_env_var {
         Some(x) => match &x[..] {
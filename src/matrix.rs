@@ -0,0 +1,671 @@
+use std::collections::BTreeMap;
+use num_complex::Complex64;
+use crate::sandbox::{self, Cancel};
+use crate::utils;
+
+/// Largest number of elements a matrix may have
+const MAX_ELEMENTS: usize = 10000;
+/// Values this much smaller than the largest in a result are shown as zero
+const EPSILON: f64 = 1e-12;
+
+pub type MatrixResult<T> = Result<T, &'static str>;
+
+type Complex = Complex64;
+
+/// A matrix of complex numbers. Numbers are 1x1 matrices and lists are
+/// matrices with a single row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<Complex>,
+}
+
+impl Matrix {
+    fn new(rows: usize, cols: usize, data: Vec<Complex>) -> MatrixResult<Self> {
+        if rows * cols > MAX_ELEMENTS {
+            return Err("matrix is too large")
+        }
+        Ok(Self { rows, cols, data })
+    }
+
+    fn scalar(x: Complex) -> Self {
+        Self { rows: 1, cols: 1, data: vec![x] }
+    }
+
+    fn identity(n: usize) -> MatrixResult<Self> {
+        // Check the size before allocating, since `n` comes from the user in `eye`
+        if n.checked_mul(n).is_none_or(|size| size > MAX_ELEMENTS) {
+            return Err("matrix is too large")
+        }
+        let mut m = Self::new(n, n, vec![Complex::new(0.0, 0.0); n * n])?;
+        for i in 0..n {
+            m.data[i * n + i] = Complex::new(1.0, 0.0);
+        }
+        Ok(m)
+    }
+
+    fn is_scalar(&self) -> bool {
+        self.rows == 1 && self.cols == 1
+    }
+
+    fn is_square(&self) -> bool {
+        self.rows == self.cols
+    }
+
+    fn get(&self, r: usize, c: usize) -> Complex {
+        self.data[r * self.cols + c]
+    }
+
+    fn as_scalar(&self) -> MatrixResult<Complex> {
+        match self.is_scalar() {
+            true => Ok(self.data[0]),
+            false => Err("expected a number, not a matrix")
+        }
+    }
+
+    fn as_real(&self) -> MatrixResult<f64> {
+        let x = self.as_scalar()?;
+        match x.im == 0.0 {
+            true => Ok(x.re),
+            false => Err("expected a real number")
+        }
+    }
+
+    /// The real number this is, if it is one
+    pub fn real(&self) -> Option<f64> {
+        self.as_real().ok()
+    }
+
+    fn map(&self, f: impl Fn(Complex) -> Complex) -> Self {
+        Self { rows: self.rows, cols: self.cols, data: self.data.iter().map(|x| f(*x)).collect() }
+    }
+
+    /// Combine two matrices of the same size element by element, or a matrix and a number
+    fn zip(&self, other: &Self, f: impl Fn(Complex, Complex) -> Complex) -> MatrixResult<Self> {
+        if other.is_scalar() {
+            let y = other.data[0];
+            Ok(self.map(|x| f(x, y)))
+        } else if self.is_scalar() {
+            let x = self.data[0];
+            Ok(other.map(|y| f(x, y)))
+        } else if self.rows == other.rows && self.cols == other.cols {
+            let data = self.data.iter().zip(&other.data).map(|(x, y)| f(*x, *y)).collect();
+            Ok(Self { rows: self.rows, cols: self.cols, data })
+        } else {
+            Err("matrix sizes don't match")
+        }
+    }
+
+    fn transpose(&self) -> Self {
+        let data = (0..self.cols)
+            .flat_map(|c| (0..self.rows).map(move |r| (r, c)))
+            .map(|(r, c)| self.get(r, c))
+            .collect();
+        Self { rows: self.cols, cols: self.rows, data }
+    }
+
+    fn mul(&self, other: &Self) -> MatrixResult<Self> {
+        if self.is_scalar() || other.is_scalar() {
+            return self.zip(other, |x, y| x * y)
+        }
+        if self.cols != other.rows {
+            return Err("matrix sizes don't match for multiplication")
+        }
+        let mut data = Vec::with_capacity(self.rows * other.cols);
+        for r in 0..self.rows {
+            for c in 0..other.cols {
+                data.push((0..self.cols).map(|k| self.get(r, k) * other.get(k, c)).sum());
+            }
+        }
+        Self::new(self.rows, other.cols, data)
+    }
+
+    /// Reduce to row echelon form with partial pivoting, applying the same
+    /// operations to `other`. Returns the determinant.
+    fn eliminate(&mut self, other: &mut Self) -> Complex {
+        let n = self.rows;
+        let mut det = Complex::new(1.0, 0.0);
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|a, b| self.get(*a, col).norm().total_cmp(&self.get(*b, col).norm()))
+                .unwrap();
+            if self.get(pivot, col).norm() == 0.0 {
+                return Complex::new(0.0, 0.0)
+            }
+            if pivot != col {
+                for m in [&mut *self, &mut *other] {
+                    for c in 0..m.cols {
+                        m.data.swap(pivot * m.cols + c, col * m.cols + c);
+                    }
+                }
+                det = -det;
+            }
+            let p = self.get(col, col);
+            det *= p;
+            for m in [&mut *self, &mut *other] {
+                for c in 0..m.cols {
+                    m.data[col * m.cols + c] /= p;
+                }
+            }
+            for r in 0..n {
+                let factor = self.get(r, col);
+                if r == col || factor.norm() == 0.0 {
+                    continue
+                }
+                for m in [&mut *self, &mut *other] {
+                    for c in 0..m.cols {
+                        let x = m.data[col * m.cols + c];
+                        m.data[r * m.cols + c] -= factor * x;
+                    }
+                }
+            }
+        }
+        det
+    }
+
+    fn det(&self) -> MatrixResult<Complex> {
+        if !self.is_square() {
+            return Err("only square matrices have determinants")
+        }
+        let mut other = Self { rows: self.rows, cols: 0, data: Vec::new() };
+        Ok(self.clone().eliminate(&mut other))
+    }
+
+    fn inv(&self) -> MatrixResult<Self> {
+        if !self.is_square() {
+            return Err("only square matrices have inverses")
+        }
+        let mut inverse = Self::identity(self.rows)?;
+        let det = self.clone().eliminate(&mut inverse);
+        if det.norm() == 0.0 {
+            return Err("matrix is singular")
+        }
+        Ok(inverse)
+    }
+
+    fn pow(&self, exp: &Self, cancel: &Cancel) -> MatrixResult<Self> {
+        let e = exp.as_scalar()?;
+        if self.is_scalar() {
+            let x = self.data[0];
+            let result = if e.im == 0.0 && e.re.fract() == 0.0 && e.re.abs() <= i32::MAX as f64 {
+                x.powi(e.re as i32)
+            } else if x.im == 0.0 && e.im == 0.0 && x.re >= 0.0 {
+                Complex::new(x.re.powf(e.re), 0.0)
+            } else {
+                x.powc(e)
+            };
+            return Ok(Self::scalar(result))
+        }
+        if !self.is_square() {
+            return Err("only square matrices can be raised to powers")
+        }
+        let n = exp.as_real().ok().filter(|n| n.fract() == 0.0 && n.abs() <= u32::MAX as f64)
+            .ok_or("matrices can only be raised to whole number powers")?;
+        let mut base = match n < 0.0 {
+            true => self.inv()?,
+            false => self.clone()
+        };
+        let mut n = n.abs() as u64;
+        let mut result = Self::identity(self.rows)?;
+        while n > 0 {
+            if cancel.is_cancelled() {
+                return Err(sandbox::CANCELLED)
+            }
+            if n & 1 == 1 {
+                result = result.mul(&base)?;
+            }
+            base = base.mul(&base)?;
+            n >>= 1;
+        }
+        Ok(result)
+    }
+}
+
+//
+// Parsing and evaluation
+//
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    vars: &'a BTreeMap<String, f64>,
+    cancel: &'a Cancel,
+}
+
+/// Evaluate an expression with complex numbers, matrices and lists.
+/// Variables in `vars` can be used as real numbers.
+pub fn eval(s: &str, vars: &BTreeMap<String, f64>, cancel: &Cancel) -> MatrixResult<Matrix> {
+    let src = s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    if src.is_empty() {
+        return Err("no expression given")
+    }
+    let mut parser = Parser { src: src.as_bytes(), pos: 0, vars, cancel };
+    let value = parser.expr()?;
+    if parser.pos < parser.src.len() {
+        return Err("unexpected character")
+    }
+    Ok(value)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_cancel(&self) -> MatrixResult<()> {
+        match self.cancel.is_cancelled() {
+            true => Err(sandbox::CANCELLED),
+            false => Ok(())
+        }
+    }
+
+    fn expr(&mut self) -> MatrixResult<Matrix> {
+        let mut lhs = self.term()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat(b'+') {
+                lhs = lhs.zip(&self.term()?, |x, y| x + y)?;
+            } else if self.eat(b'-') {
+                lhs = lhs.zip(&self.term()?, |x, y| x - y)?;
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn term(&mut self) -> MatrixResult<Matrix> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.check_cancel()?;
+            if self.eat(b'*') {
+                lhs = lhs.mul(&self.unary()?)?;
+            } else if self.eat(b'/') {
+                let rhs = self.unary()?;
+                lhs = match rhs.is_scalar() {
+                    true if rhs.data[0].norm() == 0.0 => return Err("division by zero"),
+                    true => lhs.zip(&rhs, |x, y| x / y)?,
+                    false => lhs.mul(&rhs.inv()?)?
+                };
+            } else {
+                return Ok(lhs)
+            }
+        }
+    }
+
+    fn unary(&mut self) -> MatrixResult<Matrix> {
+        if self.eat(b'-') {
+            // Subtract from zero rather than negating, so that `sqrt(-1)` isn't
+            // taken just below the branch cut
+            Ok(self.unary()?.map(|x| Complex::new(0.0, 0.0) - x))
+        } else if self.eat(b'+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> MatrixResult<Matrix> {
+        let base = self.atom()?;
+        if self.eat(b'^') {
+            // Right associative, and binds tighter than a unary minus on the left
+            let exp = self.unary()?;
+            return base.pow(&exp, self.cancel)
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> MatrixResult<Matrix> {
+        if self.eat(b'(') {
+            let inner = self.expr()?;
+            if !self.eat(b')') {
+                return Err("unmatched parenthesis")
+            }
+            return Ok(inner)
+        }
+        if self.eat(b'[') {
+            return self.matrix()
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == b'_' => self.name(),
+            None => Err("unexpected end of expression"),
+            _ => Err("unexpected character")
+        }
+    }
+
+    /// Parse the rest of a matrix such as `[1, 2; 3, 4]`
+    fn matrix(&mut self) -> MatrixResult<Matrix> {
+        let mut rows: Vec<Vec<Complex>> = vec![Vec::new()];
+        loop {
+            let value = self.expr()?;
+            rows.last_mut().unwrap().push(value.as_scalar().map_err(|_| "matrix elements must be numbers")?);
+            if self.eat(b']') {
+                break
+            } else if self.eat(b';') {
+                rows.push(Vec::new());
+            } else if !self.eat(b',') {
+                return Err("expected `,`, `;` or `]`")
+            }
+        }
+        let cols = rows[0].len();
+        if rows.iter().any(|r| r.len() != cols) {
+            return Err("matrix rows must be the same length")
+        }
+        Matrix::new(rows.len(), cols, rows.concat())
+    }
+
+    /// Parse a number such as `12`, `0.5`, `1.5e-3` or `2i`
+    fn number(&mut self) -> MatrixResult<Matrix> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == b'.') {
+            self.pos += 1;
+        }
+        let next = self.src.get(self.pos + 1).copied();
+        let after = self.src.get(self.pos + 2).copied();
+        let has_exp = self.peek() == Some(b'e') && match next {
+            Some(b'-') | Some(b'+') => matches!(after, Some(c) if c.is_ascii_digit()),
+            Some(c) => c.is_ascii_digit(),
+            None => false
+        };
+        if has_exp {
+            self.pos += 2;
+            while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+        let x = std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+            .parse::<f64>()
+            .map_err(|_| "invalid number")?;
+        // An `i` straight after a number makes it imaginary, unless it starts a name
+        let imaginary = self.peek() == Some(b'i')
+            && !matches!(self.src.get(self.pos + 1), Some(c) if c.is_ascii_alphanumeric() || *c == b'_');
+        match imaginary {
+            true => {
+                self.pos += 1;
+                Ok(Matrix::scalar(Complex::new(0.0, x)))
+            },
+            false => Ok(Matrix::scalar(Complex::new(x, 0.0)))
+        }
+    }
+
+    fn name(&mut self) -> MatrixResult<Matrix> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        if !self.eat(b'(') {
+            return match name {
+                "i" => Ok(Matrix::scalar(Complex::new(0.0, 1.0))),
+                "pi" => Ok(Matrix::scalar(Complex::new(std::f64::consts::PI, 0.0))),
+                "e" => Ok(Matrix::scalar(Complex::new(std::f64::consts::E, 0.0))),
+                _ => match self.vars.get(name) {
+                    Some(x) => Ok(Matrix::scalar(Complex::new(*x, 0.0))),
+                    None => Err("unknown variable")
+                }
+            }
+        }
+        let mut args = Vec::new();
+        if !self.eat(b')') {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(b')') {
+                    break
+                }
+                if !self.eat(b',') {
+                    return Err("expected `,` or `)`")
+                }
+            }
+        }
+        call(name, &args)
+    }
+}
+
+fn call(name: &str, args: &[Matrix]) -> MatrixResult<Matrix> {
+    if is_statistic(name) {
+        return statistic(name, args)
+    }
+    let arity = match name {
+        "dot" | "cross" => 2,
+        _ => 1
+    };
+    if args.len() != arity {
+        return Err("wrong number of arguments")
+    }
+    let x = &args[0];
+    let scalar = |c: Complex| Ok(Matrix::scalar(c));
+    let real = |x: f64| Ok(Matrix::scalar(Complex::new(x, 0.0)));
+    match name {
+        "re" | "real" => Ok(x.map(|c| Complex::new(c.re, 0.0))),
+        "im" | "imag" => Ok(x.map(|c| Complex::new(c.im, 0.0))),
+        "abs" => Ok(x.map(|c| Complex::new(c.norm(), 0.0))),
+        "arg" => Ok(x.map(|c| Complex::new(c.arg(), 0.0))),
+        "conj" => Ok(x.map(|c| c.conj())),
+        "sqrt" => Ok(x.map(|c| c.sqrt())),
+        "exp" => Ok(x.map(|c| c.exp())),
+        "ln" => Ok(x.map(|c| c.ln())),
+        "log10" => Ok(x.map(|c| c.log(10.0))),
+        "sin" => Ok(x.map(|c| c.sin())),
+        "cos" => Ok(x.map(|c| c.cos())),
+        "tan" => Ok(x.map(|c| c.tan())),
+        "sinh" => Ok(x.map(|c| c.sinh())),
+        "cosh" => Ok(x.map(|c| c.cosh())),
+        "tanh" => Ok(x.map(|c| c.tanh())),
+        "det" => scalar(x.det()?),
+        "inv" => x.inv(),
+        "transpose" => Ok(x.transpose()),
+        "trace" => match x.is_square() {
+            true => scalar((0..x.rows).map(|i| x.get(i, i)).sum()),
+            false => Err("only square matrices have traces")
+        },
+        "norm" => real(x.data.iter().map(|c| c.norm_sqr()).sum::<f64>().sqrt()),
+        "eye" | "identity" => {
+            let n = x.as_real().ok().filter(|n| n.fract() == 0.0 && *n >= 1.0)
+                .ok_or("the size of an identity matrix must be a positive whole number")?;
+            Matrix::identity((n as usize).min(MAX_ELEMENTS + 1))
+        },
+        "dot" => {
+            let (a, b) = (&args[0], &args[1]);
+            if a.data.len() != b.data.len() {
+                return Err("vectors must be the same length")
+            }
+            scalar(a.data.iter().zip(&b.data).map(|(x, y)| x * y).sum())
+        },
+        "cross" => {
+            let (a, b) = (&args[0].data, &args[1].data);
+            if a.len() != 3 || b.len() != 3 {
+                return Err("cross products need vectors of length 3")
+            }
+            let data = vec![
+                a[1] * b[2] - a[2] * b[1],
+                a[2] * b[0] - a[0] * b[2],
+                a[0] * b[1] - a[1] * b[0],
+            ];
+            Matrix::new(1, 3, data)
+        },
+        _ => Err("unknown function")
+    }
+}
+
+fn is_statistic(name: &str) -> bool {
+    matches!(name, "sum" | "mean" | "median" | "var" | "stdev" | "min" | "max" | "percentile")
+}
+
+/// Calculate a statistic over every element of the arguments. For
+/// `percentile`, the last argument is the percentile.
+fn statistic(name: &str, args: &[Matrix]) -> MatrixResult<Matrix> {
+    let (args, percent) = match name {
+        "percentile" => match args.split_last() {
+            Some((p, rest)) => (rest, Some(p.as_real()?)),
+            None => return Err("wrong number of arguments")
+        },
+        _ => (args, None)
+    };
+    let mut values = args.iter()
+        .flat_map(|m| m.data.iter())
+        .map(|c| match c.im == 0.0 {
+            true => Ok(c.re),
+            false => Err("statistics need real numbers")
+        })
+        .collect::<MatrixResult<Vec<f64>>>()?;
+    if values.is_empty() {
+        return Err("no values given")
+    }
+    if values.iter().any(|x| x.is_nan()) {
+        return Err("statistics need real numbers")
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = || match values.len() {
+        1 => Err("variance needs at least two values"),
+        _ => Ok(values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0))
+    };
+    // Linear interpolation between the closest ranks
+    let quantile = |q: f64| {
+        let rank = q * (n - 1.0);
+        let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+        values[lo] + (values[hi] - values[lo]) * (rank - lo as f64)
+    };
+    let result = match name {
+        "sum" => values.iter().sum(),
+        "mean" => mean,
+        "median" => quantile(0.5),
+        "var" => variance()?,
+        "stdev" => variance()?.sqrt(),
+        "min" => values[0],
+        "max" => values[values.len() - 1],
+        "percentile" => match percent {
+            Some(p) if (0.0..=100.0).contains(&p) => quantile(p / 100.0),
+            _ => return Err("percentiles must be between 0 and 100")
+        },
+        _ => unreachable!()
+    };
+    Ok(Matrix::scalar(Complex::new(result, 0.0)))
+}
+
+//
+// Formatting
+//
+
+fn format_complex(c: Complex, figures: usize) -> String {
+    let num = |x: f64| utils::format_sig(x, figures);
+    let imag = |x: f64| if x == 1.0 {
+        String::from("i")
+    } else if x == -1.0 {
+        String::from("-i")
+    } else {
+        format!("{}i", num(x))
+    };
+    if c.im == 0.0 {
+        num(c.re)
+    } else if c.re == 0.0 {
+        imag(c.im)
+    } else if c.im < 0.0 {
+        format!("{}-{}", num(c.re), imag(-c.im))
+    } else {
+        format!("{}+{}", num(c.re), imag(c.im))
+    }
+}
+
+/// Round parts that are tiny compared to the largest value to zero, to hide
+/// rounding errors such as in `inv` or `exp(pi*i)`
+fn clean(m: &Matrix) -> Matrix {
+    let largest = m.data.iter()
+        .flat_map(|c| [c.re.abs(), c.im.abs()])
+        .filter(|x| x.is_finite())
+        .fold(0.0, f64::max);
+    let chop = |x: f64| match x.abs() < largest * EPSILON {
+        true => 0.0,
+        false => x
+    };
+    m.map(|c| Complex::new(chop(c.re), chop(c.im)))
+}
+
+/// Format a result, using fewer significant figures for larger matrices so
+/// that they fit in `max_len` characters. Matrices are put in code blocks.
+pub fn format(m: &Matrix, max_len: usize) -> Option<String> {
+    let m = clean(m);
+    if m.is_scalar() {
+        return Some(format!("`{}`", format_complex(m.data[0], 10)))
+    }
+    (3..=6).rev().find_map(|figures| {
+        let cells = m.data.iter().map(|c| format_complex(*c, figures)).collect::<Vec<String>>();
+        let widths = (0..m.cols)
+            .map(|c| (0..m.rows).map(|r| cells[r * m.cols + c].len()).max().unwrap())
+            .collect::<Vec<usize>>();
+        let lines = (0..m.rows)
+            .map(|r| {
+                let row = (0..m.cols)
+                    .map(|c| format!("{:>width$}", cells[r * m.cols + c], width = widths[c]))
+                    .collect::<Vec<String>>();
+                format!("[ {} ]", row.join("  "))
+            })
+            .collect::<Vec<String>>();
+        let text = format!("```\n{}\n```", lines.join("\n"));
+        Some(text).filter(|t| t.len() <= max_len)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(s: &str) -> MatrixResult<Matrix> {
+        eval(s, &BTreeMap::new(), &Cancel::default())
+    }
+
+    fn close(a: Complex, b: Complex) -> bool {
+        (a - b).norm() < 1e-9
+    }
+
+    #[test]
+    fn complex_numbers() {
+        let m = eval_str("(1+2i)*(3-i)").unwrap();
+        assert!(close(m.data[0], Complex::new(5.0, 5.0)));
+        let m = eval_str("sqrt(-1)").unwrap();
+        assert!(close(m.data[0], Complex::new(0.0, 1.0)));
+    }
+
+    #[test]
+    fn matrices() {
+        assert!(close(eval_str("det([1, 2; 3, 4])").unwrap().data[0], Complex::new(-2.0, 0.0)));
+        let product = eval_str("inv([1, 2; 3, 4]) * [1, 2; 3, 4]").unwrap();
+        let identity = Matrix::identity(2).unwrap();
+        assert!(product.data.iter().zip(&identity.data).all(|(a, b)| close(*a, *b)));
+        assert_eq!(eval_str("transpose([1, 2, 3])").unwrap().rows, 3);
+        assert!(close(eval_str("[1, 1; 1, 0]^10").unwrap().data[1], Complex::new(55.0, 0.0)));
+    }
+
+    #[test]
+    fn statistics() {
+        assert_eq!(eval_str("mean([1, 2, 3, 4])").unwrap().real(), Some(2.5));
+        assert_eq!(eval_str("median([3, 1, 2])").unwrap().real(), Some(2.0));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(eval_str("inv([1, 2; 2, 4])").is_err());
+        assert!(eval_str("[1, 2] * [3, 4]").is_err());
+        assert!(eval_str("[1, 2; 3]").is_err());
+        assert_eq!(eval_str("eye(100000)").unwrap_err(), "matrix is too large");
+    }
+
+    #[test]
+    fn nan_pivots() {
+        let vars = vec![(String::from("n"), f64::NAN)].into_iter().collect();
+        assert!(eval("det([n, 1; 1, 1])", &vars, &Cancel::default()).is_ok());
+    }
+}
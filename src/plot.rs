@@ -0,0 +1,299 @@
+use meval::Expr;
+use crate::sandbox::{self, Cancel};
+use crate::utils;
+
+const WIDTH: usize = 800;
+const HEIGHT: usize = 500;
+const LEFT: usize = 80;
+const RIGHT: usize = WIDTH - 20;
+const TOP: usize = 20;
+const BOTTOM: usize = HEIGHT - 40;
+
+/// Number of points sampled for each expression
+const SAMPLES: usize = 2 * (RIGHT - LEFT);
+/// Longest expression shown in the legend
+const MAX_LEGEND_LEN: usize = 40;
+const DEFAULT_RANGE: (f64, f64) = (-10.0, 10.0);
+
+type Color = [u8; 3];
+
+const BACKGROUND: Color = [255, 255, 255];
+const GRID: Color = [225, 225, 225];
+const AXES: Color = [120, 120, 120];
+const TEXT: Color = [40, 40, 40];
+const LINE_COLORS: &[Color] = &[
+    [31, 119, 180], [214, 39, 40], [44, 160, 44], [255, 127, 14], [148, 103, 189], [140, 86, 75],
+];
+
+/// 5x7 font for ASCII 32 to 126. Each byte is a column, with the top row in the lowest bit.
+const FONT: [[u8; 5]; 95] = [
+    [0x00,0x00,0x00,0x00,0x00], [0x00,0x00,0x5f,0x00,0x00], [0x00,0x07,0x00,0x07,0x00], [0x14,0x7f,0x14,0x7f,0x14],
+    [0x24,0x2a,0x7f,0x2a,0x12], [0x23,0x13,0x08,0x64,0x62], [0x36,0x49,0x55,0x22,0x50], [0x00,0x05,0x03,0x00,0x00],
+    [0x00,0x1c,0x22,0x41,0x00], [0x00,0x41,0x22,0x1c,0x00], [0x08,0x2a,0x1c,0x2a,0x08], [0x08,0x08,0x3e,0x08,0x08],
+    [0x00,0x50,0x30,0x00,0x00], [0x08,0x08,0x08,0x08,0x08], [0x00,0x60,0x60,0x00,0x00], [0x20,0x10,0x08,0x04,0x02],
+    [0x3e,0x51,0x49,0x45,0x3e], [0x00,0x42,0x7f,0x40,0x00], [0x42,0x61,0x51,0x49,0x46], [0x21,0x41,0x45,0x4b,0x31],
+    [0x18,0x14,0x12,0x7f,0x10], [0x27,0x45,0x45,0x45,0x39], [0x3c,0x4a,0x49,0x49,0x30], [0x01,0x71,0x09,0x05,0x03],
+    [0x36,0x49,0x49,0x49,0x36], [0x06,0x49,0x49,0x29,0x1e], [0x00,0x36,0x36,0x00,0x00], [0x00,0x56,0x36,0x00,0x00],
+    [0x08,0x14,0x22,0x41,0x00], [0x14,0x14,0x14,0x14,0x14], [0x00,0x41,0x22,0x14,0x08], [0x02,0x01,0x51,0x09,0x06],
+    [0x32,0x49,0x79,0x41,0x3e], [0x7e,0x11,0x11,0x11,0x7e], [0x7f,0x49,0x49,0x49,0x36], [0x3e,0x41,0x41,0x41,0x22],
+    [0x7f,0x41,0x41,0x22,0x1c], [0x7f,0x49,0x49,0x49,0x41], [0x7f,0x09,0x09,0x09,0x01], [0x3e,0x41,0x49,0x49,0x7a],
+    [0x7f,0x08,0x08,0x08,0x7f], [0x00,0x41,0x7f,0x41,0x00], [0x20,0x40,0x41,0x3f,0x01], [0x7f,0x08,0x14,0x22,0x41],
+    [0x7f,0x40,0x40,0x40,0x40], [0x7f,0x02,0x0c,0x02,0x7f], [0x7f,0x04,0x08,0x10,0x7f], [0x3e,0x41,0x41,0x41,0x3e],
+    [0x7f,0x09,0x09,0x09,0x06], [0x3e,0x41,0x51,0x21,0x5e], [0x7f,0x09,0x19,0x29,0x46], [0x46,0x49,0x49,0x49,0x31],
+    [0x01,0x01,0x7f,0x01,0x01], [0x3f,0x40,0x40,0x40,0x3f], [0x1f,0x20,0x40,0x20,0x1f], [0x3f,0x40,0x38,0x40,0x3f],
+    [0x63,0x14,0x08,0x14,0x63], [0x07,0x08,0x70,0x08,0x07], [0x61,0x51,0x49,0x45,0x43], [0x00,0x7f,0x41,0x41,0x00],
+    [0x02,0x04,0x08,0x10,0x20], [0x00,0x41,0x41,0x7f,0x00], [0x04,0x02,0x01,0x02,0x04], [0x40,0x40,0x40,0x40,0x40],
+    [0x00,0x01,0x02,0x04,0x00], [0x20,0x54,0x54,0x54,0x78], [0x7f,0x48,0x44,0x44,0x38], [0x38,0x44,0x44,0x44,0x20],
+    [0x38,0x44,0x44,0x48,0x7f], [0x38,0x54,0x54,0x54,0x18], [0x08,0x7e,0x09,0x01,0x02], [0x0c,0x52,0x52,0x52,0x3e],
+    [0x7f,0x08,0x04,0x04,0x78], [0x00,0x44,0x7d,0x40,0x00], [0x20,0x40,0x44,0x3d,0x00], [0x7f,0x10,0x28,0x44,0x00],
+    [0x00,0x41,0x7f,0x40,0x00], [0x7c,0x04,0x18,0x04,0x78], [0x7c,0x08,0x04,0x04,0x78], [0x38,0x44,0x44,0x44,0x38],
+    [0x7c,0x14,0x14,0x14,0x08], [0x08,0x14,0x14,0x18,0x7c], [0x7c,0x08,0x04,0x04,0x08], [0x48,0x54,0x54,0x54,0x20],
+    [0x04,0x3f,0x44,0x40,0x20], [0x3c,0x40,0x40,0x20,0x7c], [0x1c,0x20,0x40,0x20,0x1c], [0x3c,0x40,0x30,0x40,0x3c],
+    [0x44,0x28,0x10,0x28,0x44], [0x0c,0x50,0x50,0x50,0x3c], [0x44,0x64,0x54,0x4c,0x44], [0x00,0x08,0x36,0x41,0x00],
+    [0x00,0x00,0x7f,0x00,0x00], [0x00,0x41,0x36,0x08,0x00], [0x08,0x04,0x08,0x10,0x08],
+];
+const FONT_SCALE: usize = 2;
+const CHAR_WIDTH: usize = 6 * FONT_SCALE;
+const CHAR_HEIGHT: usize = 7 * FONT_SCALE;
+
+/// An RGB image to draw on
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self { pixels: BACKGROUND.repeat(WIDTH * HEIGHT) }
+    }
+
+    fn set(&mut self, x: i64, y: i64, color: Color) {
+        if x >= 0 && y >= 0 && (x as usize) < WIDTH && (y as usize) < HEIGHT {
+            let i = 3 * (y as usize * WIDTH + x as usize);
+            self.pixels[i..i+3].copy_from_slice(&color);
+        }
+    }
+
+    fn fill(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: Color) {
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                self.set(x as i64, y as i64, color);
+            }
+        }
+    }
+
+    /// Draw a line two pixels wide, clipped to the plot area. The endpoints
+    /// must already be close to the plot area.
+    fn line(&mut self, (mut x0, mut y0): (i64, i64), (x1, y1): (i64, i64), color: Color) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let mut err = dx + dy;
+        loop {
+            for (ox, oy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (x, y) = (x0 + ox, y0 + oy);
+                if x >= LEFT as i64 && x <= RIGHT as i64 && y >= TOP as i64 && y <= BOTTOM as i64 {
+                    self.set(x, y, color);
+                }
+            }
+            if x0 == x1 && y0 == y1 {
+                break
+            }
+            if 2 * err >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if 2 * err <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    fn text(&mut self, x: usize, y: usize, text: &str, color: Color) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match c {
+                ' '..='~' => FONT[c as usize - 32],
+                _ => FONT['?' as usize - 32]
+            };
+            for (col, bits) in glyph.iter().enumerate() {
+                for row in 0..7 {
+                    if bits >> row & 1 == 1 {
+                        let (px, py) = (x + i * CHAR_WIDTH + col * FONT_SCALE, y + row * FONT_SCALE);
+                        self.fill(px, py, px + FONT_SCALE - 1, py + FONT_SCALE - 1, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()
+            .and_then(|mut w| w.write_image_data(&self.pixels))
+            .map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+}
+
+/// Parse `<expr>; <expr>... [from <a> to <b>]` into the expressions and the range
+pub fn parse_args(s: &str) -> Result<(Vec<String>, f64, f64), String> {
+    let (exprs, range) = match s.rfind(" from ") {
+        Some(i) => (&s[..i], Some(&s[i+6..])),
+        None => (s, None)
+    };
+    let exprs = exprs.split(';')
+        .map(|e| e.trim().to_owned())
+        .filter(|e| !e.is_empty())
+        .collect::<Vec<String>>();
+    if exprs.is_empty() {
+        return Err(String::from("nothing to plot"))
+    }
+    if exprs.len() > LINE_COLORS.len() {
+        return Err(format!("at most {} expressions can be plotted at once", LINE_COLORS.len()))
+    }
+    let (lo, hi) = match range {
+        None => DEFAULT_RANGE,
+        Some(range) => {
+            let i = range.find(" to ").ok_or("use `from <a> to <b>` to set the range")?;
+            let bound = |s: &str| meval::eval_str(s).map_err(|e| format!("invalid bound `{}`: {}", s.trim(), e));
+            (bound(&range[..i])?, bound(&range[i+4..])?)
+        }
+    };
+    if !(lo.is_finite() && hi.is_finite() && lo < hi) {
+        return Err(String::from("the range must be finite, with the start before the end"))
+    }
+    Ok((exprs, lo, hi))
+}
+
+/// Pick a step of 1, 2 or 5 times a power of 10 giving about `n` ticks
+fn tick_step(span: f64, n: f64) -> f64 {
+    let raw = span / n;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|m| m * magnitude)
+        .find(|s| *s >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+fn ticks(lo: f64, hi: f64, n: f64) -> Vec<f64> {
+    let step = tick_step(hi - lo, n);
+    let first = (lo / step).ceil() as i64;
+    let last = (hi / step).floor() as i64;
+    // Multiply rather than adding up steps so that errors don't build up
+    (first..=last).map(|k| k as f64 * step).collect()
+}
+
+/// Pick a vertical range showing most of the points, leaving out values
+/// that shoot off towards asymptotes
+fn y_range(values: &[f64]) -> (f64, f64) {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let quantile = |q: f64| sorted[((sorted.len() - 1) as f64 * q) as usize];
+    let (q1, q2) = (quantile(0.05), quantile(0.95));
+    let spread = q2 - q1;
+    let lo = sorted[0].max(q1 - spread);
+    let hi = sorted[sorted.len() - 1].min(q2 + spread);
+    if hi - lo <= hi.abs().max(lo.abs()) * 1e-9 {
+        let pad = (lo.abs() * 0.1).max(1.0);
+        return (lo - pad, hi + pad)
+    }
+    let pad = (hi - lo) * 0.05;
+    (lo - pad, hi + pad)
+}
+
+/// Sample each expression in `x` between `lo` and `hi`
+fn sample(exprs: &[String], lo: f64, hi: f64, cancel: &Cancel) -> Result<Vec<Vec<f64>>, String> {
+    let mut result = Vec::new();
+    for expr in exprs {
+        let f = expr.parse::<Expr>()
+            .and_then(|e| e.bind("x"))
+            .map_err(|e| format!("error in `{}`: {}", expr, e))?;
+        let mut ys = Vec::with_capacity(SAMPLES + 1);
+        for i in 0..=SAMPLES {
+            if cancel.is_cancelled() {
+                return Err(String::from(sandbox::CANCELLED))
+            }
+            ys.push(f(lo + (hi - lo) * i as f64 / SAMPLES as f64));
+        }
+        result.push(ys);
+    }
+    Ok(result)
+}
+
+/// Plot expressions in `x` between `lo` and `hi`, returning a PNG image
+pub fn plot(exprs: &[String], lo: f64, hi: f64, cancel: &Cancel) -> Result<Vec<u8>, String> {
+    let samples = sample(exprs, lo, hi, cancel)?;
+    let finite = samples.iter().flatten().copied().filter(|y| y.is_finite()).collect::<Vec<f64>>();
+    if finite.is_empty() {
+        return Err(String::from("no finite values in that range"))
+    }
+    let (ylo, yhi) = y_range(&finite);
+    let px = |x: f64| LEFT as f64 + (x - lo) / (hi - lo) * (RIGHT - LEFT) as f64;
+    let py = |y: f64| BOTTOM as f64 - (y - ylo) / (yhi - ylo) * (BOTTOM - TOP) as f64;
+
+    let mut canvas = Canvas::new();
+    // Grid and tick labels
+    for x in ticks(lo, hi, 8.0) {
+        let p = px(x).round() as usize;
+        canvas.fill(p, TOP, p, BOTTOM, GRID);
+        let label = utils::format_number(x);
+        let width = label.len() * CHAR_WIDTH;
+        canvas.text(p.saturating_sub(width / 2), BOTTOM + 8, &label, TEXT);
+    }
+    for y in ticks(ylo, yhi, 6.0) {
+        let p = py(y).round() as usize;
+        canvas.fill(LEFT, p, RIGHT, p, GRID);
+        let label = utils::format_number(y);
+        let width = label.len() * CHAR_WIDTH;
+        canvas.text(LEFT.saturating_sub(width + 6), p.saturating_sub(CHAR_HEIGHT / 2), &label, TEXT);
+    }
+    // Axes through the origin, if it is in view
+    if lo <= 0.0 && 0.0 <= hi {
+        let p = px(0.0).round() as usize;
+        canvas.fill(p, TOP, p, BOTTOM, AXES);
+    }
+    if ylo <= 0.0 && 0.0 <= yhi {
+        let p = py(0.0).round() as usize;
+        canvas.fill(LEFT, p, RIGHT, p, AXES);
+    }
+    canvas.fill(LEFT, TOP, RIGHT, TOP, AXES);
+    canvas.fill(LEFT, BOTTOM, RIGHT, BOTTOM, AXES);
+    canvas.fill(LEFT, TOP, LEFT, BOTTOM, AXES);
+    canvas.fill(RIGHT, TOP, RIGHT, BOTTOM, AXES);
+
+    // Lines, broken at non-finite values and at jumps bigger than the whole
+    // plot, which are almost always discontinuities rather than steep slopes
+    let clamp = |y: f64| py(y).clamp(TOP as f64 - 2.0, BOTTOM as f64 + 2.0).round() as i64;
+    for (ys, color) in samples.iter().zip(LINE_COLORS) {
+        for (i, pair) in ys.windows(2).enumerate() {
+            let (y0, y1) = (pair[0], pair[1]);
+            if !y0.is_finite() || !y1.is_finite() || (y1 - y0).abs() > yhi - ylo {
+                continue
+            }
+            let x0 = px(lo + (hi - lo) * i as f64 / SAMPLES as f64).round() as i64;
+            let x1 = px(lo + (hi - lo) * (i + 1) as f64 / SAMPLES as f64).round() as i64;
+            canvas.line((x0, clamp(y0)), (x1, clamp(y1)), *color);
+        }
+    }
+
+    // Legend in the top left corner
+    let labels = exprs.iter()
+        .map(|e| match e.chars().count() > MAX_LEGEND_LEN {
+            true => format!("{}...", e.chars().take(MAX_LEGEND_LEN - 3).collect::<String>()),
+            false => e.clone()
+        })
+        .collect::<Vec<String>>();
+    let longest = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let (x, y) = (LEFT + 10, TOP + 10);
+    let line_height = CHAR_HEIGHT + 6;
+    canvas.fill(x, y, x + 40 + longest * CHAR_WIDTH, y + 6 + labels.len() * line_height, BACKGROUND);
+    for (i, (label, color)) in labels.iter().zip(LINE_COLORS).enumerate() {
+        let row = y + 6 + i * line_height;
+        canvas.fill(x + 6, row + CHAR_HEIGHT / 2 - 1, x + 26, row + CHAR_HEIGHT / 2, *color);
+        canvas.text(x + 34, row, label, TEXT);
+    }
+
+    canvas.encode()
+}
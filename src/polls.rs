@@ -0,0 +1,552 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use serde::{Serialize, Deserialize};
+use serenity::model::prelude::*;
+use crate::utils;
+
+/// The width of the bars in poll results, in characters
+const BAR_WIDTH: usize = 16;
+/// Messages can only have 20 different reactions
+pub const MAX_OPTIONS: usize = 20;
+/// Longest a poll can stay open, in milliseconds
+const MAX_DURATION: u64 = 90*24*60*60*1000;
+
+/// How users vote in a poll
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PollMode {
+    /// Users select one option
+    #[default]
+    Single,
+    /// Users select up to this many options
+    Multi(usize),
+    /// Users select any number of options
+    Approval,
+    /// Users rank options by the order they react, and the winner is found
+    /// by instant-runoff
+    Ranked,
+}
+
+impl PollMode {
+    /// How to vote in a poll with this mode
+    pub fn instructions(&self) -> String {
+        match self {
+            PollMode::Single => String::from("Select one option"),
+            PollMode::Multi(n) => format!("Select up to {} options", n),
+            PollMode::Approval => String::from("Select every option you approve of"),
+            PollMode::Ranked => String::from("React in order of preference, starting with your favourite"),
+        }
+    }
+}
+
+/// Settings given before a poll's question
+#[derive(Default)]
+pub struct PollSettings {
+    /// How long until the poll closes, in milliseconds
+    pub duration: Option<u64>,
+    pub mode: PollMode,
+    pub anonymous: bool,
+}
+
+/// Parse the settings at the start of a poll, such as `2h ranked |`,
+/// returning them and the rest of the poll. Settings must be followed by a
+/// `|` before the first `;`, so that questions starting with words like
+/// `approval` aren't mistaken for settings. If anything before the `|` isn't
+/// a setting, the whole poll is taken to have no settings.
+pub fn parse_settings(s: &str) -> Result<(PollSettings, &str), &'static str> {
+    let s = s.trim();
+    let end = s.find(';').unwrap_or(s.len());
+    let (head, rest) = match s[..end].find('|') {
+        Some(idx) => (&s[..idx], s[idx+1..].trim_start()),
+        None => return Ok((PollSettings::default(), s))
+    };
+    let mut settings = PollSettings::default();
+    let mut words = head.split_whitespace();
+    while let Some(word) = words.next() {
+        let has_mode = settings.mode != PollMode::Single;
+        match word {
+            "multi" | "approval" | "ranked" if has_mode => return Err("Only one poll mode can be given"),
+            "multi" => match words.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n >= 2 => settings.mode = PollMode::Multi(n),
+                _ => return Err("`multi` must be followed by the number of options users may select, at least 2")
+            },
+            "approval" => settings.mode = PollMode::Approval,
+            "anonymous" if settings.anonymous => return Err("`anonymous` can only be given once"),
+            "anonymous" => settings.anonymous = true,
+            "ranked" => settings.mode = PollMode::Ranked,
+            _ => match utils::parse_duration(word) {
+                Some(_) if settings.duration.is_some() => return Err("Only one duration can be given"),
+                Some(d) if d > MAX_DURATION => return Err("Polls can't last longer than 90 days"),
+                Some(d) => settings.duration = Some(d),
+                None => return Ok((PollSettings::default(), s))
+            }
+        }
+    }
+    Ok((settings, rest))
+}
+
+/// An option in a poll, voted for by reacting with its emoji
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PollOption {
+    pub emoji: ReactionType,
+    pub text: String,
+}
+
+impl PollOption {
+    /// Parse an option, which may start with its own emoji unless `plain` is
+    /// set. Otherwise it uses `default`.
+    fn parse(s: &str, default: ReactionType, plain: bool) -> Result<Self, String> {
+        let s = s.trim();
+        if plain {
+            return Ok(Self { emoji: default, text: s.to_owned() })
+        }
+        let (first, rest) = match s.find(char::is_whitespace) {
+            Some(idx) => (&s[..idx], s[idx..].trim_start()),
+            None => (s, "")
+        };
+        let is_shortcode = first.len() > 2 && first.starts_with(':') && first.ends_with(':')
+            && first[1..first.len()-1].chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if is_shortcode {
+            // Discord replaces the names of emoji the user can use, so this isn't one
+            return Err(format!("Unknown emoji `{}`", first))
+        }
+        let emoji = match ReactionType::try_from(first) {
+            Ok(emoji @ ReactionType::Custom { .. }) => Some(emoji),
+            _ if is_unicode_emoji(first) => Some(ReactionType::Unicode(first.to_owned())),
+            _ => None
+        };
+        match emoji {
+            Some(emoji) => Ok(Self { emoji, text: rest.to_owned() }),
+            None if s.is_empty() => Err(String::from("Options can't be empty")),
+            None => Ok(Self { emoji: default, text: s.to_owned() })
+        }
+    }
+
+    fn label(&self) -> String {
+        match self.text.as_str() {
+            "" => self.emoji.to_string(),
+            text => format!("{} {}", self.emoji, text)
+        }
+    }
+}
+
+/// The emoji for an option without its own emoji, out of `count` options.
+/// Options are numbered, or lettered if there are more than 9.
+pub fn default_emoji(i: usize, count: usize) -> ReactionType {
+    let emoji = match count < utils::NUM_EMOJIS.len() {
+        true => utils::NUM_EMOJIS[i+1].to_owned(),
+        false => std::char::from_u32(0x1F1E6 + i as u32).unwrap().to_string()
+    };
+    ReactionType::Unicode(emoji)
+}
+
+/// Parse the options of a poll. Options without their own emoji, and those
+/// at the positions in `plain`, use the default emoji.
+pub fn parse_options(parts: &[&str], plain: &[usize]) -> Result<Vec<PollOption>, String> {
+    let options = parts.iter()
+        .enumerate()
+        .map(|(i, part)| PollOption::parse(part, default_emoji(i, parts.len()), plain.contains(&i)))
+        .collect::<Result<Vec<PollOption>, String>>()?;
+    for (i, option) in options.iter().enumerate() {
+        if options[..i].iter().any(|o| same_emoji(&o.emoji, &option.emoji)) {
+            return Err(format!("{} is used for more than one option", option.emoji))
+        }
+    }
+    Ok(options)
+}
+
+/// Whether a word is made of emoji, such as `🍕` or `👍🏽`. This can't tell
+/// emoji apart from other symbols, so emoji found this way should be checked
+/// with Discord before they're used.
+fn is_unicode_emoji(s: &str) -> bool {
+    let keycap = s.ends_with('\u{20E3}');
+    !s.is_empty() && (keycap || s.chars().all(|c| !c.is_ascii() && !c.is_alphanumeric()))
+}
+
+/// A poll created by `vote` or `poll`, with everyone's votes
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub channel: ChannelId,
+    #[serde(default)]
+    pub guild: Option<GuildId>,
+    pub creator: UserId,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    #[serde(default)]
+    pub mode: PollMode,
+    /// The options each user voted for, in the order they chose them
+    pub votes: HashMap<UserId, Vec<usize>>,
+    /// When the poll closes, in milliseconds since the epoch
+    #[serde(default)]
+    pub closes_at: Option<u64>,
+    #[serde(default)]
+    pub closed: bool,
+    /// Set when the poll has closed but its results haven't been announced
+    /// yet, so that the scheduler tries again
+    #[serde(default)]
+    pub unannounced: bool,
+    /// Whether reactions are removed once they're counted, so that votes
+    /// are secret
+    #[serde(default)]
+    pub anonymous: bool,
+    /// Set when the bot can't remove reactions because it's missing the
+    /// Manage Messages permission. Old reactions are left in place, and only
+    /// the latest votes count.
+    #[serde(default)]
+    pub missing_permissions: bool,
+    /// Reactions the bot has removed, so that their removal events don't take
+    /// back votes
+    #[serde(skip)]
+    removed: HashSet<(UserId, usize)>,
+}
+
+impl Poll {
+    pub fn new(msg: &Message, question: &str, options: Vec<PollOption>, settings: &PollSettings, now: u64) -> Self {
+        Self {
+            channel: msg.channel_id,
+            guild: msg.guild_id,
+            creator: msg.author.id,
+            question: question.to_owned(),
+            options,
+            mode: settings.mode,
+            votes: HashMap::new(),
+            closes_at: settings.duration.and_then(|d| now.checked_add(d)),
+            closed: false,
+            unannounced: false,
+            anonymous: settings.anonymous,
+            missing_permissions: false,
+            removed: HashSet::new()
+        }
+    }
+
+    /// Notes on how to vote, shown under the options
+    pub fn instructions(&self) -> Vec<String> {
+        let mut notes = Vec::new();
+        if self.mode != PollMode::Single {
+            notes.push(self.mode.instructions());
+        }
+        if self.anonymous {
+            notes.push(String::from("Votes are anonymous, so reactions are removed once they're counted. React again to take back a vote. Results are shown when the poll closes."));
+        }
+        notes
+    }
+
+    /// Whether the poll can be forgotten to make room for others, given when
+    /// it was created. Closed polls can be once their results have been
+    /// announced, and polls without a time limit once they're older than a
+    /// timed poll could be.
+    pub fn is_finished(&self, created: u64, now: u64) -> bool {
+        match self.closes_at {
+            _ if self.closed => !self.unannounced,
+            Some(_) => false,
+            None => now.saturating_sub(created) > MAX_DURATION
+        }
+    }
+
+    /// Whether the poll is still open but should be closed at `now`
+    pub fn is_due(&self, now: u64) -> bool {
+        !self.closed && self.closes_at.is_some_and(|t| t <= now)
+    }
+
+    /// Find the option that a reaction is for
+    pub fn option(&self, emoji: &ReactionType) -> Option<usize> {
+        self.options.iter().position(|o| same_emoji(&o.emoji, emoji))
+    }
+
+    /// Record a vote for an option. If this takes the user over the number of
+    /// options they may select, their earliest choice is dropped and returned.
+    /// In anonymous polls, voting for an option again takes the vote back.
+    pub fn vote(&mut self, user: UserId, option: usize) -> Option<usize> {
+        let choices = self.votes.entry(user).or_default();
+        if let Some(idx) = choices.iter().position(|o| *o == option) {
+            if self.anonymous {
+                choices.remove(idx);
+            }
+            return None
+        }
+        choices.push(option);
+        let limit = match self.mode {
+            PollMode::Single => 1,
+            PollMode::Multi(n) => n,
+            PollMode::Approval | PollMode::Ranked => return None
+        };
+        match choices.len() > limit {
+            true => Some(choices.remove(0)),
+            false => None
+        }
+    }
+
+    /// Note that the bot is removing a user's reaction for an option
+    pub fn removed_reaction(&mut self, user: UserId, option: usize) {
+        self.removed.insert((user, option));
+    }
+
+    /// Note that the bot couldn't remove a reaction after all
+    pub fn removal_failed(&mut self, user: UserId, option: usize) {
+        self.removed.remove(&(user, option));
+    }
+
+    /// Take back a vote when a user removes their reaction. Removals by the
+    /// bot are ignored, as are removals for options the user no longer has
+    /// selected.
+    pub fn unvote(&mut self, user: UserId, option: usize) {
+        if self.removed.remove(&(user, option)) {
+            return
+        } else if self.anonymous && !self.missing_permissions {
+            // Only the bot removes reactions from anonymous polls
+            return
+        }
+        if let Some(choices) = self.votes.get_mut(&user) {
+            choices.retain(|o| *o != option);
+        }
+    }
+
+    /// Each user's choices, written as the options' emoji in the order chosen
+    pub fn ballots(&self) -> Vec<(UserId, String)> {
+        self.votes.iter()
+            .filter(|(_,choices)| !choices.is_empty())
+            .map(|(user, choices)| (*user, choices.iter()
+                .map(|o| self.options[*o].emoji.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")))
+            .collect()
+    }
+
+    /// The number of users who selected each option
+    pub fn counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for option in self.votes.values().flatten() {
+            counts[*option] += 1;
+        }
+        counts
+    }
+
+    /// The results of the poll. Ranked polls show each round of the runoff,
+    /// others show the number of votes and percentage of voters for each
+    /// option, with bar charts.
+    pub fn results(&self) -> String {
+        if self.mode == PollMode::Ranked {
+            return self.runoff()
+        }
+        let counts = self.counts();
+        let voters = self.votes.values().filter(|v| !v.is_empty()).count();
+        let lines = self.options.iter()
+            .zip(&counts)
+            .map(|(option, count)| {
+                let fraction = match voters {
+                    0 => 0.0,
+                    _ => *count as f64 / voters as f64
+                };
+                format!("{}\n`{}` {} ({:.0}%)", option.label(), bar(fraction), count, fraction * 100.0)
+            })
+            .collect::<Vec<String>>();
+        format!("{}\n\nVoters: {}", lines.join("\n"), voters)
+    }
+
+    /// Count a ranked poll by instant-runoff. Each round, every ballot counts
+    /// for its highest ranked option still remaining. An option with a
+    /// majority wins, otherwise the options with the fewest votes are
+    /// eliminated.
+    fn runoff(&self) -> String {
+        let mut remaining = vec![true; self.options.len()];
+        let mut lines = Vec::new();
+        let winners = loop {
+            let mut counts = vec![0; self.options.len()];
+            for ballot in self.votes.values() {
+                if let Some(choice) = ballot.iter().find(|o| remaining[**o]) {
+                    counts[*choice] += 1;
+                }
+            }
+            let active = counts.iter().sum::<usize>();
+            let standing = (0..self.options.len())
+                .filter(|o| remaining[*o])
+                .collect::<Vec<usize>>();
+            let tally = standing.iter()
+                .map(|o| format!("{} {}", self.options[*o].emoji, counts[*o]))
+                .collect::<Vec<String>>()
+                .join(", ");
+            let most = standing.iter().map(|o| counts[*o]).max().unwrap_or(0);
+            let fewest = standing.iter().map(|o| counts[*o]).min().unwrap_or(0);
+            if active == 0 {
+                break Vec::new()
+            } else if most * 2 > active || fewest == most {
+                lines.push(format!("Round {}: {}", lines.len() + 1, tally));
+                break standing.into_iter().filter(|o| counts[*o] == most).collect()
+            }
+            let eliminated = standing.into_iter()
+                .filter(|o| counts[*o] == fewest)
+                .collect::<Vec<usize>>();
+            let names = eliminated.iter()
+                .map(|o| self.options[*o].label())
+                .collect::<Vec<String>>()
+                .join(", ");
+            lines.push(format!("Round {}: {} \u{2014} eliminated {}", lines.len() + 1, tally, names));
+            for o in eliminated {
+                remaining[o] = false;
+            }
+        };
+        let voters = self.votes.values().filter(|v| !v.is_empty()).count();
+        let outcome = match winners.as_slice() {
+            [] => String::from("No votes were cast"),
+            [winner] => format!("Winner: {}", self.options[*winner].label()),
+            tied => format!("Tie between {}", tied.iter()
+                .map(|o| self.options[*o].label())
+                .collect::<Vec<String>>()
+                .join(", "))
+        };
+        match lines.is_empty() {
+            true => format!("{}\n\nVoters: {}", outcome, voters),
+            false => format!("{}\n\n**{}**\n\nVoters: {}", lines.join("\n"), outcome, voters)
+        }
+    }
+}
+
+/// Draw a bar filled up to a fraction
+fn bar(fraction: f64) -> String {
+    let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+    format!("{}{}", "\u{2588}".repeat(filled), "\u{2591}".repeat(BAR_WIDTH - filled))
+}
+
+/// Compare emoji, ignoring the names of custom emoji and variation selectors,
+/// which Discord doesn't always send back the same way
+fn same_emoji(a: &ReactionType, b: &ReactionType) -> bool {
+    match (a, b) {
+        (ReactionType::Custom { id: a, .. }, ReactionType::Custom { id: b, .. }) => a == b,
+        (ReactionType::Unicode(a), ReactionType::Unicode(b)) =>
+            a.trim_end_matches('\u{FE0F}') == b.trim_end_matches('\u{FE0F}'),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(mode: PollMode, anonymous: bool) -> Poll {
+        Poll {
+            channel: ChannelId(1),
+            guild: None,
+            creator: UserId(1),
+            question: String::from("Which?"),
+            options: parse_options(&["a", "b", "c"], &[]).unwrap(),
+            mode,
+            votes: HashMap::new(),
+            closes_at: None,
+            closed: false,
+            unannounced: false,
+            anonymous,
+            missing_permissions: false,
+            removed: HashSet::new()
+        }
+    }
+
+    #[test]
+    fn single_vote_replaces_choice() {
+        let mut poll = poll(PollMode::Single, false);
+        assert_eq!(poll.vote(UserId(2), 0), None);
+        assert_eq!(poll.vote(UserId(2), 1), Some(0));
+        assert_eq!(poll.votes[&UserId(2)], vec![1]);
+        assert_eq!(poll.counts(), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn finished_polls() {
+        let mut poll = poll(PollMode::Single, false);
+        assert!(!poll.is_finished(0, 1000));
+        assert!(poll.is_finished(0, MAX_DURATION + 1));
+        poll.closes_at = Some(MAX_DURATION * 2);
+        assert!(!poll.is_finished(0, MAX_DURATION + 1));
+        poll.closed = true;
+        poll.unannounced = true;
+        assert!(!poll.is_finished(0, 1000));
+        poll.unannounced = false;
+        assert!(poll.is_finished(0, 1000));
+    }
+
+    #[test]
+    fn durations() {
+        let (settings, rest) = parse_settings("2h | Which?; A; B").unwrap();
+        assert_eq!(settings.duration, Some(2*60*60*1000));
+        assert_eq!(rest, "Which?; A; B");
+        assert!(parse_settings("2h 3h | Which?").is_err());
+        assert!(parse_settings("91d | Which?").is_err());
+        // Settings need a separator, so questions can start with anything
+        let (settings, rest) = parse_settings("2h meeting?; Yes; No").unwrap();
+        assert_eq!(settings.duration, None);
+        assert_eq!(rest, "2h meeting?; Yes; No");
+        let (settings, rest) = parse_settings("Cats | dogs?; A; B").unwrap();
+        assert_eq!(settings.duration, None);
+        assert_eq!(rest, "Cats | dogs?; A; B");
+        assert_eq!(parse_settings("Which?; A | B; C").unwrap().1, "Which?; A | B; C");
+    }
+
+    #[test]
+    fn modes() {
+        let (settings, rest) = parse_settings("multi 2|Which?").unwrap();
+        assert!(settings.mode == PollMode::Multi(2));
+        assert_eq!(rest, "Which?");
+        assert!(parse_settings("ranked approval | Which?").is_err());
+        assert!(parse_settings("multi 1 | Which?").is_err());
+        let (settings, rest) = parse_settings("approval voting; Yes; No").unwrap();
+        assert!(settings.mode == PollMode::Single);
+        assert_eq!(rest, "approval voting; Yes; No");
+    }
+
+    #[test]
+    fn multi_vote_drops_earliest() {
+        let mut poll = poll(PollMode::Multi(2), false);
+        poll.vote(UserId(2), 0);
+        poll.vote(UserId(2), 1);
+        assert_eq!(poll.vote(UserId(2), 2), Some(0));
+        assert_eq!(poll.votes[&UserId(2)], vec![1, 2]);
+    }
+
+    #[test]
+    fn ranked_runoff() {
+        let mut poll = poll(PollMode::Ranked, false);
+        let ballots = vec![vec![0], vec![0], vec![1, 0], vec![2, 1], vec![2, 1]];
+        for (user, ballot) in ballots.into_iter().enumerate() {
+            for option in ballot {
+                poll.vote(UserId(user as u64 + 2), option);
+            }
+        }
+        let results = poll.results();
+        assert!(results.contains(&format!("eliminated {}", poll.options[1].label())));
+        assert!(results.contains(&format!("Winner: {}", poll.options[0].label())));
+    }
+
+    #[test]
+    fn anonymous_vote_toggles() {
+        let (settings, _) = parse_settings("anonymous | Which?").unwrap();
+        assert!(settings.anonymous);
+        assert!(parse_settings("anonymous anonymous | Which?").is_err());
+        let mut poll = poll(PollMode::Approval, true);
+        poll.vote(UserId(2), 0);
+        poll.vote(UserId(2), 0);
+        assert!(poll.votes[&UserId(2)].is_empty());
+    }
+
+    #[test]
+    fn options() {
+        let options = parse_options(&["a", "🍕 pizza"], &[]).unwrap();
+        assert_eq!(options[1].emoji, ReactionType::Unicode(String::from("🍕")));
+        assert_eq!(options[1].text, "pizza");
+        assert!(parse_options(&["🍕 a", "🍕 b"], &[]).is_err());
+        assert!(parse_options(&[":unknown: a"], &[]).is_err());
+        // Options whose emoji were rejected keep the symbol in their text
+        let options = parse_options(&["a", "\u{2192} b"], &[1]).unwrap();
+        assert_eq!(options[1].emoji, default_emoji(1, 2));
+        assert_eq!(options[1].text, "\u{2192} b");
+    }
+
+    #[test]
+    fn unvote_ignores_bot_removals() {
+        let mut poll = poll(PollMode::Approval, false);
+        poll.vote(UserId(2), 0);
+        poll.vote(UserId(2), 1);
+        poll.removed_reaction(UserId(2), 0);
+        poll.unvote(UserId(2), 0);
+        assert_eq!(poll.votes[&UserId(2)], vec![0, 1]);
+        poll.unvote(UserId(2), 1);
+        assert_eq!(poll.votes[&UserId(2)], vec![0]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Most evaluations that can run at once, others wait for a free worker
+const WORKERS: usize = 4;
+/// Longest an evaluation can run, or wait for a worker, before giving up
+pub const TIME_LIMIT: Duration = Duration::from_secs(3);
+/// Longest input accepted by evaluation commands
+pub const MAX_INPUT_LEN: usize = 500;
+
+/// Error returned by evaluations that give up after being cancelled
+pub const CANCELLED: &str = "cancelled";
+
+static POOL: OnceLock<Semaphore> = OnceLock::new();
+
+/// Set when an evaluation runs out of time. Blocking threads can't be
+/// stopped from outside, so long-running evaluations should check this and
+/// give up early.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn check_input(input: &str) -> Result<(), String> {
+    match input.chars().count() > MAX_INPUT_LEN {
+        true => Err(format!("the input is too long, the limit is {} characters", MAX_INPUT_LEN)),
+        false => Ok(())
+    }
+}
+
+/// Run an evaluation on the worker pool, with a time limit. The result is
+/// given up on once it runs out of time, but the worker is only released
+/// when `f` returns, so `f` must check `Cancel` in any loop that can run for
+/// long.
+pub async fn run<T, F>(f: F) -> Result<T, String>
+where T: Send + 'static, F: FnOnce(&Cancel) -> T + Send + 'static {
+    let pool = POOL.get_or_init(|| Semaphore::new(WORKERS));
+    let permit = tokio::time::timeout(TIME_LIMIT, pool.acquire()).await
+        .map_err(|_| String::from("too many things are being evaluated right now, try again in a moment"))?;
+    let cancel = Cancel::default();
+    let worker_cancel = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f(&worker_cancel)
+    });
+    match tokio::time::timeout(TIME_LIMIT, task).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(_)) => Err(String::from("something went wrong while evaluating")),
+        Err(_) => {
+            cancel.cancel();
+            Err(format!("evaluation took too long, the limit is {} seconds", TIME_LIMIT.as_secs()))
+        }
+    }
+}
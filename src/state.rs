@@ -1,21 +1,114 @@
-use serenity::model::id::UserId;
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 use serenity::prelude::*;
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
 use serde::{Serialize, Deserialize};
 use std::fs::File;
 use std::collections::{HashSet, HashMap};
+use crate::calc::Workspace;
+use crate::cards::Deck;
+use crate::counting::{self, Achievement, CountStats, CountingGame, CountingResult, CurrentSeason, Season};
+use crate::dice::Sheet;
+use crate::eightball::{self, Answer};
+use crate::fair::{Draw, FairSeed, Replay};
+use crate::initiative::Initiative;
+use crate::polls::Poll;
+use crate::users::UserDirectory;
+use crate::utils;
 
-const COUNT_TIMEOUT: u64 = 60*60*1000; // 1hr as millis
+const DEFAULT_COUNT_TIMEOUT: u64 = 60*60*1000; // 1hr as millis
+const MIN_COUNT_TIMEOUT: u64 = 60*1000; // 1min as millis
+/// Shortest time between scheduled seasons, since each one resets all counts
+const MIN_SEASON_INTERVAL: u64 = 24*60*60*1000; // 1 day as millis
+
+const MAX_LEADERBOARDS: usize = 32;
+const MAX_AUDIT_ENTRIES: usize = 100;
+const MAX_REVEALED_SEEDS: usize = 10;
+/// Most polls kept. The oldest finished polls are forgotten first, and new
+/// polls can't be created if none have finished.
+const MAX_POLLS: usize = 1000;
+/// How long after a poll closes to keep trying to announce its results
+const MAX_ANNOUNCE_DELAY: u64 = 24*60*60*1000; // 1 day as millis
 
 pub type StateResult<T> = Result<T,&'static str>;
 
+/// A paginated leaderboard message, kept so that page reactions can be handled
+#[derive(Clone)]
+pub struct Leaderboard {
+    pub guild: Option<GuildId>,
+    pub requester: UserId,
+    pub season: Option<String>,
+    pub entries: Vec<(UserId, u64)>,
+    pub page: usize,
+}
+
+impl Leaderboard {
+    pub fn pages(&self, page_size: usize) -> usize {
+        self.entries.len().div_ceil(page_size).max(1)
+    }
+
+    pub fn rank_of(&self, user: UserId) -> Option<usize> {
+        self.entries.iter().position(|(u,_)| *u == user).map(|i| i+1)
+    }
+}
+
+/// An action taken by a bot admin
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub user: UserId,
+    pub action: String,
+    pub target: Option<UserId>,
+}
+
+/// An RNG for a random result, which can be moved to another task
+pub type Random = Box<dyn RngCore + Send>;
+
 #[derive(Default)]
 #[derive(Serialize, Deserialize)]
 pub struct State {
     banned: HashSet<UserId>,
     admins: HashSet<UserId>,
     count: HashMap<UserId, u64>,
+    /// When each user last counted outside a server
     count_cooldown: HashMap<UserId, u64>,
+    /// When each user last counted in each server, since servers have
+    /// their own cooldowns
+    #[serde(default)]
+    guild_count_cooldowns: HashMap<GuildId, HashMap<UserId, u64>>,
+    #[serde(default)]
+    count_stats: HashMap<UserId, CountStats>,
+    #[serde(default)]
+    count_timeouts: HashMap<GuildId, u64>,
+    #[serde(default)]
+    season: CurrentSeason,
+    #[serde(default)]
+    seasons: Vec<Season>,
+    #[serde(default)]
+    counting_games: HashMap<GuildId, CountingGame>,
+    #[serde(default)]
+    sheets: HashMap<GuildId, HashMap<UserId, Sheet>>,
+    #[serde(default)]
+    initiative: HashMap<ChannelId, Initiative>,
+    #[serde(default)]
+    fair_seeds: HashMap<GuildId, FairSeed>,
+    #[serde(default)]
+    revealed_seeds: HashMap<GuildId, Vec<FairSeed>>,
+    #[serde(default)]
+    eight_ball: HashMap<GuildId, Vec<Answer>>,
+    #[serde(default)]
+    decks: HashMap<ChannelId, Deck>,
+    #[serde(default)]
+    workspaces: HashMap<UserId, Workspace>,
+    #[serde(default)]
+    polls: HashMap<MessageId, Poll>,
     custom_cmds: HashMap<String, String>,
+    #[serde(default)]
+    users: UserDirectory,
+    #[serde(default)]
+    audit_log: Vec<AuditEntry>,
+    #[serde(skip)]
+    leaderboards: HashMap<MessageId, Leaderboard>,
     #[serde(skip)]
     file: Option<String>,
     #[serde(skip)]
@@ -37,15 +130,15 @@ impl State {
                 x.file = Some(name.to_owned());
                 Ok(x)
             }
-            Err(_) => return Err("Could not parse file")
+            Err(_) => Err("Could not parse file")
         }
     }
 
     pub fn ban(&mut self, user: UserId) -> StateResult<()> {
         if self.admins.contains(&user) {
-            return Err("Cannot ban an admin")
+            Err("Cannot ban an admin")
         } else if self.banned.contains(&user) {
-            return Err("User is already banned")
+            Err("User is already banned")
         } else {
             self.banned.insert(user);
             self.dirty = true;
@@ -55,7 +148,7 @@ impl State {
 
     pub fn unban(&mut self, user: UserId) -> StateResult<()> {
         if !self.banned.contains(&user) {
-            return Err("User is not banned")
+            Err("User is not banned")
         } else {
             self.banned.remove(&user);
             self.dirty = true;
@@ -71,35 +164,496 @@ impl State {
         self.banned.contains(&user)
     }
     
-    pub fn count_up(&mut self, user: UserId) -> u64 {
-        use std::time::*;
-        use std::convert::TryInto;
-        let cooldown = *self.count_cooldown.entry(user).or_insert(0);
-        let ctime: u64 = SystemTime::now()
-            .duration_since(UNIX_EPOCH).unwrap()
-            .as_millis().try_into().unwrap();
-        if ctime > (cooldown + COUNT_TIMEOUT) {
-            *self.count.entry(user).or_insert(0) += 1;
+    pub fn get_banned(&self) -> Vec<UserId> {
+        self.banned.iter().copied().collect()
+    }
+
+    /// Record an admin action in the audit log, forgetting the oldest entry
+    /// if it's full
+    pub fn audit(&mut self, user: UserId, action: &str, target: Option<UserId>) {
+        self.audit_log.push(AuditEntry { time: utils::now_millis(), user, action: action.to_owned(), target });
+        if self.audit_log.len() > MAX_AUDIT_ENTRIES {
+            self.audit_log.remove(0);
+        }
+        self.dirty = true;
+    }
+
+    pub fn get_audit_log(&self) -> &[AuditEntry] {
+        &self.audit_log
+    }
+
+    pub fn users(&self) -> &UserDirectory {
+        &self.users
+    }
+
+    pub fn users_mut(&mut self) -> &mut UserDirectory {
+        &mut self.users
+    }
+    
+    pub fn count_timeout(&self, guild: Option<GuildId>) -> u64 {
+        guild.and_then(|g| self.count_timeouts.get(&g))
+            .copied()
+            .unwrap_or(DEFAULT_COUNT_TIMEOUT)
+    }
+
+    pub fn set_count_timeout(&mut self, guild: GuildId, timeout: Option<u64>) -> StateResult<()> {
+        if timeout.is_some_and(|t| t < MIN_COUNT_TIMEOUT) {
+            return Err("The count cooldown can't be shorter than a minute")
+        }
+        match timeout {
+            Some(t) => self.count_timeouts.insert(guild, t),
+            None => self.count_timeouts.remove(&guild)
+        };
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn count_up(&mut self, user: UserId, guild: Option<GuildId>) -> Result<Vec<Achievement>, u64> {
+        let timeout = self.count_timeout(guild);
+        let cooldowns = match guild {
+            Some(g) => self.guild_count_cooldowns.entry(g).or_default(),
+            None => &mut self.count_cooldown
+        };
+        let cooldown = cooldowns.get(&user).copied().unwrap_or(0);
+        let ctime = utils::now_millis();
+        if ctime > (cooldown + timeout) {
+            cooldowns.insert(user, ctime);
+            let count = self.count.entry(user).or_insert(0);
+            *count += 1;
+            let count = *count;
             self.dirty = true;
-            self.count_cooldown.insert(user, ctime);
-            0
+            let rank = self.get_count_rank(user).unwrap_or(0);
+            let stats = self.count_stats.entry(user)
+                .or_insert_with(|| CountStats::new(count - 1));
+            Ok(stats.record(counting::day_of(ctime), count, rank))
         } else {
-            cooldown + COUNT_TIMEOUT - ctime
+            Err(cooldown + timeout - ctime)
         }
     }
 
-    pub fn get_count(&mut self, user: UserId) -> u64 {
-        *self.count.entry(user).or_insert(0)
+    pub fn get_count(&self, user: UserId) -> u64 {
+        self.count.get(&user).copied().unwrap_or(0)
     }
 
-    pub fn get_count_all(&mut self) -> Vec<(UserId, u64)> {
+    pub fn get_count_all(&self) -> Vec<(UserId, u64)> {
         let mut sorted = self.count.iter()
+            .filter(|(_,b)| **b > 0)
             .map(|(a,b)| (*a,*b))
             .collect::<Vec<(UserId, u64)>>();
         sorted.sort_by_key(|(_,a)| u64::MAX-*a);
         sorted
     }
 
+    pub fn get_count_rank(&self, user: UserId) -> Option<usize> {
+        self.get_count_all().iter().position(|(u,_)| *u == user).map(|i| i+1)
+    }
+
+    pub fn get_count_stats(&self, user: UserId) -> Option<&CountStats> {
+        self.count_stats.get(&user)
+    }
+
+    pub fn current_season(&self) -> &CurrentSeason {
+        &self.season
+    }
+
+    pub fn get_seasons(&self) -> &[Season] {
+        &self.seasons
+    }
+
+    pub fn get_season(&self, name: &str) -> Option<&Season> {
+        self.seasons.iter().find(|s| s.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Archive the current leaderboard, reset all counts, and start a new
+    /// season, returning the name the old season was archived under
+    pub fn start_season(&mut self, name: Option<&str>) -> StateResult<String> {
+        if let Some(name) = name {
+            if self.get_season(name).is_some() || self.season.name.as_deref() == Some(name) {
+                return Err("A season with that name already exists")
+            }
+        }
+        let ctime = utils::now_millis();
+        let archived = match self.season.name.take() {
+            Some(n) if self.get_season(&n).is_none() => n,
+            _ => format!("Season {}", self.seasons.len() + 1)
+        };
+        self.seasons.push(Season {
+            name: archived.clone(),
+            started: self.season.started,
+            ended: ctime,
+            standings: self.get_count_all(),
+        });
+        self.count.clear();
+        self.count_cooldown.clear();
+        self.guild_count_cooldowns.clear();
+        self.season.name = name.map(|n| n.to_owned());
+        self.season.started = ctime;
+        self.dirty = true;
+        Ok(archived)
+    }
+
+    pub fn schedule_seasons(&mut self, interval: Option<u64>) -> StateResult<()> {
+        if interval.is_some_and(|i| i < MIN_SEASON_INTERVAL) {
+            return Err("Seasons must last at least a day")
+        }
+        self.season.interval = interval;
+        self.season.next_rollover = interval.map(|i| utils::now_millis() + i);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Start a new season if the scheduled rollover time has passed
+    pub fn rollover_season(&mut self, now: u64) -> Option<String> {
+        let (interval, next) = match (self.season.interval, self.season.next_rollover) {
+            (Some(i), Some(n)) if n <= now => (i, n),
+            _ => return None
+        };
+        let archived = self.start_season(None).ok()?;
+        let missed = (now - next) / interval;
+        self.season.next_rollover = Some(next + (missed + 1) * interval);
+        Some(archived)
+    }
+
+    pub fn get_counting_game(&self, guild: GuildId) -> Option<&CountingGame> {
+        self.counting_games.get(&guild)
+    }
+
+    pub fn is_counting_channel(&self, guild: GuildId, channel: ChannelId) -> bool {
+        self.counting_games.get(&guild).filter(|g| !g.disabled).map(|g| g.channel) == Some(channel)
+    }
+
+    /// Set the counting channel for a guild, or turn the game off, keeping
+    /// the count and player statistics if the guild already had one
+    pub fn set_counting_channel(&mut self, guild: GuildId, channel: Option<ChannelId>) {
+        match channel {
+            Some(c) => {
+                let game = self.counting_games.entry(guild)
+                    .or_insert_with(|| CountingGame::new(c));
+                game.channel = c;
+                game.disabled = false;
+            },
+            None => if let Some(game) = self.counting_games.get_mut(&guild) {
+                game.disabled = true;
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub fn set_counting_expressions(&mut self, guild: GuildId, enabled: bool) -> StateResult<()> {
+        match self.counting_games.get_mut(&guild) {
+            Some(game) => {
+                game.expressions = enabled;
+                self.dirty = true;
+                Ok(())
+            },
+            None => Err("This server has no counting channel")
+        }
+    }
+
+    /// Submit a number to a guild's counting game, returning `None` if the game is off
+    pub fn submit_count(&mut self, guild: GuildId, user: UserId, number: f64) -> Option<CountingResult> {
+        let game = self.counting_games.get_mut(&guild).filter(|g| !g.disabled)?;
+        self.dirty = true;
+        Some(game.submit(user, number))
+    }
+
+    pub fn get_sheet(&self, guild: GuildId, user: UserId) -> Option<&Sheet> {
+        self.sheets.get(&guild).and_then(|s| s.get(&user))
+    }
+
+    /// Edit a user's sheet with `f`, removing the sheet if it ends up empty
+    pub fn edit_sheet<T>(&mut self, guild: GuildId, user: UserId, f: impl FnOnce(&mut Sheet) -> T) -> T {
+        let sheets = self.sheets.entry(guild).or_default();
+        let sheet = sheets.entry(user).or_default();
+        let result = f(sheet);
+        if sheet.is_empty() {
+            sheets.remove(&user);
+        }
+        if sheets.is_empty() {
+            self.sheets.remove(&guild);
+        }
+        self.dirty = true;
+        result
+    }
+
+    pub fn get_initiative(&self, channel: ChannelId) -> Option<&Initiative> {
+        self.initiative.get(&channel)
+    }
+
+    pub fn initiative_mut(&mut self, channel: ChannelId) -> &mut Initiative {
+        self.dirty = true;
+        self.initiative.entry(channel).or_default()
+    }
+
+    /// Add a combatant to the channel's initiative, starting a new one only
+    /// if the combatant could be added
+    pub fn add_combatant(&mut self, channel: ChannelId, name: &str, initiative: i64) -> StateResult<()> {
+        match self.initiative.get_mut(&channel) {
+            Some(tracker) => tracker.add(name, initiative)?,
+            None => {
+                let mut tracker = Initiative::default();
+                tracker.add(name, initiative)?;
+                self.initiative.insert(channel, tracker);
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn end_initiative(&mut self, channel: ChannelId) -> Option<Initiative> {
+        self.dirty = true;
+        self.initiative.remove(&channel)
+    }
+
+    /// Get the RNG for a random result. If the guild uses seeded randomness,
+    /// the result is recorded and an ID is returned to tag it with.
+    pub fn random(&mut self, guild: Option<GuildId>, draw: Draw) -> (Random, Option<String>) {
+        match guild.and_then(|g| self.fair_seeds.get_mut(&g)) {
+            Some(seed) => {
+                let (id, rng) = seed.draw(draw);
+                self.dirty = true;
+                (Box::new(rng), Some(id))
+            },
+            None => (Box::new(StdRng::from_entropy()), None)
+        }
+    }
+
+    /// Get the RNG for an 8-ball answer from `answers`, recording the answer
+    /// set along with the result if the guild uses seeded randomness
+    pub fn random_answer(&mut self, guild: Option<GuildId>, answers: &[Answer]) -> (Random, Option<String>) {
+        let draw = match guild.and_then(|g| self.fair_seeds.get_mut(&g)) {
+            Some(seed) => seed.eight_ball(answers),
+            None => Draw::EightBall
+        };
+        self.random(guild, draw)
+    }
+
+    pub fn get_fair_seed(&self, guild: GuildId) -> Option<&FairSeed> {
+        self.fair_seeds.get(&guild)
+    }
+
+    pub fn get_revealed_seeds(&self, guild: GuildId) -> &[FairSeed] {
+        self.revealed_seeds.get(&guild).map(|s| s.as_slice()).unwrap_or(&[])
+    }
+
+    /// Start using seeded randomness in a guild, returning the seed's commitment
+    pub fn start_fair_seed(&mut self, guild: GuildId) -> StateResult<String> {
+        if self.fair_seeds.contains_key(&guild) {
+            return Err("Seeded randomness is already on")
+        }
+        let seed = FairSeed::new();
+        let commitment = seed.commitment();
+        self.fair_seeds.insert(guild, seed);
+        self.dirty = true;
+        Ok(commitment)
+    }
+
+    /// Reveal a guild's current seed, replacing it with a new one if `restart` is set
+    pub fn reveal_fair_seed(&mut self, guild: GuildId, restart: bool) -> StateResult<FairSeed> {
+        let mut seed = self.fair_seeds.remove(&guild).ok_or("Seeded randomness is off")?;
+        seed.revealed = true;
+        let revealed = self.revealed_seeds.entry(guild).or_default();
+        revealed.push(seed.clone());
+        if revealed.len() > MAX_REVEALED_SEEDS {
+            revealed.remove(0);
+        }
+        if restart {
+            self.fair_seeds.insert(guild, FairSeed::new());
+        }
+        self.dirty = true;
+        Ok(seed)
+    }
+
+    /// Find a seeded result from its ID so that it can be reproduced
+    pub fn replay(&self, guild: GuildId, id: &str) -> StateResult<Replay> {
+        self.fair_seeds.get(&guild).into_iter()
+            .chain(self.get_revealed_seeds(guild))
+            .find(|s| s.matches(id))
+            .ok_or("no result with that ID")?
+            .replay(id)
+    }
+
+    /// Get the 8-ball answers for a guild, which are the default answers
+    /// unless they have been changed
+    pub fn get_answers(&self, guild: Option<GuildId>) -> Vec<Answer> {
+        guild.and_then(|g| self.eight_ball.get(&g))
+            .cloned()
+            .unwrap_or_else(eightball::default_answers)
+    }
+
+    pub fn add_answer(&mut self, guild: GuildId, answer: Answer) -> StateResult<()> {
+        let answers = self.eight_ball.entry(guild).or_insert_with(eightball::default_answers);
+        if answers.len() >= eightball::MAX_ANSWERS {
+            return Err("there are too many answers")
+        }
+        answers.push(answer);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Remove the answer at `index`, counting from 0
+    pub fn remove_answer(&mut self, guild: GuildId, index: usize) -> StateResult<Answer> {
+        let answers = self.eight_ball.entry(guild).or_insert_with(eightball::default_answers);
+        if index >= answers.len() {
+            return Err("There is no answer with that number")
+        }
+        if answers.len() == 1 {
+            return Err("The last answer can't be removed")
+        }
+        self.dirty = true;
+        Ok(answers.remove(index))
+    }
+
+    pub fn reset_answers(&mut self, guild: GuildId) {
+        self.eight_ball.remove(&guild);
+        self.dirty = true;
+    }
+
+    pub fn get_deck(&self, channel: ChannelId) -> Option<&Deck> {
+        self.decks.get(&channel)
+    }
+
+    pub fn set_deck(&mut self, channel: ChannelId, deck: Deck) {
+        self.decks.insert(channel, deck);
+        self.dirty = true;
+    }
+
+    pub fn get_workspace(&self, user: UserId) -> Option<&Workspace> {
+        self.workspaces.get(&user)
+    }
+
+    /// Choose whether a user's evaluations are exact by default
+    pub fn set_exact_mode(&mut self, user: UserId, exact: bool) {
+        if self.get_workspace(user).is_some_and(|w| w.exact) != exact {
+            self.workspaces.entry(user).or_default().exact = exact;
+            self.dirty = true;
+        }
+    }
+
+    /// Set how many decimal places a user's exact results are shown to
+    pub fn set_precision(&mut self, user: UserId, precision: usize) {
+        if self.get_workspace(user).cloned().unwrap_or_default().precision() != precision {
+            self.workspaces.entry(user).or_default().precision = Some(precision);
+            self.dirty = true;
+        }
+    }
+
+    /// Store a user's variables and functions after an evaluation, keeping
+    /// their current settings. Fails if they were stored or cleared since
+    /// `workspace` was copied.
+    pub fn update_workspace(&mut self, user: UserId, workspace: Workspace) -> StateResult<()> {
+        let stored = self.get_workspace(user).cloned().unwrap_or_default();
+        if stored.version != workspace.version {
+            return Err("Your variables changed while this was being evaluated, so the result wasn't stored")
+        }
+        if (stored.ans, &stored.vars, &stored.funcs) == (workspace.ans, &workspace.vars, &workspace.funcs) {
+            return Ok(())
+        }
+        self.workspaces.insert(user, Workspace {
+            ans: workspace.ans,
+            vars: workspace.vars,
+            funcs: workspace.funcs,
+            version: stored.version + 1,
+            ..stored
+        });
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Remove a user's variables and functions, keeping their settings
+    pub fn clear_workspace(&mut self, user: UserId) {
+        if let Some(workspace) = self.workspaces.get_mut(&user) {
+            workspace.ans = None;
+            workspace.vars.clear();
+            workspace.funcs.clear();
+            workspace.version += 1;
+            self.dirty = true;
+        }
+    }
+
+    /// The oldest poll that can be forgotten to make room for another
+    fn oldest_finished_poll(&self) -> Option<MessageId> {
+        let now = utils::now_millis();
+        self.polls.iter()
+            .filter(|(id, poll)| poll.is_finished(id.created_at().timestamp_millis() as u64, now))
+            .map(|(id,_)| *id)
+            .min()
+    }
+
+    /// Check that there's room to store another poll
+    pub fn can_add_poll(&self) -> StateResult<()> {
+        match self.polls.len() < MAX_POLLS || self.oldest_finished_poll().is_some() {
+            true => Ok(()),
+            false => Err("There are too many open polls right now, try again once some have closed")
+        }
+    }
+
+    pub fn add_poll(&mut self, msg: MessageId, poll: Poll) -> StateResult<()> {
+        self.can_add_poll()?;
+        if self.polls.len() >= MAX_POLLS {
+            if let Some(oldest) = self.oldest_finished_poll() {
+                self.polls.remove(&oldest);
+            }
+        }
+        self.polls.insert(msg, poll);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn get_poll(&self, msg: MessageId) -> Option<&Poll> {
+        self.polls.get(&msg)
+    }
+
+    /// Close every poll whose time is up, returning them along with any
+    /// earlier polls whose results still need to be announced. Polls that
+    /// still can't be announced a day after closing are given up on.
+    pub fn close_due_polls(&mut self, now: u64) -> Vec<(MessageId, Poll)> {
+        let mut closed = Vec::new();
+        for (id, poll) in self.polls.iter_mut() {
+            if poll.is_due(now) {
+                poll.closed = true;
+                poll.unannounced = true;
+                self.dirty = true;
+            } else if poll.unannounced && poll.closes_at.is_some_and(|t| now.saturating_sub(t) > MAX_ANNOUNCE_DELAY) {
+                poll.unannounced = false;
+                self.dirty = true;
+            }
+            if poll.unannounced {
+                closed.push((*id, poll.clone()));
+            }
+        }
+        closed
+    }
+
+    /// Record that a closed poll's results have been announced
+    pub fn poll_announced(&mut self, msg: MessageId) {
+        if let Some(poll) = self.polls.get_mut(&msg) {
+            poll.unannounced = false;
+            self.dirty = true;
+        }
+    }
+
+    pub fn poll_mut(&mut self, msg: MessageId) -> Option<&mut Poll> {
+        self.dirty = true;
+        self.polls.get_mut(&msg)
+    }
+
+    pub fn add_leaderboard(&mut self, msg: MessageId, leaderboard: Leaderboard) {
+        self.leaderboards.insert(msg, leaderboard);
+        if self.leaderboards.len() > MAX_LEADERBOARDS {
+            let oldest = *self.leaderboards.keys().min().unwrap();
+            self.leaderboards.remove(&oldest);
+        }
+    }
+
+    pub fn has_leaderboard(&self, msg: MessageId) -> bool {
+        self.leaderboards.contains_key(&msg)
+    }
+
+    pub fn leaderboard_mut(&mut self, msg: MessageId) -> Option<&mut Leaderboard> {
+        self.leaderboards.get_mut(&msg)
+    }
+
     pub fn add_cmd(&mut self, cmd: &str, text: &str) {
         self.custom_cmds.insert(cmd.to_owned(), text.to_owned());
         self.dirty = true;
@@ -125,10 +679,11 @@ impl State {
         if self.file.is_none() {
             return Err(String::from("No file set"))
         }
+        self.users.prune();
         use std::fs::OpenOptions;
         let file = match OpenOptions::new()
             .write(true).truncate(true)
-            .open(&(self.file.as_ref().unwrap())) {
+            .open(self.file.as_ref().unwrap()) {
             Ok(x) => x,
             Err(e) => return Err(format!("{:?}", e))
         };
@@ -140,3 +695,57 @@ impl State {
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldowns_are_per_guild() {
+        let mut state = State::default();
+        let user = UserId(1);
+        assert!(state.count_up(user, Some(GuildId(1))).is_ok());
+        assert!(state.count_up(user, Some(GuildId(1))).is_err());
+        assert!(state.count_up(user, Some(GuildId(2))).is_ok());
+        assert!(state.count_up(user, None).is_ok());
+        assert_eq!(state.get_count(user), 3);
+    }
+
+    #[test]
+    fn cooldowns_can_be_shortened() {
+        let mut state = State::default();
+        assert!(state.set_count_timeout(GuildId(1), Some(1000)).is_err());
+        assert!(state.set_count_timeout(GuildId(1), Some(5*60*1000)).is_ok());
+        assert_eq!(state.count_timeout(Some(GuildId(1))), 5*60*1000);
+        assert_eq!(state.count_timeout(Some(GuildId(2))), DEFAULT_COUNT_TIMEOUT);
+    }
+
+    fn timed_poll() -> Poll {
+        serde_json::from_value(serde_json::json!({
+            "channel": 1, "creator": 1, "question": "Which?", "options": [], "votes": {},
+            "closes_at": u64::MAX
+        })).unwrap()
+    }
+
+    #[test]
+    fn open_polls_are_kept() {
+        let mut state = State::default();
+        for id in 0..MAX_POLLS as u64 {
+            state.add_poll(MessageId(id), timed_poll()).unwrap();
+        }
+        assert!(state.add_poll(MessageId(MAX_POLLS as u64), timed_poll()).is_err());
+        state.poll_mut(MessageId(5)).unwrap().closed = true;
+        assert!(state.add_poll(MessageId(MAX_POLLS as u64), timed_poll()).is_ok());
+        assert!(state.get_poll(MessageId(5)).is_none());
+        assert!(state.get_poll(MessageId(0)).is_some());
+    }
+
+    #[test]
+    fn seasons_last_a_day() {
+        let mut state = State::default();
+        assert!(state.schedule_seasons(Some(1000)).is_err());
+        assert_eq!(state.current_season().interval, None);
+        assert!(state.schedule_seasons(Some(7*24*60*60*1000)).is_ok());
+        assert!(state.schedule_seasons(None).is_ok());
+    }
+}
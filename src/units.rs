@@ -0,0 +1,319 @@
+use crate::sandbox::{self, Cancel};
+
+/// Length, mass, time, temperature and data
+const DIMENSIONS: &[&str] = &["length", "mass", "time", "temperature", "data"];
+
+type Dims = [i8; 5];
+
+const LENGTH: Dims = [1, 0, 0, 0, 0];
+const MASS: Dims = [0, 1, 0, 0, 0];
+const TIME: Dims = [0, 0, 1, 0, 0];
+const TEMPERATURE: Dims = [0, 0, 0, 1, 0];
+const DATA: Dims = [0, 0, 0, 0, 1];
+const AREA: Dims = [2, 0, 0, 0, 0];
+const VOLUME: Dims = [3, 0, 0, 0, 0];
+const SPEED: Dims = [1, 0, -1, 0, 0];
+const ENERGY: Dims = [2, 1, -2, 0, 0];
+const POWER: Dims = [2, 1, -3, 0, 0];
+
+/// Names, size in SI base units (bits for data), dimensions, and whether SI prefixes can be used
+const UNITS: &[(&[&str], f64, Dims, bool)] = &[
+    // Length
+    (&["m", "meter", "meters", "metre", "metres"], 1.0, LENGTH, true),
+    (&["in", "inch", "inches", "\""], 0.0254, LENGTH, false),
+    (&["ft", "foot", "feet", "'"], 0.3048, LENGTH, false),
+    (&["yd", "yard", "yards"], 0.9144, LENGTH, false),
+    (&["mi", "mile", "miles"], 1609.344, LENGTH, false),
+    (&["nmi", "nautical_mile", "nautical_miles"], 1852.0, LENGTH, false),
+    (&["au"], 1.495978707e11, LENGTH, false),
+    (&["ly", "lightyear", "lightyears"], 9.4607304725808e15, LENGTH, false),
+    (&["pc", "parsec", "parsecs"], 3.085677581491367e16, LENGTH, true),
+    // Area
+    (&["ha", "hectare", "hectares"], 1e4, AREA, false),
+    (&["acre", "acres"], 4046.8564224, AREA, false),
+    // Mass
+    (&["g", "gram", "grams", "gramme", "grammes"], 1e-3, MASS, true),
+    (&["t", "tonne", "tonnes"], 1e3, MASS, false),
+    (&["lb", "lbs", "pound", "pounds"], 0.45359237, MASS, false),
+    (&["oz", "ounce", "ounces"], 0.028349523125, MASS, false),
+    (&["st", "stone", "stones"], 6.35029318, MASS, false),
+    // Time
+    (&["s", "sec", "secs", "second", "seconds"], 1.0, TIME, true),
+    (&["min", "mins", "minute", "minutes"], 60.0, TIME, false),
+    (&["h", "hr", "hrs", "hour", "hours"], 3600.0, TIME, false),
+    (&["d", "day", "days"], 86400.0, TIME, false),
+    (&["wk", "week", "weeks"], 604800.0, TIME, false),
+    (&["yr", "year", "years"], 31557600.0, TIME, false),
+    // Temperature, see TEMPERATURES for the offsets
+    (&["K", "kelvin"], 1.0, TEMPERATURE, false),
+    (&["C", "°C", "celsius"], 1.0, TEMPERATURE, false),
+    (&["F", "°F", "fahrenheit"], 5.0/9.0, TEMPERATURE, false),
+    (&["R", "°R", "rankine"], 5.0/9.0, TEMPERATURE, false),
+    // Volume
+    (&["L", "l", "liter", "liters", "litre", "litres"], 1e-3, VOLUME, true),
+    (&["gal", "gallon", "gallons"], 3.785411784e-3, VOLUME, false),
+    (&["qt", "quart", "quarts"], 9.46352946e-4, VOLUME, false),
+    (&["pt", "pint", "pints"], 4.73176473e-4, VOLUME, false),
+    (&["cup", "cups"], 2.365882365e-4, VOLUME, false),
+    (&["floz", "fl_oz"], 2.95735295625e-5, VOLUME, false),
+    (&["tbsp", "tablespoon", "tablespoons"], 1.478676478125e-5, VOLUME, false),
+    (&["tsp", "teaspoon", "teaspoons"], 4.92892159375e-6, VOLUME, false),
+    // Speed
+    (&["mph"], 0.44704, SPEED, false),
+    (&["kph", "kmh"], 1.0/3.6, SPEED, false),
+    (&["kn", "kt", "knot", "knots"], 1852.0/3600.0, SPEED, false),
+    // Data
+    (&["b", "bit", "bits"], 1.0, DATA, true),
+    (&["B", "byte", "bytes"], 8.0, DATA, true),
+    // Energy and power
+    (&["J", "joule", "joules"], 1.0, ENERGY, true),
+    (&["cal", "calorie", "calories"], 4.184, ENERGY, true),
+    (&["Cal", "kcal"], 4184.0, ENERGY, false),
+    (&["Wh"], 3600.0, ENERGY, true),
+    (&["eV"], 1.602176634e-19, ENERGY, true),
+    (&["BTU", "btu"], 1055.05585262, ENERGY, false),
+    (&["W", "watt", "watts"], 1.0, POWER, true),
+    (&["hp", "horsepower"], 745.6998715822702, POWER, false),
+];
+
+/// Offsets from absolute zero, in kelvin
+const TEMPERATURES: &[(&str, f64)] = &[
+    ("C", 273.15),
+    ("F", 459.67 * 5.0/9.0),
+];
+
+const PREFIXES: &[(&str, f64)] = &[
+    ("Ki", 1024.0), ("Mi", 1048576.0), ("Gi", 1073741824.0), ("Ti", 1099511627776.0),
+    ("da", 1e1), ("Y", 1e24), ("Z", 1e21), ("E", 1e18), ("P", 1e15), ("T", 1e12),
+    ("G", 1e9), ("M", 1e6), ("k", 1e3), ("h", 1e2), ("d", 1e-1), ("c", 1e-2),
+    ("m", 1e-3), ("µ", 1e-6), ("u", 1e-6), ("n", 1e-9), ("p", 1e-12), ("f", 1e-15),
+];
+
+/// A unit, made of named units multiplied, divided and raised to powers
+#[derive(Clone, Debug)]
+pub struct Unit {
+    factor: f64,
+    dims: Dims,
+    /// Offset from zero for absolute temperatures, only set for a single temperature unit
+    offset: Option<f64>,
+}
+
+impl Unit {
+    fn one() -> Self {
+        Self { factor: 1.0, dims: [0; 5], offset: None }
+    }
+
+    fn pow(&self, n: i8) -> Self {
+        let mut dims = self.dims;
+        for d in dims.iter_mut() {
+            *d *= n;
+        }
+        Self { factor: self.factor.powi(n as i32), dims, offset: None }
+    }
+
+    fn mul(&self, other: &Unit) -> Self {
+        let mut dims = self.dims;
+        for (d, o) in dims.iter_mut().zip(other.dims.iter()) {
+            *d += o;
+        }
+        Self { factor: self.factor * other.factor, dims, offset: None }
+    }
+
+    /// Describe the unit's dimensions, such as `length/time`
+    fn describe(&self) -> String {
+        let part = |sign: i8| DIMENSIONS.iter()
+            .zip(self.dims.iter())
+            .filter(|(_, d)| d.signum() == sign)
+            .map(|(name, d)| match d.abs() {
+                1 => name.to_string(),
+                n => format!("{}^{}", name, n)
+            })
+            .collect::<Vec<String>>()
+            .join("·");
+        match (part(1), part(-1)) {
+            (num, den) if num.is_empty() && den.is_empty() => String::from("dimensionless"),
+            (num, den) if den.is_empty() => num,
+            (num, den) if num.is_empty() => format!("1/{}", den),
+            (num, den) => format!("{}/{}", num, den)
+        }
+    }
+}
+
+fn lookup(name: &str) -> Option<Unit> {
+    let find = |name: &str| UNITS.iter().find(|(names, ..)| names.contains(&name));
+    let unit = |(_, factor, dims, _): &(&[&str], f64, Dims, bool), scale: f64| Unit {
+        factor: factor * scale,
+        dims: *dims,
+        offset: None
+    };
+    if let Some(u) = find(name) {
+        let offset = TEMPERATURES.iter()
+            .find(|(n,_)| u.0.contains(n))
+            .map(|(_,o)| *o)
+            .or(if u.2 == TEMPERATURE { Some(0.0) } else { None });
+        return Some(Unit { offset, ..unit(u, 1.0) })
+    }
+    // Temperatures can't have prefixes, since prefixed units have no offset
+    for (prefix, scale) in PREFIXES {
+        if let Some(u) = name.strip_prefix(prefix).and_then(find).filter(|u| u.3 && u.2 != TEMPERATURE) {
+            // Binary prefixes only make sense for data
+            if prefix.ends_with('i') && u.2 != DATA {
+                continue
+            }
+            return Some(unit(u, *scale))
+        }
+    }
+    let lower = name.to_lowercase();
+    match lower != name {
+        true => find(&lower).map(|u| unit(u, 1.0)),
+        false => None
+    }
+}
+
+/// Parse a unit such as `km/h`, `kg m/s^2` or `m²`
+pub fn parse_unit(s: &str) -> Result<Unit, String> {
+    let s = s.trim().replace('²', "^2").replace('³', "^3");
+    if s.is_empty() {
+        return Err(String::from("no unit given"))
+    }
+    let mut result = Unit::one();
+    let mut count = 0;
+    let mut divide = false;
+    let mut chars = s.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(' ') | Some('*') | Some('·')) {
+            chars.next();
+        }
+        if chars.peek() == Some(&'/') {
+            chars.next();
+            divide = true;
+            continue
+        }
+        let name = std::iter::from_fn(|| chars.next_if(|c| !matches!(c, ' ' | '*' | '·' | '/' | '^')))
+            .collect::<String>();
+        if name.is_empty() {
+            break
+        }
+        let mut unit = lookup(&name).ok_or_else(|| format!("unknown unit `{}`", name))?;
+        let mut power = 1;
+        if chars.peek() == Some(&'^') {
+            chars.next();
+            let exp = std::iter::from_fn(|| chars.next_if(|c| *c == '-' || c.is_ascii_digit()))
+                .collect::<String>();
+            power = exp.parse::<i8>().ok().filter(|p| (-9..=9).contains(p))
+                .ok_or_else(|| format!("invalid power of `{}`", name))?;
+        }
+        if divide {
+            power = -power;
+            divide = false;
+        }
+        let offset = unit.offset;
+        unit = unit.pow(power);
+        result = result.mul(&unit);
+        count += 1;
+        if count == 1 && power == 1 {
+            result.offset = offset;
+        }
+    }
+    if count != 1 {
+        result.offset = None;
+    }
+    Ok(result)
+}
+
+/// Split a quantity such as `5 km/h` or `2*3.5 ft` into its value and unit
+fn split_quantity<'a>(s: &'a str, cancel: &Cancel) -> Result<(f64, &'a str), String> {
+    let s = s.trim();
+    let end = s.find(|c: char| !(c.is_ascii_digit() || " .+-*/^()".contains(c)))
+        .unwrap_or(s.len());
+    // The value may have swallowed the start of a unit such as `/h`, so back off
+    for i in (1..=end).rev().filter(|i| s.is_char_boundary(*i)) {
+        if cancel.is_cancelled() {
+            return Err(String::from(sandbox::CANCELLED))
+        }
+        if let Ok(v) = meval::eval_str(&s[..i]) {
+            return Ok((v, s[i..].trim()))
+        }
+    }
+    Err(String::from("no value given"))
+}
+
+/// Whether `s` looks like a conversion, with a quantity and a unit on either side of ` to `
+pub fn is_conversion(s: &str, cancel: &Cancel) -> bool {
+    match s.rfind(" to ") {
+        Some(i) => split_quantity(&s[..i], cancel).is_ok_and(|(_, from)| parse_unit(from).is_ok())
+            && parse_unit(&s[i+4..]).is_ok(),
+        None => false
+    }
+}
+
+/// Convert a quantity such as `5 km/h to m/s`, returning the converted value
+pub fn convert(s: &str, cancel: &Cancel) -> Result<(f64, String), String> {
+    let i = s.rfind(" to ").ok_or("use `<value> <unit> to <unit>`")?;
+    let (value, from) = split_quantity(&s[..i], cancel)?;
+    let from_unit = parse_unit(from)?;
+    let to = s[i+4..].trim();
+    let to_unit = parse_unit(to)?;
+    if from_unit.dims != to_unit.dims {
+        return Err(format!("can't convert `{}` ({}) to `{}` ({})",
+            from, from_unit.describe(), to, to_unit.describe()))
+    }
+    // Absolute temperatures are converted with offsets, anything else is a difference
+    let (from_offset, to_offset) = match (from_unit.offset, to_unit.offset) {
+        (Some(a), Some(b)) => (a, b),
+        _ => (0.0, 0.0)
+    };
+    let si = value * from_unit.factor + from_offset;
+    Ok(((si - to_offset) / to_unit.factor, to.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_value(s: &str) -> Result<f64, String> {
+        convert(s, &Cancel::default()).map(|(v, _)| v)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn simple_units() {
+        assert!(close(convert_value("1 km to m").unwrap(), 1000.0));
+        assert!(close(convert_value("6 ft to in").unwrap(), 72.0));
+        assert!(close(convert_value("1 GiB to MiB").unwrap(), 1024.0));
+        assert!(close(convert_value("2*3 kg to g").unwrap(), 6000.0));
+    }
+
+    #[test]
+    fn compound_units() {
+        assert!(close(convert_value("36 km/h to m/s").unwrap(), 10.0));
+        assert!(close(convert_value("1 m^2 to cm^2").unwrap(), 10000.0));
+    }
+
+    #[test]
+    fn temperatures() {
+        assert!(close(convert_value("32 F to C").unwrap(), 0.0));
+        assert!(close(convert_value("100 C to K").unwrap(), 373.15));
+        // Differences when combined with other units
+        assert!(close(convert_value("9 F/s to C/s").unwrap(), 5.0));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(convert_value("1 m to s").unwrap_err().starts_with("can't convert"));
+        assert!(convert_value("1 mK to K").is_err());
+        assert!(convert_value("1 KiB to m").is_err());
+        assert!(convert_value("km to m").is_err());
+    }
+
+    #[test]
+    fn detects_conversions() {
+        let cancel = Cancel::default();
+        assert!(is_conversion("5 km/h to m/s", &cancel));
+        assert!(!is_conversion("x to y", &cancel));
+        assert!(!is_conversion("1 + 2", &cancel));
+    }
+}
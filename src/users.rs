@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use crate::state::State;
+use crate::utils;
+
+const USER_TTL: u64 = 7*24*60*60*1000; // 1 week as millis
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedUser {
+    pub name: String,
+    pub discriminator: u16,
+    pub bot: bool,
+    updated: u64,
+}
+
+impl CachedUser {
+    pub fn tag(&self) -> String {
+        format!("{}#{:04}", self.name, self.discriminator)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct GuildMembers {
+    nicks: HashMap<UserId, Option<String>>,
+    complete: bool,
+    updated: u64,
+}
+
+/// Names and guild memberships seen over the gateway, so that rendering
+/// a list of users doesn't need one HTTP request per user
+#[derive(Default, Serialize, Deserialize)]
+pub struct UserDirectory {
+    users: HashMap<UserId, CachedUser>,
+    guilds: HashMap<GuildId, GuildMembers>,
+}
+
+impl UserDirectory {
+    pub fn set_current_user(&mut self, user: &CurrentUser) {
+        self.insert(user.id, &user.name, user.discriminator, user.bot);
+    }
+
+    pub fn update_user(&mut self, user: &User) {
+        self.insert(user.id, &user.name, user.discriminator, user.bot);
+    }
+
+    pub fn update_member(&mut self, guild: GuildId, user: &User, nick: Option<String>) {
+        self.update_user(user);
+        self.guilds.entry(guild).or_default().nicks.insert(user.id, nick);
+    }
+
+    pub fn remove_member(&mut self, guild: GuildId, user: UserId) {
+        if let Some(members) = self.guilds.get_mut(&guild) {
+            members.nicks.remove(&user);
+        }
+    }
+
+    pub fn set_guild(&mut self, guild: &Guild) {
+        for member in guild.members.values() {
+            self.update_user(&member.user);
+        }
+        let nicks = guild.members.iter()
+            .map(|(id, member)| (*id, member.nick.clone()))
+            .collect::<HashMap<UserId, Option<String>>>();
+        let complete = nicks.len() as u64 >= guild.member_count;
+        self.guilds.insert(guild.id, GuildMembers { nicks, complete, updated: utils::now_millis() });
+    }
+
+    /// Get a user, if they were seen recently enough to be trusted
+    pub fn get(&self, user: UserId) -> Option<&CachedUser> {
+        self.users.get(&user)
+            .filter(|u| utils::now_millis() < u.updated + USER_TTL)
+    }
+
+    /// Get a user's nickname in the guild if they have one, otherwise their
+    /// username. Nicknames are only trusted while the user is.
+    pub fn display_name(&self, guild: Option<GuildId>, user: UserId) -> Option<String> {
+        let cached = self.get(user)?;
+        let nick = guild
+            .and_then(|g| self.guilds.get(&g))
+            .and_then(|m| m.nicks.get(&user).cloned())
+            .flatten();
+        Some(nick.unwrap_or_else(|| cached.name.clone()))
+    }
+
+    /// Get every member of a guild, if the full member list is known
+    pub fn guild_members(&self, guild: GuildId) -> Option<HashSet<UserId>> {
+        self.guilds.get(&guild)
+            .filter(|m| m.complete && utils::now_millis() < m.updated + USER_TTL)
+            .map(|m| m.nicks.keys().copied().collect())
+    }
+
+    /// Remove users that haven't been seen within the TTL, along with their
+    /// nicknames, and guilds with no known members left
+    pub fn prune(&mut self) {
+        let now = utils::now_millis();
+        self.users.retain(|_, u| now < u.updated + USER_TTL);
+        let users = &self.users;
+        for members in self.guilds.values_mut() {
+            let before = members.nicks.len();
+            members.nicks.retain(|id, _| users.contains_key(id));
+            if members.nicks.len() != before {
+                members.complete = false;
+            }
+        }
+        self.guilds.retain(|_, m| !m.nicks.is_empty());
+    }
+
+    fn insert(&mut self, id: UserId, name: &str, discriminator: u16, bot: bool) {
+        self.users.insert(id, CachedUser {
+            name: name.to_owned(),
+            discriminator,
+            bot,
+            updated: utils::now_millis(),
+        });
+    }
+}
+
+/// Get a user from the directory, falling back to the API if they aren't known
+pub async fn fetch_user(http: &Http, dir: &mut UserDirectory, id: UserId) -> serenity::Result<CachedUser> {
+    if let Some(user) = dir.get(id) {
+        return Ok(user.clone())
+    }
+    let user = http.get_user(id.into()).await?;
+    dir.update_user(&user);
+    Ok(dir.get(id).unwrap().clone())
+}
+
+pub async fn display_name(http: &Http, dir: &mut UserDirectory, guild: Option<GuildId>, id: UserId) -> serenity::Result<String> {
+    if let Some(name) = dir.display_name(guild, id) {
+        return Ok(name)
+    }
+    Ok(fetch_user(http, dir, id).await?.name)
+}
+
+/// Get a user like `fetch_user`, but only locking the state while reading
+/// and updating the directory, not during the request
+pub async fn fetch_user_unlocked(ctx: &Context, id: UserId) -> serenity::Result<CachedUser> {
+    let cached = ctx.data.read().await.get::<State>()
+        .and_then(|s| s.users().get(id).cloned());
+    if let Some(user) = cached {
+        return Ok(user)
+    }
+    let user = ctx.http.get_user(id.into()).await?;
+    let mut data = ctx.data.write().await;
+    let dir = match data.get_mut::<State>() {
+        Some(state) => state.users_mut(),
+        None => return Ok(CachedUser { name: user.name, discriminator: user.discriminator, bot: user.bot, updated: utils::now_millis() })
+    };
+    dir.update_user(&user);
+    Ok(dir.get(id).unwrap().clone())
+}
+
+/// Get display names for several users like `display_name`, but without
+/// holding the state lock during requests for users that aren't known
+pub async fn display_names_unlocked(ctx: &Context, guild: Option<GuildId>, ids: &[UserId]) -> serenity::Result<HashMap<UserId, String>> {
+    let mut names = HashMap::new();
+    if let Some(state) = ctx.data.read().await.get::<State>() {
+        for id in ids {
+            if let Some(name) = state.users().display_name(guild, *id) {
+                names.insert(*id, name);
+            }
+        }
+    }
+    for id in ids {
+        if !names.contains_key(id) {
+            let user = fetch_user_unlocked(ctx, *id).await?;
+            names.insert(*id, user.name);
+        }
+    }
+    Ok(names)
+}
+
+/// Get every member of a guild, fetching the member list 1000 at a time if
+/// the gateway didn't provide all of it. The state is only locked while
+/// reading and updating the directory, not during the requests.
+pub async fn guild_members_unlocked(ctx: &Context, guild: GuildId) -> serenity::Result<HashSet<UserId>> {
+    let cached = ctx.data.read().await.get::<State>()
+        .and_then(|s| s.users().guild_members(guild));
+    if let Some(members) = cached {
+        return Ok(members)
+    }
+    let mut members = Vec::new();
+    let mut after = None;
+    loop {
+        let page = guild.members(&ctx.http, Some(1000), after).await?;
+        let full = page.len() == 1000;
+        after = page.last().map(|m| m.user.id);
+        members.extend(page);
+        if !full {
+            break
+        }
+    }
+    let ids = members.iter().map(|m| m.user.id).collect();
+    let mut data = ctx.data.write().await;
+    if let Some(state) = data.get_mut::<State>() {
+        let dir = state.users_mut();
+        for member in &members {
+            dir.update_user(&member.user);
+        }
+        let nicks = members.into_iter()
+            .map(|m| (m.user.id, m.nick))
+            .collect();
+        dir.guilds.insert(guild, GuildMembers { nicks, complete: true, updated: utils::now_millis() });
+    }
+    Ok(ids)
+}
@@ -1,4 +1,9 @@
 use rand::Rng;
+use std::collections::BTreeMap;
+use crate::dice;
+use crate::sandbox::Cancel;
+use serenity::http::{Http, HttpError};
+use serenity::model::prelude::*;
 
 pub const POLL_COLOR: u32 = 0x225599;
 pub const HELP_COLOR: u32 = 0x228844;
@@ -18,6 +23,18 @@ pub const NUM_EMOJIS: &[&str] = &[
     "9\u{FE0F}\u{20E3}", 
 ];
 
+pub const PAGE_PREV: &str = "\u{25C0}\u{FE0F}";
+pub const PAGE_NEXT: &str = "\u{25B6}\u{FE0F}";
+pub const PAGE_SIZE: usize = 10;
+
+pub fn now_millis() -> u64 {
+    use std::time::*;
+    use std::convert::TryInto;
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH).unwrap()
+        .as_millis().try_into().unwrap()
+}
+
 pub fn timeformat(mut millis: u64) -> String {
     let mut result = String::new();
     if millis > 60*60*1000 {
@@ -33,6 +50,112 @@ pub fn timeformat(mut millis: u64) -> String {
     result
 }
 
+const SPARK_CHARS: &[char] = &['\u{2581}', '\u{2582}', '\u{2583}', '\u{2584}', '\u{2585}', '\u{2586}', '\u{2587}', '\u{2588}'];
+/// Render values as a line of block characters scaled to the maximum value
+pub fn sparkline(values: &[u64]) -> String {
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values.iter()
+        .map(|v| SPARK_CHARS[(v * (SPARK_CHARS.len() as u64 - 1) / max) as usize])
+        .collect()
+}
+
+/// Format a duration as its nonzero days, hours, minutes, and seconds
+pub fn format_duration(millis: u64) -> String {
+    let secs = millis / 1000;
+    let parts = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+    let result = parts.iter()
+        .filter(|(n,_)| *n > 0)
+        .map(|(n,unit)| format!("{}{}", n, unit))
+        .collect::<Vec<String>>()
+        .join(" ");
+    match result.as_str() {
+        "" => "0s".to_owned(),
+        _ => result
+    }
+}
+
+/// Parse a duration such as `90s`, `2h`, or `1d12h` into milliseconds
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let mut total = 0u64;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue
+        }
+        let unit: u64 = match c {
+            's' => 1000,
+            'm' => 60*1000,
+            'h' => 60*60*1000,
+            'd' => 24*60*60*1000,
+            'w' => 7*24*60*60*1000,
+            _ => return None
+        };
+        let n = num.parse::<u64>().ok()?;
+        total = total.checked_add(n.checked_mul(unit)?)?;
+        num.clear();
+    }
+    match num.is_empty() && total > 0 {
+        true => Some(total),
+        false => None
+    }
+}
+
+/// Get a member's permissions in a guild from their roles, along with the
+/// roles. The owner and administrators have every permission.
+async fn guild_permissions(http: &Http, guild: GuildId, user: UserId) -> serenity::Result<(Permissions, Vec<RoleId>)> {
+    let partial = http.get_guild(guild.0).await?;
+    let member = http.get_member(guild.0, user.0).await?;
+    let perms = partial.roles.values()
+        .filter(|r| r.id.0 == guild.0 || member.roles.contains(&r.id))
+        .fold(Permissions::empty(), |p, r| p | r.permissions);
+    match partial.owner_id == user || perms.contains(Permissions::ADMINISTRATOR) {
+        true => Ok((Permissions::all(), member.roles)),
+        false => Ok((perms, member.roles))
+    }
+}
+
+/// Check whether a member has a permission in a guild, either directly
+/// through their roles or by being an administrator or the owner
+pub async fn has_permission(http: &Http, guild: GuildId, user: UserId, permission: Permissions) -> serenity::Result<bool> {
+    let (perms, _) = guild_permissions(http, guild, user).await?;
+    Ok(perms.contains(permission))
+}
+
+/// Check whether a member has a permission in a channel, applying the
+/// channel's overwrites for everyone, then the member's roles, then the member
+pub async fn has_channel_permission(http: &Http, guild: GuildId, channel: ChannelId, user: UserId, permission: Permissions) -> serenity::Result<bool> {
+    let (mut perms, roles) = guild_permissions(http, guild, user).await?;
+    if perms.contains(Permissions::ADMINISTRATOR) {
+        return Ok(true)
+    }
+    let overwrites = match http.get_channel(channel.0).await? {
+        Channel::Guild(c) => c.permission_overwrites,
+        _ => Vec::new()
+    };
+    let everyone = PermissionOverwriteType::Role(RoleId(guild.0));
+    for o in overwrites.iter().filter(|o| o.kind == everyone) {
+        perms = (perms & !o.deny) | o.allow;
+    }
+    let (allow, deny) = overwrites.iter()
+        .filter(|o| matches!(o.kind, PermissionOverwriteType::Role(r) if roles.contains(&r)))
+        .fold((Permissions::empty(), Permissions::empty()), |(a, d), o| (a | o.allow, d | o.deny));
+    perms = (perms & !deny) | allow;
+    for o in overwrites.iter().filter(|o| o.kind == PermissionOverwriteType::Member(user)) {
+        perms = (perms & !o.deny) | o.allow;
+    }
+    Ok(perms.contains(permission))
+}
+
+/// Whether a request failed because the bot is missing a permission
+pub fn is_missing_permissions(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(e) => matches!(**e,
+            HttpError::UnsuccessfulRequest(ref r) if r.error.code == 50013),
+        _ => false
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ErrorBox<T: std::fmt::Debug + Send>(pub T);
 impl<T: std::fmt::Debug + Send> std::fmt::Display for ErrorBox<T> {
@@ -42,69 +165,68 @@ impl<T: std::fmt::Debug + Send> std::fmt::Display for ErrorBox<T> {
 }
 impl<T: std::fmt::Debug + Send> std::error::Error for ErrorBox<T> {}
 
-pub fn roll_dice(dicestr: &str, sort: bool) -> Result<Vec<i64>, &'static str> {
-    let repl = dicestr.replace("-", "+-");
-    let dice: Vec<&str> = repl.split("+").collect();
-    let mut rolls: Vec<i64> = Vec::new();
-    let mut rng = rand::thread_rng();
-    for die in dice {
-        if die.len() == 0 {continue}
-        let (die, sign) = if die.chars().next().unwrap() == '-' {
-            (&die[1..], -1)
-        } else {
-            (die, 1)
-        };
-        let parts = die.split("d").collect::<Vec<&str>>();
-        match parts.len() {
-            1 => match parts[0].parse::<i64>() {
-                Ok(i) => rolls.push(i*sign),
-                Err(_) => return Err("could not parse integer")
-            },
-            2 => {
-                let count = match parts[0] {
-                    "" => 1,
-                    x => match x.parse::<i64>() {
-                        Ok(n) => n,
-                        Err(_) => return Err("could not parse integer")
-                    }
-                };
-                if count + (rolls.len() as i64) > 2048 {
-                    return Err("too many dice")
-                }
-                let sides = match parts[1].parse::<i64>() {
-                    Ok(n) => n,
-                    Err(_) => return Err("could not parse integer")
-                };
-                if sides <= 0 {
-                    return Err("dice must have at least one side")
-                }
-                for _ in 0..count {
-                    rolls.push(sign*rng.gen_range(1, sides+1));
-                }
-            }
-            _ => return Err("invalid dice notation")
-        }
+pub fn roll_dice<R: Rng + ?Sized>(dicestr: &str, sort: bool, stats: &BTreeMap<String, i64>, rng: &mut R, cancel: &Cancel) -> Result<dice::Roll, &'static str> {
+    let expr = dice::parse_with(dicestr, stats)?;
+    dice::roll(&expr, rng, sort, cancel)
+}
+
+/// Format a number to 10 significant figures, without trailing zeros
+pub fn format_number(x: f64) -> String {
+    format_sig(x, 10)
+}
+
+/// Format a number to `figures` significant figures, without trailing zeros
+pub fn format_sig(x: f64, figures: usize) -> String {
+    if x == 0.0 {
+        return String::from("0")
+    }
+    if !x.is_finite() {
+        return x.to_string()
     }
-    if sort {
-        rolls.sort();
+    let exp = x.abs().log10().floor() as i32;
+    if (-4..15).contains(&exp) {
+        let s = format!("{:.*}", (figures as i32 - 1 - exp).max(0) as usize, x);
+        match s.contains('.') {
+            true => s.trim_end_matches('0').trim_end_matches('.').to_owned(),
+            false => s
+        }
+    } else {
+        let s = format!("{:.*e}", figures - 1, x);
+        let (mantissa, exp) = s.split_at(s.find('e').unwrap());
+        format!("{}{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exp)
     }
-    Ok(rolls)
 }
 
-pub const EIGHT_BALL: &[&str] = &[
-    "It is certain.", "It is decidedly so.", "Without a doubt.", 
-    "Yes - definitely.", "You may rely on it.", "As I see it, yes.", 
-    "Most likely.", "Outlook good.", "Yes.", "Signs point to yes.",
+/// Longest message that Discord allows
+pub const MAX_MESSAGE_LEN: usize = 2000;
+/// Most items that can be given to `choose` or `shuffle`
+pub const MAX_ITEMS: usize = 1000;
 
-    "Reply hazy, try again.", "Ask again later.", "Better not tell you know.", 
-    "Cannot predict now.", "Concentrate and ask again.",
+/// Generate a random integer between `min` and `max` inclusive
+pub fn random_int<R: Rng + ?Sized>(min: i64, max: i64, rng: &mut R) -> i64 {
+    rng.sample(rand::distributions::Uniform::new_inclusive(min, max))
+}
 
-    "Don't count on it.", "My reply is no.", "My sources say no.", 
-    "Outlook not so good.", "Very doubtful."
-];
-pub fn eight_ball() -> &'static str {
-    let idx = rand::thread_rng().gen_range(0, EIGHT_BALL.len());
-    return EIGHT_BALL[idx];
+/// Split a list of items separated by semicolons, or commas or spaces if there are no semicolons
+pub fn split_items(s: &str) -> Vec<String> {
+    let items: Vec<&str> = if s.contains(';') {
+        s.split(';').collect()
+    } else if s.contains(',') {
+        s.split(',').collect()
+    } else {
+        s.split_whitespace().collect()
+    };
+    items.into_iter()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Flip `n` coins, returning the number of heads and tails
+pub fn flip_coins<R: Rng + ?Sized>(n: u32, rng: &mut R) -> (u32, u32) {
+    let heads = (0..n).filter(|_| rng.gen()).count() as u32;
+    (heads, n - heads)
 }
 
 
@@ -207,7 +329,7 @@ async fn wikipedia_inner(query: &str) -> Result<EmbedResult, Box<dyn std::error:
 }
 
 pub async fn xkcd(query: &str) -> Result<EmbedResult, EmbedError> {
-    if query.len() > 0 && query.parse::<u32>().is_err() {
+    if !query.is_empty() && query.parse::<u32>().is_err() {
         return Err(EmbedError::BadQuery("Invalid comic number".to_string()))
     }
     match xkcd_inner(query).await {
@@ -234,7 +356,7 @@ async fn xkcd_inner(query: &str) -> Result<EmbedResult, Box<dyn std::error::Erro
     let image_url = json["img"].as_str().ok_or(ErrorBox("Error retrieving image"))?;
     Ok(EmbedResult {
         title: title.to_owned(),
-        url: url,
+        url,
         text: text.to_owned(),
         image_url: Some(image_url.to_owned())
     })
@@ -242,7 +364,7 @@ async fn xkcd_inner(query: &str) -> Result<EmbedResult, Box<dyn std::error::Erro
 
 pub async fn imgflip(query: &str, uname: &str, passwd: &str) -> Result<EmbedResult, EmbedError> {
     let parts = query.split(";").collect::<Vec<&str>>();
-    if parts.len() < 1 {
+    if parts.is_empty() {
         return Err(EmbedError::Missing("No template name specified".to_owned()))
     }
     if parts.len() < 2 {
@@ -305,3 +427,31 @@ async fn imgflip_inner(id: u32, texts: Vec<&str>, uname: &str, passwd: &str)
         Err(ErrorBox(failure))?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn flips_add_up() {
+        for n in [0, 1, 10, 1000] {
+            let (heads, tails) = flip_coins(n, &mut StdRng::seed_from_u64(n as u64));
+            assert_eq!(heads + tails, n);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_flips() {
+        let a = flip_coins(100, &mut StdRng::seed_from_u64(3));
+        let b = flip_coins(100, &mut StdRng::seed_from_u64(3));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn flips_are_roughly_fair() {
+        let (heads, _) = flip_coins(10000, &mut StdRng::seed_from_u64(1));
+        assert!((4500..=5500).contains(&heads), "{}", heads);
+    }
+}